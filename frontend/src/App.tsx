@@ -45,7 +45,11 @@ export default function App() {
                 <Setup onComplete={checkStatus} />
               </Show>
               <Show when={s().initialized && (!s().unlocked || !s().authenticated)}>
-                <Unlock unlocked={s().unlocked} onComplete={checkStatus} />
+                <Unlock
+                  unlocked={s().unlocked}
+                  secondFactor={s().unlocked && s().second_factor_required}
+                  onComplete={checkStatus}
+                />
               </Show>
               <Show when={s().initialized && s().unlocked && s().authenticated}>
                 <Show when={page() === "vault"}>
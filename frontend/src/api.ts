@@ -4,8 +4,13 @@ export interface Status {
   initialized: boolean;
   unlocked: boolean;
   authenticated: boolean;
+  second_factor_required: boolean;
 }
 
+// Unlocking with the password leaves a session waiting for a TOTP or
+// backup code once two-factor authentication is on
+export type UnlockResult = "unlocked" | "second_factor";
+
 export interface LinkedImage {
   id: string;
   original_mime: string;
@@ -29,22 +34,55 @@ export async function fetchStatus(): Promise<Status> {
   return res.json();
 }
 
-export async function setup(password: string): Promise<void> {
+export async function setup(password: string): Promise<string> {
   const res = await fetch("/api/setup", {
     method: "POST",
     headers: { "Content-Type": "application/json" },
     body: JSON.stringify({ password }),
   });
   if (!res.ok) throw new Error(await res.text());
+  const data: { recovery_key: string } = await res.json();
+  return data.recovery_key;
+}
+
+export async function recover(recoveryKey: string, newPassword: string): Promise<UnlockResult> {
+  const res = await fetch("/api/recover", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ recovery_key: recoveryKey, new_password: newPassword }),
+  });
+  if (!res.ok) throw new Error(await res.text());
+  return res.status === 202 ? "second_factor" : "unlocked";
+}
+
+export async function regenerateRecoveryKey(password: string): Promise<string> {
+  const res = await fetch("/api/recovery-key", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ password }),
+  });
+  if (!res.ok) throw new Error(await res.text());
+  const data: { recovery_key: string } = await res.json();
+  return data.recovery_key;
 }
 
-export async function unlock(password: string): Promise<void> {
+export async function unlock(password: string): Promise<UnlockResult> {
   const res = await fetch("/api/unlock", {
     method: "POST",
     headers: { "Content-Type": "application/json" },
     body: JSON.stringify({ password }),
   });
   if (!res.ok) throw new Error(await res.text());
+  return res.status === 202 ? "second_factor" : "unlocked";
+}
+
+export async function verifySecondFactor(code: string): Promise<void> {
+  const res = await fetch("/api/totp/verify", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ code }),
+  });
+  if (!res.ok) throw new Error(await res.text());
 }
 
 export async function logout(): Promise<void> {
@@ -8,6 +8,7 @@ export function Header(props: {
   onUpload: () => void;
   onBulkTag: () => void;
   onRenameTag: () => void;
+  onRecoveryKey: () => void;
   onStatusChange: () => void;
   onNavigate: (page: Page) => void;
 }) {
@@ -24,6 +25,7 @@ export function Header(props: {
   const toolItems: MenuItem[] = [
     { label: "Bulk Tag", onClick: props.onBulkTag },
     { label: "Rename Tag", onClick: props.onRenameTag },
+    { label: "Recovery Key", onClick: props.onRecoveryKey },
   ];
 
   const mobileItems: MenuItem[] = [
@@ -31,6 +33,7 @@ export function Header(props: {
     { label: "Reels", onClick: () => props.onNavigate("reels") },
     { label: "Bulk Tag", onClick: props.onBulkTag },
     { label: "Rename Tag", onClick: props.onRenameTag },
+    { label: "Recovery Key", onClick: props.onRecoveryKey },
     { label: "Logout", onClick: handleLogout },
     { label: "Lock Vault", onClick: handleLock },
   ];
@@ -0,0 +1,71 @@
+import { createSignal, Show } from "solid-js";
+import { Modal } from "./ui/Modal";
+import { Button } from "./ui/Button";
+import { Input } from "./ui/Input";
+import { RecoveryKeyNotice } from "./RecoveryKeyNotice";
+import * as api from "../api";
+
+export function RecoveryKeyModal(props: { open: boolean; onClose: () => void }) {
+  const [password, setPassword] = createSignal("");
+  const [recoveryKey, setRecoveryKey] = createSignal("");
+  const [error, setError] = createSignal("");
+  const [loading, setLoading] = createSignal(false);
+
+  const close = () => {
+    setPassword("");
+    setRecoveryKey("");
+    setError("");
+    props.onClose();
+  };
+
+  // The old key is gone already, so until the new one is confirmed closing
+  // only hides it
+  const dismiss = () => (recoveryKey() ? props.onClose() : close());
+
+  const regenerate = async (e: Event) => {
+    e.preventDefault();
+    setError("");
+    setLoading(true);
+    try {
+      setRecoveryKey(await api.regenerateRecoveryKey(password()));
+      setPassword("");
+    } catch (err: any) {
+      setError(err.message || "Failed to generate a recovery key");
+    }
+    setLoading(false);
+  };
+
+  return (
+    <Modal open={props.open} onClose={dismiss} title="Recovery Key">
+      <Show
+        when={recoveryKey()}
+        fallback={
+          <form onSubmit={regenerate} class="flex flex-col gap-4">
+            <p class="text-sm text-gray-500 dark:text-gray-400">
+              Generates a new recovery key. The current one stops working right away.
+            </p>
+            <Input
+              label="Password"
+              type="password"
+              placeholder="Enter your password"
+              value={password()}
+              onChange={setPassword}
+              required
+            />
+            <div class="flex justify-end gap-2">
+              <Button variant="secondary" onClick={close}>Cancel</Button>
+              <Button type="submit" disabled={loading()}>
+                {loading() ? "Generating…" : "Generate New Key"}
+              </Button>
+            </div>
+            <Show when={error()}>
+              <p class="text-sm font-medium text-red-500">{error()}</p>
+            </Show>
+          </form>
+        }
+      >
+        <RecoveryKeyNotice recoveryKey={recoveryKey()} onDone={close} />
+      </Show>
+    </Modal>
+  );
+}
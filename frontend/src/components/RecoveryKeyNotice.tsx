@@ -0,0 +1,49 @@
+import { createSignal } from "solid-js";
+import { Button } from "./ui/Button";
+
+/**
+ * Shows a freshly generated recovery key. The server never shows it again,
+ * so the user has to confirm they kept a copy before moving on.
+ */
+export function RecoveryKeyNotice(props: { recoveryKey: string; onDone: () => void }) {
+  const [saved, setSaved] = createSignal(false);
+  const [copied, setCopied] = createSignal(false);
+
+  const copy = async () => {
+    try {
+      await navigator.clipboard.writeText(props.recoveryKey);
+      setCopied(true);
+    } catch {}
+  };
+
+  return (
+    <div class="flex flex-col gap-4">
+      <p class="text-sm text-gray-500 dark:text-gray-400">
+        This key unlocks the vault and resets the password if you forget it. It is shown only
+        this once: print it or save it somewhere safe, away from this device.
+      </p>
+      <div class="rounded-lg border border-gray-200 dark:border-gray-700 bg-gray-50 dark:bg-gray-800 px-3 py-3 font-mono text-sm text-center break-all select-all">
+        {props.recoveryKey}
+      </div>
+      <div class="flex gap-2">
+        <Button variant="secondary" onClick={copy} class="flex-1">
+          {copied() ? "Copied" : "Copy"}
+        </Button>
+        <Button variant="secondary" onClick={() => window.print()} class="flex-1">
+          Print
+        </Button>
+      </div>
+      <label class="flex items-center gap-2 text-sm cursor-pointer">
+        <input
+          type="checkbox"
+          checked={saved()}
+          onChange={(e) => setSaved(e.currentTarget.checked)}
+        />
+        I have printed or saved this key
+      </label>
+      <Button disabled={!saved()} onClick={props.onDone} class="w-full">
+        Continue
+      </Button>
+    </div>
+  );
+}
@@ -1,62 +1,193 @@
-import { createSignal, Show } from "solid-js";
-import { unlock } from "../api";
+import { createSignal, Match, Show, Switch } from "solid-js";
+import { logout, recover, unlock, verifySecondFactor, type UnlockResult } from "../api";
 import { Button } from "../components/ui/Button";
 import { Input } from "../components/ui/Input";
 
 export default function Unlock(props: {
   unlocked: boolean;
+  secondFactor: boolean;
   onComplete: () => void;
 }) {
   const [password, setPassword] = createSignal("");
+  const [recovering, setRecovering] = createSignal(false);
+  const [recoveryKey, setRecoveryKey] = createSignal("");
+  const [newPassword, setNewPassword] = createSignal("");
+  const [confirmPassword, setConfirmPassword] = createSignal("");
+  const [code, setCode] = createSignal("");
   const [error, setError] = createSignal("");
   const [loading, setLoading] = createSignal(false);
 
   const isLoginOnly = () => props.unlocked;
 
+  // A pending second factor keeps this page up, now asking for the code
+  const finish = (result: UnlockResult) => {
+    if (result === "second_factor") {
+      setPassword("");
+      setRecovering(false);
+      setLoading(false);
+    }
+    props.onComplete();
+  };
+
   const handleSubmit = async (e: Event) => {
     e.preventDefault();
     setError("");
     setLoading(true);
     try {
-      await unlock(password());
-      props.onComplete();
+      finish(await unlock(password()));
     } catch (err: any) {
       setError(err.message || "Authentication failed");
       setLoading(false);
     }
   };
 
+  // The recovery key only gets in together with a new password, which
+  // replaces the forgotten one
+  const handleRecover = async (e: Event) => {
+    e.preventDefault();
+    if (newPassword() !== confirmPassword()) {
+      setError("Passwords do not match");
+      return;
+    }
+    setError("");
+    setLoading(true);
+    try {
+      finish(await recover(recoveryKey(), newPassword()));
+    } catch (err: any) {
+      setError(err.message || "Recovery failed");
+      setLoading(false);
+    }
+  };
+
+  const handleVerify = async (e: Event) => {
+    e.preventDefault();
+    setError("");
+    setLoading(true);
+    try {
+      await verifySecondFactor(code().trim());
+      props.onComplete();
+    } catch (err: any) {
+      setError(err.message || "Invalid code");
+      setCode("");
+      setLoading(false);
+    }
+  };
+
+  const toggleRecovering = () => {
+    setRecovering(!recovering());
+    setError("");
+  };
+
+  // Drops the session waiting for a code, back to the password
+  const startOver = async () => {
+    await logout();
+    setCode("");
+    setError("");
+    props.onComplete();
+  };
+
   return (
     <div class="flex items-center justify-center min-h-screen p-4">
       <div class="w-full max-w-sm bg-white dark:bg-gray-900 rounded-xl shadow-lg p-6">
-        <h2 class="text-xl font-bold mb-1">
-          {isLoginOnly() ? "Welcome Back" : "Unlock Vault"}
-        </h2>
-        <p class="text-sm text-gray-500 dark:text-gray-400 mb-6">
-          {isLoginOnly()
-            ? "The vault is unlocked. Enter your password to continue."
-            : "Enter your master password to unlock the vault."}
-        </p>
-        <form onSubmit={handleSubmit} class="flex flex-col gap-4">
-          <Input
-            label="Password"
-            type="password"
-            placeholder="Enter your password"
-            value={password()}
-            onChange={setPassword}
-            required
-          />
-          <Button type="submit" disabled={loading()} class="w-full">
-            {loading()
-              ? "Unlocking…"
-              : isLoginOnly()
-                ? "Login"
-                : "Unlock"}
-          </Button>
-        </form>
+        <Switch
+          fallback={
+            <>
+              <h2 class="text-xl font-bold mb-1">
+                {isLoginOnly() ? "Welcome Back" : "Unlock Vault"}
+              </h2>
+              <p class="text-sm text-gray-500 dark:text-gray-400 mb-6">
+                {isLoginOnly()
+                  ? "The vault is unlocked. Enter your password to continue."
+                  : "Enter your master password to unlock the vault."}
+              </p>
+              <form onSubmit={handleSubmit} class="flex flex-col gap-4">
+                <Input
+                  label="Password"
+                  type="password"
+                  placeholder="Enter your password"
+                  value={password()}
+                  onChange={setPassword}
+                  required
+                />
+                <Button type="submit" disabled={loading()} class="w-full">
+                  {loading()
+                    ? "Unlocking…"
+                    : isLoginOnly()
+                      ? "Login"
+                      : "Unlock"}
+                </Button>
+              </form>
+            </>
+          }
+        >
+          <Match when={props.secondFactor}>
+            <h2 class="text-xl font-bold mb-1">Two-Factor Authentication</h2>
+            <p class="text-sm text-gray-500 dark:text-gray-400 mb-6">
+              Enter the code from your authenticator app, or one of your backup codes.
+            </p>
+            <form onSubmit={handleVerify} class="flex flex-col gap-4">
+              <Input
+                label="Code"
+                placeholder="123456"
+                value={code()}
+                onChange={setCode}
+                required
+              />
+              <Button type="submit" disabled={loading()} class="w-full">
+                {loading() ? "Verifying…" : "Verify"}
+              </Button>
+            </form>
+          </Match>
+          <Match when={recovering()}>
+            <h2 class="text-xl font-bold mb-1">Recover Vault</h2>
+            <p class="text-sm text-gray-500 dark:text-gray-400 mb-6">
+              Enter your recovery key and choose a new password. The old password stops working.
+            </p>
+            <form onSubmit={handleRecover} class="flex flex-col gap-4">
+              <Input
+                label="Recovery Key"
+                placeholder="XXXX-XXXX-…"
+                value={recoveryKey()}
+                onChange={setRecoveryKey}
+                required
+              />
+              <Input
+                label="New Password"
+                type="password"
+                placeholder="Enter a strong password"
+                value={newPassword()}
+                onChange={setNewPassword}
+                required
+              />
+              <Input
+                label="Confirm New Password"
+                type="password"
+                placeholder="Enter it again"
+                value={confirmPassword()}
+                onChange={setConfirmPassword}
+                required
+              />
+              <Button type="submit" disabled={loading()} class="w-full">
+                {loading() ? "Recovering…" : "Reset Password and Unlock"}
+              </Button>
+            </form>
+          </Match>
+        </Switch>
         <Show when={error()}>
           <p class="mt-3 text-sm font-medium text-red-500">{error()}</p>
         </Show>
+        <Show
+          when={props.secondFactor}
+          fallback={
+            <Button variant="ghost" onClick={toggleRecovering} class="mt-4 w-full">
+              {recovering() ? "Back to password" : "Forgot your password?"}
+            </Button>
+          }
+        >
+          <Button variant="ghost" onClick={startOver} class="mt-4 w-full">
+            Start over
+          </Button>
+        </Show>
       </div>
     </div>
   );
@@ -12,6 +12,7 @@ import { UploadModal } from "../components/UploadModal";
 import { TagModal } from "../components/TagModal";
 import { InfoModal } from "../components/InfoModal";
 import { RenameTagModal } from "../components/RenameTagModal";
+import { RecoveryKeyModal } from "../components/RecoveryKeyModal";
 import {
   BulkTagModal,
   BulkBanner,
@@ -20,7 +21,7 @@ import {
 } from "../components/BulkTag";
 import type { Page } from "../App";
 
-type ModalType = "upload" | "tag" | "info" | "bulkSetup" | "rename" | null;
+type ModalType = "upload" | "tag" | "info" | "bulkSetup" | "rename" | "recovery" | null;
 
 export default function Vault(props: { onStatusChange: () => void; onNavigate: (page: Page) => void }) {
   const store = createVaultStore(props.onStatusChange);
@@ -115,6 +116,7 @@ export default function Vault(props: { onStatusChange: () => void; onNavigate: (
         onUpload={() => setModal("upload")}
         onBulkTag={() => setModal("bulkSetup")}
         onRenameTag={() => setModal("rename")}
+        onRecoveryKey={() => setModal("recovery")}
         onStatusChange={props.onStatusChange}
         onNavigate={props.onNavigate}
       />
@@ -172,6 +174,7 @@ export default function Vault(props: { onStatusChange: () => void; onNavigate: (
       <TagModal open={modal() === "tag"} onClose={() => setModal(null)} image={lightboxImage()} store={store} />
       <InfoModal open={modal() === "info"} onClose={() => setModal(null)} image={lightboxImage()} />
       <RenameTagModal open={modal() === "rename"} onClose={() => setModal(null)} store={store} />
+      <RecoveryKeyModal open={modal() === "recovery"} onClose={() => setModal(null)} />
       <BulkTagModal
         open={modal() === "bulkSetup"}
         onClose={cancelBulk}
@@ -1,17 +1,28 @@
 use axum::{
     Json, Router,
-    extract::{Multipart, Query, Request, State},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Multipart, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
     middleware,
-    response::{IntoResponse, Response},
+    response::{IntoResponse, IntoResponseParts, Response},
     routing::{delete, get, post},
 };
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures_util::TryStreamExt;
+use std::{
+    net::SocketAddr,
+    ops::Range,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 use tower_sessions::Session;
+use zeroize::Zeroizing;
 
 use crate::{
     app_state::AppState,
     image_processor,
-    vault::{ImageVariant, VaultError, mime_to_ext},
+    vault::{
+        AuditEvent, BlobReader, Credentials, ImageVariant, Snapshot, Vault, VaultError, mime_to_ext,
+    },
 };
 
 const ALLOWED_IMAGE_TYPES: &[&str] = &[
@@ -28,11 +39,94 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct UnlockRequest {
     pub password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SetupRequest {
     pub password: String,
+    /// Base64 keyfile the password will require
+    #[serde(default)]
+    pub keyfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RecoverRequest {
+    pub recovery_key: String,
+    pub new_password: String,
+    /// Base64 keyfile the new password will require
+    #[serde(default)]
+    pub keyfile: Option<String>,
+    /// Password slot to replace; defaults to the oldest password slot.
+    pub slot_id: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordRequest {
+    pub password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeKeyfileRequest {
+    pub password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+    /// Base64 keyfile to require from now on; omitted to stop requiring one
+    #[serde(default)]
+    pub new_keyfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AddKeySlotRequest {
+    pub password: String,
+    pub new_password: String,
+    pub label: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateDecoyRequest {
+    pub password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+    pub duress_password: String,
+    /// Whether unlocking with the duress password removes every other
+    /// library's key slots.
+    #[serde(default)]
+    pub destroy_others: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RemoveDecoyRequest {
+    pub password: String,
+    /// Base64 keyfile contents, for slots that require one
+    #[serde(default)]
+    pub keyfile: Option<String>,
+    pub duress_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    /// TOTP code, or a backup code when verifying a session
+    pub code: String,
 }
 
 #[derive(Deserialize)]
@@ -46,6 +140,20 @@ pub struct RenameTagRequest {
     pub new_tag: String,
 }
 
+#[derive(Deserialize)]
+pub struct AuditParams {
+    /// Only records older than this sequence number, for the next page
+    pub before: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CheckRequest {
+    /// Regenerate broken variants and quarantine orphans
+    #[serde(default)]
+    pub repair: bool,
+}
+
 #[derive(Deserialize)]
 pub struct ListParams {
     /// Tag search query: space-separated tags. Prefix with - to exclude.
@@ -62,6 +170,10 @@ pub fn get_api_router(state: AppState) -> Router {
         .route("/images/{id}/tags", post(add_tag))
         .route("/images/{id}/tags", delete(remove_tag))
         .route("/images/{id}/download", get(download_image))
+        .route("/trash", get(list_trash))
+        .route("/trash", delete(empty_trash))
+        .route("/trash/{id}/restore", post(restore_image))
+        .route("/trash/{id}", delete(purge_image))
         .route("/images/{id}/linked", post(upload_to_linked_set))
         .route(
             "/images/{id}/linked/{sub_id}",
@@ -73,6 +185,39 @@ pub fn get_api_router(state: AppState) -> Router {
         )
         .route("/tags", get(list_tags))
         .route("/tags/rename", post(rename_tag))
+        .route("/password", post(change_password))
+        .route("/keyfile", post(change_keyfile))
+        .route("/slots", get(list_key_slots))
+        .route("/slots", post(add_key_slot))
+        .route("/slots/{slot_id}", delete(remove_key_slot))
+        .route("/recovery-key", post(regenerate_recovery_key))
+        .route("/decoy", post(create_decoy))
+        .route("/decoy", delete(remove_decoy))
+        .route("/sessions", get(list_sessions))
+        .route("/audit", get(audit_log))
+        .route("/audit/verify", get(verify_audit_log))
+        .route("/sessions/{id}", delete(revoke_session))
+        .route("/totp", post(begin_totp))
+        .route("/totp", delete(disable_totp))
+        .route("/totp/confirm", post(confirm_totp))
+        .route("/totp/backup-codes", post(regenerate_backup_codes))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            busy_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Stay reachable while the vault is busy so progress can be followed
+    let admin_routes = Router::new()
+        .route("/admin/rotation", get(rotation_progress))
+        .route("/admin/rotation", post(start_rotation))
+        .route("/admin/fsck", post(check_vault))
+        .route("/admin/gc", post(collect_garbage))
+        .route("/admin/backup", post(backup_vault))
+        .route("/admin/mirror", post(mirror_vault))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
@@ -82,9 +227,16 @@ pub fn get_api_router(state: AppState) -> Router {
         .route("/status", get(get_status))
         .route("/unlock", post(unlock_vault))
         .route("/setup", post(setup_vault))
+        .route("/recover", post(recover_vault))
+        .route("/totp/verify", post(verify_second_factor))
         .route("/logout", post(logout))
         .route("/lock", post(lock_vault))
         .merge(protected_routes)
+        .merge(admin_routes)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            retry_after_middleware,
+        ))
         .with_state(state)
 }
 
@@ -100,20 +252,67 @@ async fn auth_middleware(
         .unwrap_or(None)
         .unwrap_or(false);
 
-    if !is_authenticated {
-        return Err((StatusCode::UNAUTHORIZED, "Not authenticated".to_string()));
-    }
-
     {
         let vault = state.vault.read().await;
+        if !is_authenticated {
+            let message = if pending_session(&session, &state, &vault).await.is_some() {
+                "Second factor required"
+            } else {
+                "Not authenticated"
+            };
+            return Err((StatusCode::UNAUTHORIZED, message.to_string()));
+        }
         if !vault.is_unlocked() {
             return Err((StatusCode::FORBIDDEN, "Vault is locked".to_string()));
         }
+        // Revoked sessions and those from before the vault was last locked
+        // have to log in again
+        if current_session(&session, &state, &vault).await.is_none() {
+            session.flush().await.ok();
+            return Err((StatusCode::UNAUTHORIZED, "Session expired".to_string()));
+        }
+        vault.touch();
+    }
+
+    Ok(next.run(request).await)
+}
+
+async fn busy_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: middleware::Next,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    {
+        let vault = state.vault.read().await;
+        if vault.is_busy() {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Re-encryption in progress".to_string(),
+            ));
+        }
     }
 
     Ok(next.run(request).await)
 }
 
+/// Tells throttled clients when they may try again.
+async fn retry_after_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: middleware::Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = state.vault.read().await.retry_after().unwrap_or(0);
+        if retry_after > 0 {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+    }
+    response
+}
+
 async fn get_status(
     State(state): State<AppState>,
     session: Session,
@@ -123,29 +322,38 @@ async fn get_status(
         .get("authenticated")
         .await
         .unwrap_or(None)
-        .unwrap_or(false);
+        .unwrap_or(false)
+        && current_session(&session, &state, &vault).await.is_some();
 
     let status = serde_json::json!({
         "initialized": !vault.needs_setup(),
         "unlocked": vault.is_unlocked(),
         "authenticated": is_authenticated,
+        "second_factor_required": pending_session(&session, &state, &vault).await.is_some(),
+        // Seconds until the vault locks itself, if it is set to
+        "auto_lock_in": vault.auto_lock_remaining(Instant::now()).map(|left| left.as_secs()),
     });
 
     Ok(Json(status))
 }
 
-async fn logout(session: Session) -> impl IntoResponse {
+async fn logout(State(state): State<AppState>, session: Session) -> impl IntoResponse {
+    if let Ok(Some(id)) = session.get::<uuid::Uuid>("session_id").await {
+        state.sessions.revoke(id);
+    }
     session.flush().await.ok();
     (StatusCode::OK, "Logged out")
 }
 
 async fn lock_vault(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     session: Session,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     session.flush().await.ok();
 
     let vault = state.vault.read().await;
+    vault.audit(AuditEvent::Lock, Some(client.ip()));
     vault.lock();
 
     Ok((StatusCode::OK, "Vault locked and logged out"))
@@ -154,42 +362,135 @@ async fn lock_vault(
 async fn setup_vault(
     State(state): State<AppState>,
     session: Session,
+    headers: HeaderMap,
     Json(payload): Json<SetupRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
     let mut vault = state.vault.write().await;
 
-    vault
-        .setup(&payload.password)
+    let recovery_key = vault
+        .setup(credentials(&payload.password, &keyfile))
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-    session.insert("authenticated", true).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to create session".to_string(),
+    register_session(&session, &state, &vault, &headers, false).await?;
+
+    // The recovery key is only ever shown here
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "recovery_key": recovery_key })),
+    ))
+}
+
+async fn recover_vault(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    session: Session,
+    headers: HeaderMap,
+    Json(payload): Json<RecoverRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if payload.new_password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Password cannot be empty".to_string(),
+        ));
+    }
+
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    derive_keys(&vault, move |vault| {
+        vault.recover(
+            &payload.recovery_key,
+            credentials(&payload.new_password, &keyfile),
+            payload.slot_id,
         )
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => {
+            (StatusCode::UNAUTHORIZED, "Invalid recovery key".to_string())
+        }
+        VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Key slot not found".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
     })?;
 
-    Ok((StatusCode::CREATED, "Vault initialized and unlocked"))
+    vault.audit(AuditEvent::Recover, Some(client.ip()));
+    // Whoever knew the old password is logged out
+    state.sessions.revoke_others(None);
+    if authenticate_session(&session, &state, &vault, &headers).await? {
+        return Ok((StatusCode::ACCEPTED, "Second factor required"));
+    }
+    Ok((StatusCode::OK, "Password reset and vault unlocked"))
 }
 
 async fn unlock_vault(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     session: Session,
+    headers: HeaderMap,
     Json(payload): Json<UnlockRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let vault = state.vault.read().await;
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let vault = state.vault.read().await.clone();
+
+    // Already unlocked with the same password this only checks it
+    derive_keys(&vault, move |vault| {
+        vault.unlock(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => {
+            // Only reaches a log while some library is unlocked, the
+            // rest are summed up on the next unlock
+            vault.audit(AuditEvent::UnlockFailed, Some(client.ip()));
+            (StatusCode::UNAUTHORIZED, "Invalid password".to_string())
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+    vault.audit(AuditEvent::Unlock, Some(client.ip()));
 
-    if vault.is_unlocked() {
-        vault
-            .verify_password(&payload.password)
-            .map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))?;
-    } else {
-        vault
-            .unlock(&payload.password)
-            .map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))?;
+    if authenticate_session(&session, &state, &vault, &headers).await? {
+        return Ok((StatusCode::ACCEPTED, "Second factor required"));
     }
+    Ok((StatusCode::OK, "Vault unlocked"))
+}
+
+async fn verify_second_factor(
+    State(state): State<AppState>,
+    session: Session,
+    Json(payload): Json<CodeRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // Write lock so two requests cannot both spend the same code
+    let vault = state.vault.write().await;
+    // Only for the unlock the password was given to. Locking the vault,
+    // unlocking another library or revoking the session ends it.
+    let Some(id) = pending_session(&session, &state, &vault).await else {
+        return Err((StatusCode::UNAUTHORIZED, "Not authenticated".to_string()));
+    };
+    if !vault.is_unlocked() {
+        return Err((StatusCode::FORBIDDEN, "Vault is locked".to_string()));
+    }
+    vault
+        .verify_second_factor(&payload.code, unix_now())
+        .map_err(|e| match e {
+            VaultError::Throttled(_) | VaultError::LockedOut => {
+                (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+            }
+            VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid code".to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
 
+    if !state.sessions.confirm(id, vault.unlock_generation()) {
+        return Err((StatusCode::UNAUTHORIZED, "Session expired".to_string()));
+    }
     session.insert("authenticated", true).await.map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -200,6 +501,511 @@ async fn unlock_vault(
     Ok("Vault unlocked")
 }
 
+async fn change_password(
+    State(state): State<AppState>,
+    session: Session,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if payload.new_password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Password cannot be empty".to_string(),
+        ));
+    }
+
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    derive_keys(&vault, move |vault| {
+        vault.change_password(
+            credentials(&payload.old_password, &keyfile),
+            &payload.new_password,
+        )
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    // Every other session has to log in with the new password
+    let current = current_session(&session, &state, &vault).await;
+    state.sessions.revoke_others(current);
+
+    Ok("Password changed")
+}
+
+async fn change_keyfile(
+    State(state): State<AppState>,
+    session: Session,
+    Json(payload): Json<ChangeKeyfileRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let new_keyfile = decode_keyfile(payload.new_keyfile.as_deref())?;
+    let removed = new_keyfile.is_none();
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    derive_keys(&vault, move |vault| {
+        vault.change_keyfile(
+            credentials(&payload.password, &keyfile),
+            new_keyfile.as_deref().map(Vec::as_slice),
+        )
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    let current = current_session(&session, &state, &vault).await;
+    state.sessions.revoke_others(current);
+
+    Ok(if removed {
+        "Keyfile removed"
+    } else {
+        "Keyfile changed"
+    })
+}
+
+async fn regenerate_recovery_key(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    let recovery_key = derive_keys(&vault, move |vault| {
+        vault.regenerate_recovery_key(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(Json(serde_json::json!({ "recovery_key": recovery_key })))
+}
+
+async fn start_rotation(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    // Waits out the requests in flight, which must not write under the old
+    // key once the rotation started. The vault is busy from then on anyway.
+    let vault = state.vault.write().await;
+
+    let started = derive_keys(&vault, move |vault| {
+        vault.start_rotation(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::Busy(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    // The new recovery key is only ever shown here
+    Ok((StatusCode::ACCEPTED, Json(started)))
+}
+
+async fn rotation_progress(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let progress = vault
+        .rotation_progress()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(progress))
+}
+
+async fn check_vault(
+    State(state): State<AppState>,
+    Json(payload): Json<CheckRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // Uploads in between would leave blobs the check takes for orphans
+    let vault = state.vault.write().await;
+
+    let report = vault.check(payload.repair).await.map_err(|e| match e {
+        VaultError::Busy(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(Json(report))
+}
+
+async fn collect_garbage(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let report = vault.collect_garbage().await.map_err(|e| match e {
+        VaultError::Busy(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(Json(report))
+}
+
+async fn backup_vault(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (vault, snapshot) = take_snapshot(&state).await?;
+
+    let path = vault
+        .backup_path()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let manifest = vault
+        .backup(snapshot, path.clone())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(serde_json::json!({
+        "path": path,
+        "files": manifest.files.len(),
+        "bytes": manifest.files.iter().map(|f| f.size).sum::<u64>(),
+    })))
+}
+
+async fn mirror_vault(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let target = state.vault.read().await.mirror_dir().ok_or((
+        StatusCode::BAD_REQUEST,
+        "No mirror directory configured".to_string(),
+    ))?;
+    let (vault, snapshot) = take_snapshot(&state).await?;
+
+    let report = vault
+        .mirror(snapshot, target)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(report))
+}
+
+/// Snapshots the vault for a backup or mirror. Nothing may be written while
+/// it is taken, but the copying that follows leaves the vault available.
+async fn take_snapshot(state: &AppState) -> Result<(Vault, Snapshot), (StatusCode, String)> {
+    let vault = state.vault.write().await;
+    let snapshot = vault.snapshot().map_err(|e| match e {
+        VaultError::Busy(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+    Ok((vault.clone(), snapshot))
+}
+
+async fn list_key_slots(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let slots = vault
+        .list_key_slots()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(slots))
+}
+
+async fn add_key_slot(
+    State(state): State<AppState>,
+    Json(payload): Json<AddKeySlotRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if payload.new_password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Password cannot be empty".to_string(),
+        ));
+    }
+
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    let slot = derive_keys(&vault, move |vault| {
+        vault.add_key_slot(
+            credentials(&payload.password, &keyfile),
+            &payload.new_password,
+            &payload.label,
+        )
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::Corruption(msg) => (StatusCode::BAD_REQUEST, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok((StatusCode::CREATED, Json(slot)))
+}
+
+async fn remove_key_slot(
+    State(state): State<AppState>,
+    axum::extract::Path(slot_id): axum::extract::Path<u32>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    derive_keys(&vault, move |vault| {
+        vault.remove_key_slot(credentials(&payload.password, &keyfile), slot_id)
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Key slot not found".to_string()),
+        VaultError::Corruption(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_decoy(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateDecoyRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if payload.duress_password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Password cannot be empty".to_string(),
+        ));
+    }
+
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    let recovery_key = derive_keys(&vault, move |vault| {
+        vault.create_decoy(
+            credentials(&payload.password, &keyfile),
+            &payload.duress_password,
+            payload.destroy_others,
+        )
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::Corruption(msg) => (StatusCode::BAD_REQUEST, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    // The decoy's recovery key is only ever shown here
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "recovery_key": recovery_key })),
+    ))
+}
+
+async fn remove_decoy(
+    State(state): State<AppState>,
+    Json(payload): Json<RemoveDecoyRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    vault
+        .remove_decoy(
+            credentials(&payload.password, &keyfile),
+            &payload.duress_password,
+        )
+        .await
+        .map_err(|e| match e {
+            VaultError::Throttled(_) | VaultError::LockedOut => {
+                (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+            }
+            VaultError::EncryptionError => {
+                (StatusCode::UNAUTHORIZED, "Invalid password".to_string())
+            }
+            VaultError::Corruption(msg) => (StatusCode::CONFLICT, msg),
+            VaultError::Busy(msg) => (StatusCode::CONFLICT, msg),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_sessions(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+    let current = current_session(&session, &state, &vault).await;
+    let sessions = state.sessions.list(vault.unlock_generation());
+
+    Ok(Json(serde_json::json!({
+        "current": current,
+        "sessions": sessions,
+    })))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !state.sessions.revoke(id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn audit_log(
+    State(state): State<AppState>,
+    Query(params): Query<AuditParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+    let limit = params.limit.unwrap_or(50).clamp(1, 500);
+
+    let records = vault
+        .audit_log(params.before, limit)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Pass as `before` for the next page; none once the oldest was returned
+    let next = records
+        .last()
+        .map(|record| record.seq)
+        .filter(|seq| *seq > 0 && records.len() == limit);
+
+    Ok(Json(serde_json::json!({
+        "records": records,
+        "next": next,
+    })))
+}
+
+async fn verify_audit_log(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let check = vault
+        .verify_audit_log()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(check))
+}
+
+async fn begin_totp(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    let enrollment = derive_keys(&vault, move |vault| {
+        vault.begin_totp(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::Corruption(msg) => (StatusCode::CONFLICT, msg),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(Json(enrollment))
+}
+
+async fn confirm_totp(
+    State(state): State<AppState>,
+    Json(payload): Json<CodeRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.write().await;
+
+    let backup_codes = vault
+        .confirm_totp(&payload.code, unix_now())
+        .map_err(|e| match e {
+            VaultError::EncryptionError => (StatusCode::BAD_REQUEST, "Invalid code".to_string()),
+            VaultError::NotFound(_) => (
+                StatusCode::NOT_FOUND,
+                "No two-factor enrollment in progress".to_string(),
+            ),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    // The backup codes are only ever shown here
+    Ok(Json(serde_json::json!({ "backup_codes": backup_codes })))
+}
+
+async fn disable_totp(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    derive_keys(&vault, move |vault| {
+        vault.disable_totp(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn regenerate_backup_codes(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let keyfile = decode_keyfile(payload.keyfile.as_deref())?;
+    let _changes = state.slot_changes.lock().await;
+    let vault = state.vault.read().await.clone();
+
+    let backup_codes = derive_keys(&vault, move |vault| {
+        vault.regenerate_backup_codes(credentials(&payload.password, &keyfile))
+    })
+    .await
+    .map_err(|e| match e {
+        VaultError::Throttled(_) | VaultError::LockedOut => {
+            (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+        }
+        VaultError::EncryptionError => (StatusCode::UNAUTHORIZED, "Invalid password".to_string()),
+        VaultError::NotFound(_) => (
+            StatusCode::NOT_FOUND,
+            "Two-factor authentication is not enabled".to_string(),
+        ),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+
+    Ok(Json(serde_json::json!({ "backup_codes": backup_codes })))
+}
+
 async fn list_images(
     State(state): State<AppState>,
     Query(params): Query<ListParams>,
@@ -234,13 +1040,20 @@ async fn list_images(
     Ok(Json(images))
 }
 
+/// Stores an uploaded image. Blobs are encrypted a chunk at a time, but the
+/// upload itself is buffered whole, as decoding it for the smaller variants
+/// needs all of it: memory per upload is bounded by `MAX_UPLOAD_MIB` only.
 async fn upload_image(
     State(state): State<AppState>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     let vault = state.vault.read().await;
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| (e.status(), e.body_text()))?
+    {
         let name = field.name().unwrap_or_default().to_string();
         let mime = field.content_type().unwrap_or("image/jpeg").to_string();
 
@@ -249,10 +1062,11 @@ async fn upload_image(
         }
 
         if name == "file" {
+            // Too large an upload is refused with 413
             let raw_data = field
                 .bytes()
                 .await
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                .map_err(|e| (e.status(), e.body_text()))?;
 
             // Process the image: strip metadata + generate all resolution variants
             let processed = image_processor::process_upload(&raw_data, &mime)
@@ -277,7 +1091,8 @@ async fn upload_image(
 async fn get_image(
     State(state): State<AppState>,
     axum::extract::Path((id, variant_name)): axum::extract::Path<(uuid::Uuid, String)>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
     let variant = ImageVariant::from_name(&variant_name).ok_or((
         StatusCode::BAD_REQUEST,
         format!("Invalid variant: {variant_name}"),
@@ -285,7 +1100,7 @@ async fn get_image(
 
     let vault = state.vault.read().await;
 
-    let (data, mime) = vault
+    let (blob, mime) = vault
         .retrieve_image(id, variant)
         .await
         .map_err(|e| match e {
@@ -293,33 +1108,95 @@ async fn get_image(
             _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
         })?;
 
-    // Vault images are immutable once stored so we can cache them aggressively
-    Ok((
+    // A blob written again gets a new ETag, so each one can be cached for good
+    blob_response(
+        blob,
+        &headers,
         [
-            (axum::http::header::CONTENT_TYPE, mime),
+            (header::CONTENT_TYPE, mime),
             (
-                axum::http::header::CACHE_CONTROL,
+                header::CACHE_CONTROL,
                 "private, max-age=31536000, immutable".to_string(),
             ),
         ],
-        data,
-    ))
+    )
+    .await
 }
 
 async fn delete_image(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
 ) -> Result<StatusCode, (StatusCode, String)> {
     let vault = state.vault.read().await;
 
-    vault.delete_image(id).await.map_err(|e| match e {
+    vault.trash_image(id).map_err(|e| match e {
         VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Image not found".to_string()),
         _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
     })?;
+    vault.audit(AuditEvent::Delete { id }, Some(client.ip()));
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+async fn list_trash(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let entries = vault
+        .list_trash()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(entries))
+}
+
+async fn restore_image(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let entry = vault.restore_image(id).map_err(|e| match e {
+        VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Image not in trash".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+    vault.audit(AuditEvent::Restore { id }, Some(client.ip()));
+
+    Ok(Json(entry))
+}
+
+async fn purge_image(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    vault.purge_image(id).await.map_err(|e| match e {
+        VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Image not in trash".to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })?;
+    vault.audit(AuditEvent::Purge { id }, Some(client.ip()));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn empty_trash(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let vault = state.vault.read().await;
+
+    let purged = vault
+        .purge_trash(u64::MAX, Some(client.ip()))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
 async fn add_tag(
     State(state): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
@@ -366,6 +1243,7 @@ async fn list_tags(
 
 async fn rename_tag(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     Json(payload): Json<RenameTagRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     let vault = state.vault.read().await;
@@ -376,6 +1254,11 @@ async fn rename_tag(
             VaultError::Corruption(msg) => (StatusCode::BAD_REQUEST, msg),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
         })?;
+    let event = AuditEvent::RenameTag {
+        old: payload.old_tag,
+        new: payload.new_tag,
+    };
+    vault.audit(event, Some(client.ip()));
 
     Ok(Json(serde_json::json!({ "renamed": count })))
 }
@@ -389,7 +1272,11 @@ async fn upload_to_linked_set(
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     let vault = state.vault.read().await;
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| (e.status(), e.body_text()))?
+    {
         let name = field.name().unwrap_or_default().to_string();
         let mime = field.content_type().unwrap_or("image/jpeg").to_string();
 
@@ -398,10 +1285,11 @@ async fn upload_to_linked_set(
         }
 
         if name == "file" {
+            // Too large an upload is refused with 413
             let raw_data = field
                 .bytes()
                 .await
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                .map_err(|e| (e.status(), e.body_text()))?;
 
             let processed = image_processor::process_upload(&raw_data, &mime)
                 .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
@@ -430,6 +1318,7 @@ async fn upload_to_linked_set(
 
 async fn remove_from_linked_set(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     axum::extract::Path((id, sub_id)): axum::extract::Path<(uuid::Uuid, uuid::Uuid)>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     let vault = state.vault.read().await;
@@ -441,6 +1330,7 @@ async fn remove_from_linked_set(
             VaultError::NotFound(_) => (StatusCode::NOT_FOUND, "Not found".to_string()),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
         })?;
+    vault.audit(AuditEvent::Delete { id: sub_id }, Some(client.ip()));
 
     Ok(Json(entry))
 }
@@ -452,7 +1342,8 @@ async fn get_linked_image(
         uuid::Uuid,
         String,
     )>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
     let variant = ImageVariant::from_name(&variant_name).ok_or((
         StatusCode::BAD_REQUEST,
         format!("Invalid variant: {variant_name}"),
@@ -460,7 +1351,7 @@ async fn get_linked_image(
 
     let vault = state.vault.read().await;
 
-    let (data, mime) = vault
+    let (blob, mime) = vault
         .retrieve_linked_image(id, sub_id, variant)
         .await
         .map_err(|e| match e {
@@ -468,21 +1359,25 @@ async fn get_linked_image(
             _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
         })?;
 
-    Ok((
+    blob_response(
+        blob,
+        &headers,
         [
-            (axum::http::header::CONTENT_TYPE, mime),
+            (header::CONTENT_TYPE, mime),
             (
-                axum::http::header::CACHE_CONTROL,
+                header::CACHE_CONTROL,
                 "private, max-age=31536000, immutable".to_string(),
             ),
         ],
-        data,
-    ))
+    )
+    .await
 }
 
 async fn download_image(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    headers: HeaderMap,
 ) -> Result<Response, (StatusCode, String)> {
     let vault = state.vault.read().await;
 
@@ -491,31 +1386,35 @@ async fn download_image(
         _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
     })?;
 
+    // Recorded only once there is something to send
     if entry.linked_images.is_empty() {
         // Single image — serve original directly
-        let (data, mime) = vault
+        let (blob, mime) = vault
             .retrieve_image(id, ImageVariant::Original)
             .await
             .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        vault.audit(AuditEvent::Download { id }, Some(client.ip()));
 
         let ext = mime_to_ext(&entry.original_mime);
-        Ok((
+        blob_response(
+            blob,
+            &headers,
             [
-                (axum::http::header::CONTENT_TYPE, mime),
+                (header::CONTENT_TYPE, mime),
                 (
-                    axum::http::header::CONTENT_DISPOSITION,
+                    header::CONTENT_DISPOSITION,
                     format!("attachment; filename=\"{id}.{ext}\""),
                 ),
             ],
-            data,
         )
-            .into_response())
+        .await
     } else {
         // Linked set — serve zip
         let zip_data = vault
             .download_linked_set(id)
             .await
             .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        vault.audit(AuditEvent::Download { id }, Some(client.ip()));
 
         Ok((
             [
@@ -528,8 +1427,315 @@ async fn download_image(
                     format!("attachment; filename=\"{id}.zip\""),
                 ),
             ],
-            zip_data,
+            // Owned by the body, so the archive is wiped once it has been sent
+            Body::from(Bytes::from_owner(zip_data)),
         )
             .into_response())
     }
 }
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a body of `size` bytes. Only a single
+/// `bytes` range is honoured; anything else gets the full body, which the
+/// spec allows a server to send instead.
+fn parse_range(value: &str, size: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        // Suffix range: the last n bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => size.saturating_sub(n)..size,
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => start..size,
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if range.start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// The range of a body of `size` bytes tagged `etag` the request asks for.
+fn requested_range(request_headers: &HeaderMap, etag: &str, size: u64) -> ByteRange {
+    // A stale If-Range means the client's partial copy is of something else
+    let range_allowed = match request_headers.get(header::IF_RANGE) {
+        Some(if_range) => if_range.as_bytes() == etag.as_bytes(),
+        None => true,
+    };
+    match request_headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(value) if range_allowed => parse_range(value, size),
+        _ => ByteRange::Full,
+    }
+}
+
+/// Streams a blob, or the byte range the request asks for as `206 Partial
+/// Content`. The ETag, which `If-Range` is checked against, comes from the
+/// blob as written, so a repaired blob is never taken for the one it
+/// replaced. Only the chunks covering the range are decrypted.
+async fn blob_response(
+    mut blob: BlobReader,
+    request_headers: &HeaderMap,
+    headers: impl IntoResponseParts,
+) -> Result<Response, (StatusCode, String)> {
+    let size = blob.size();
+    let etag = format!("\"{}\"", blob.revision());
+
+    let range = match requested_range(request_headers, &etag, size) {
+        ByteRange::Full => None,
+        ByteRange::Partial(range) => Some(range),
+        ByteRange::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response());
+        }
+    };
+
+    blob.select(range.clone().unwrap_or(0..size))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let common = [
+        (header::ETAG, etag),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ];
+    // Each chunk is wiped when hyper drops it after sending
+    let body = Body::from_stream(blob.into_stream().map_ok(Bytes::from_owner));
+
+    Ok(match range {
+        Some(range) => (
+            StatusCode::PARTIAL_CONTENT,
+            headers,
+            common,
+            [
+                (
+                    header::CONTENT_LENGTH,
+                    (range.end - range.start).to_string(),
+                ),
+                (
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        None => (
+            headers,
+            common,
+            [(header::CONTENT_LENGTH, size.to_string())],
+            body,
+        )
+            .into_response(),
+    })
+}
+
+/// Keyfiles travel base64-encoded in JSON bodies.
+fn decode_keyfile(
+    keyfile: Option<&str>,
+) -> Result<Option<Zeroizing<Vec<u8>>>, (StatusCode, String)> {
+    keyfile
+        .map(|keyfile| {
+            BASE64_STANDARD
+                .decode(keyfile)
+                .map(Zeroizing::new)
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid keyfile".to_string()))
+        })
+        .transpose()
+}
+
+/// Runs a vault call that checks a password or recovery key, or wraps a key
+/// slot, on the blocking pool. Each key it derives takes as long as the KDF
+/// was calibrated for, a second by default.
+async fn derive_keys<T: Send + 'static>(
+    vault: &Vault,
+    call: impl FnOnce(&Vault) -> Result<T, VaultError> + Send + 'static,
+) -> Result<T, VaultError> {
+    let vault = vault.clone();
+    tokio::task::spawn_blocking(move || call(&vault))
+        .await
+        .map_err(|e| VaultError::Io(std::io::Error::other(e)))?
+}
+
+fn credentials<'a>(password: &'a str, keyfile: &'a Option<Zeroizing<Vec<u8>>>) -> Credentials<'a> {
+    Credentials {
+        password,
+        keyfile: keyfile.as_deref().map(Vec::as_slice),
+    }
+}
+
+/// Lets `session` in after a password unlock, or only halfway when the
+/// unlocked library also wants a TOTP or backup code. Returns whether the
+/// second factor is still pending.
+async fn authenticate_session(
+    session: &Session,
+    state: &AppState,
+    vault: &Vault,
+    headers: &HeaderMap,
+) -> Result<bool, (StatusCode, String)> {
+    let pending = vault
+        .second_factor_required()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    register_session(session, state, vault, headers, pending).await?;
+    Ok(pending)
+}
+
+/// Adds `session` to the registry for the current unlock, authenticated or
+/// still `pending` its second factor.
+async fn register_session(
+    session: &Session,
+    state: &AppState,
+    vault: &Vault,
+    headers: &HeaderMap,
+    pending: bool,
+) -> Result<(), (StatusCode, String)> {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let id = state
+        .sessions
+        .register(vault.unlock_generation(), user_agent, pending);
+
+    let stored = async {
+        session.insert("session_id", id).await?;
+        session.insert("authenticated", !pending).await
+    };
+    stored.await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create session".to_string(),
+        )
+    })
+}
+
+/// The registry id of `session`, if it is still valid for the current unlock.
+async fn current_session(session: &Session, state: &AppState, vault: &Vault) -> Option<uuid::Uuid> {
+    let id = session
+        .get::<uuid::Uuid>("session_id")
+        .await
+        .ok()
+        .flatten()?;
+    state
+        .sessions
+        .touch(id, vault.unlock_generation())
+        .then_some(id)
+}
+
+/// The registry id of `session`, if it waits for the second factor of the
+/// current unlock.
+async fn pending_session(session: &Session, state: &AppState, vault: &Vault) -> Option<uuid::Uuid> {
+    let id = session
+        .get::<uuid::Uuid>("session_id")
+        .await
+        .ok()
+        .flatten()?;
+    state
+        .sessions
+        .is_pending(id, vault.unlock_generation())
+        .then_some(id)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0..100));
+        assert_eq!(
+            parse_range("bytes=990-2000", 1000),
+            ByteRange::Partial(990..1000)
+        );
+        // Open-ended and suffix ranges
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            ByteRange::Partial(0..1000)
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(
+            parse_range("bytes=1000-1099", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn serves_the_full_body_for_other_ranges() {
+        for value in [
+            "bytes=0-99,200-299",
+            "bytes=100-0",
+            "items=0-99",
+            "bytes=abc",
+            "bytes=0-x",
+        ] {
+            assert_eq!(parse_range(value, 1000), ByteRange::Full, "{}", value);
+        }
+    }
+
+    #[test]
+    fn honours_ranges_only_for_a_matching_if_range() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, "bytes=0-9".parse().unwrap());
+        assert_eq!(
+            requested_range(&headers, "\"a\"", 100),
+            ByteRange::Partial(0..10)
+        );
+
+        headers.insert(header::IF_RANGE, "\"a\"".parse().unwrap());
+        assert_eq!(
+            requested_range(&headers, "\"a\"", 100),
+            ByteRange::Partial(0..10)
+        );
+        // The body changed since the client got its partial copy
+        assert_eq!(requested_range(&headers, "\"b\"", 100), ByteRange::Full);
+    }
+}
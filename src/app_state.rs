@@ -1,18 +1,28 @@
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
-use crate::vault::{Vault, VaultError};
+use crate::{
+    config::Config,
+    sessions::SessionRegistry,
+    vault::{Vault, VaultError},
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub vault: Arc<RwLock<Vault>>,
+    pub sessions: Arc<SessionRegistry>,
+    // Changes to key slots and second factors, which check a password first,
+    // run one at a time
+    pub slot_changes: Arc<Mutex<()>>,
 }
 
 impl AppState {
-    pub fn new() -> Result<Self, VaultError> {
-        let vault = Vault::new()?;
+    pub fn new(config: &Config) -> Result<Self, VaultError> {
+        let vault = Vault::new(config.vault.clone())?;
         Ok(AppState {
             vault: Arc::new(RwLock::new(vault)),
+            sessions: Arc::new(SessionRegistry::default()),
+            slot_changes: Arc::new(Mutex::new(())),
         })
     }
 }
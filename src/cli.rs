@@ -0,0 +1,141 @@
+use crate::{
+    config::Config,
+    vault::{Credentials, FsckReport, Vault},
+};
+use std::{env, error::Error, io, path::PathBuf};
+use zeroize::Zeroizing;
+
+const USAGE: &str =
+    "Usage: vanta [fsck [--repair] | backup <file> | restore <file> | mirror <dir>]";
+
+/// Runs the command in `args` instead of the server.
+pub async fn run(config: &Config, args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["fsck"] => fsck(config, false).await,
+        ["fsck", "--repair"] => fsck(config, true).await,
+        ["backup", file] => backup(config, file).await,
+        ["restore", file] => restore(file).await,
+        ["mirror", dir] => mirror(config, dir).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Checks the library the password unlocks, which is read from
+/// `VAULT_PASSWORD` or else from standard input. Fails if anything is left
+/// to fix, so it can be scripted.
+async fn fsck(config: &Config, repair: bool) -> Result<(), Box<dyn Error>> {
+    let vault = Vault::new(config.vault.clone())?;
+    let password = match env::var("VAULT_PASSWORD") {
+        Ok(password) => Zeroizing::new(password),
+        Err(_) => {
+            eprintln!("Password:");
+            let mut line = Zeroizing::new(String::new());
+            io::stdin().read_line(&mut line)?;
+            Zeroizing::new(line.trim_end_matches(['\r', '\n']).to_string())
+        }
+    };
+
+    // The keyfile comes from KEYFILE_PATH, as for the server
+    vault.unlock(Credentials {
+        password: &password,
+        keyfile: None,
+    })?;
+    let report = vault.check(repair).await;
+    vault.shutdown()?;
+    let report = report?;
+
+    print_report(&report);
+    if !report.is_clean() {
+        return Err("The vault has problems left to fix".into());
+    }
+    Ok(())
+}
+
+/// Backs the vault up without unlocking it, as everything is copied still
+/// encrypted. The server must not be running, which opening the database
+/// checks.
+async fn backup(config: &Config, file: &str) -> Result<(), Box<dyn Error>> {
+    let vault = Vault::new(config.vault.clone())?;
+    let manifest = match vault.snapshot() {
+        Ok(snapshot) => vault.backup(snapshot, PathBuf::from(file)).await,
+        Err(e) => Err(e),
+    };
+    vault.shutdown()?;
+    let manifest = manifest?;
+
+    println!(
+        "Backed up {} files, {} bytes, to {}",
+        manifest.files.len(),
+        manifest.files.iter().map(|f| f.size).sum::<u64>(),
+        file
+    );
+    Ok(())
+}
+
+async fn restore(file: &str) -> Result<(), Box<dyn Error>> {
+    let manifest = Vault::restore_backup(PathBuf::from(file)).await?;
+    println!("Restored {} files", manifest.files.len());
+    Ok(())
+}
+
+/// Brings the mirror in `dir` up to date, without unlocking the vault. Fails
+/// if copied blobs did not verify, so it can be scripted.
+async fn mirror(config: &Config, dir: &str) -> Result<(), Box<dyn Error>> {
+    let vault = Vault::new(config.vault.clone())?;
+    let report = match vault.snapshot() {
+        Ok(snapshot) => vault.mirror(snapshot, PathBuf::from(dir)).await,
+        Err(e) => Err(e),
+    };
+    vault.shutdown()?;
+    let report = report?;
+
+    println!(
+        "Copied {} blobs, {} bytes, and {} records; verified {}",
+        report.copied, report.bytes, report.records, report.verified
+    );
+    println!(
+        "Removed {} blobs gone from the vault, keeping {} for now",
+        report.removed, report.retained
+    );
+    for blob in &report.corrupt {
+        println!("Corrupt copy {}, copied again next run", blob);
+    }
+    if !report.corrupt.is_empty() {
+        return Err("Some copies did not verify".into());
+    }
+    Ok(())
+}
+
+fn print_report(report: &FsckReport) {
+    println!(
+        "Checked {} entries and {} blobs",
+        report.entries, report.blobs
+    );
+    if report.corrupt_entries > 0 {
+        println!("{} entries do not decode", report.corrupt_entries);
+    }
+    for problem in &report.problems {
+        let image = match problem.linked {
+            Some(linked) => format!("{} (linked {})", problem.entry, linked),
+            None => problem.entry.to_string(),
+        };
+        println!(
+            "{} {}: {:?}{}",
+            image,
+            problem.variant.filename(),
+            problem.issue,
+            if problem.repaired { ", repaired" } else { "" }
+        );
+    }
+    for orphan in &report.orphans {
+        println!(
+            "Orphan {}{}",
+            orphan,
+            if report.quarantined {
+                ", quarantined"
+            } else {
+                ""
+            }
+        );
+    }
+}
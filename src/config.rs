@@ -0,0 +1,118 @@
+use crate::vault::{BlobPadding, KdfParams};
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+/// Runtime settings, read once from the environment at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub vault: VaultConfig,
+    /// Largest request body accepted, in bytes. Uploads are held in memory
+    /// whole while they are processed, so this bounds what each one takes.
+    pub max_upload: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct VaultConfig {
+    /// How long key derivation should take on this host, used to calibrate at setup
+    pub kdf_target: Duration,
+    /// Upper bound on the memory calibration may choose, in KiB
+    pub kdf_max_memory: u32,
+    /// Upper bound on the iterations calibration may choose
+    pub kdf_max_iterations: u32,
+    /// Slots wrapped with weaker parameters are upgraded on the next unlock
+    pub kdf_min: KdfParams,
+    /// Padding applied to blobs as they are written
+    pub blob_padding: BlobPadding,
+    /// Keyfile used for unlocking when a request does not upload one
+    pub keyfile_path: Option<PathBuf>,
+    /// Locks the vault after this long without authenticated requests
+    pub idle_lock: Option<Duration>,
+    /// Locks the vault this long after it was unlocked, however busy it is
+    pub max_unlock: Option<Duration>,
+    /// Failed attempts after which only the recovery key unlocks
+    pub lockout_after: Option<u32>,
+    /// Failed attempts after which every key slot is wiped. That includes
+    /// the slots of decoy libraries: a wrong password belongs to none of
+    /// them, so there is no telling which one was attacked.
+    pub self_destruct_after: Option<u32>,
+    /// How often orphaned blobs are collected while the vault is unlocked,
+    /// besides right after each unlock
+    pub gc_interval: Option<Duration>,
+    /// Orphans modified more recently than this may belong to an upload in
+    /// progress and are left alone
+    pub gc_grace: Duration,
+    /// How long deleted images stay in the trash before they are purged
+    pub trash_retention: Option<Duration>,
+    /// Where backups taken through the API are written
+    pub backup_dir: PathBuf,
+    /// Mirror kept up to date through the API
+    pub mirror_dir: Option<PathBuf>,
+    /// How long a mirror keeps blobs after they are gone from the vault
+    pub mirror_retention: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = KdfParams::default();
+
+        Config {
+            vault: VaultConfig {
+                kdf_target: Duration::from_millis(env_or("KDF_TARGET_MS", 1000)),
+                kdf_max_memory: env_or::<u32>("KDF_MAX_MEMORY_MIB", 256).saturating_mul(1024),
+                kdf_max_iterations: env_or("KDF_MAX_ITERATIONS", 16),
+                kdf_min: KdfParams {
+                    m_cost: env_or::<u32>("KDF_MIN_MEMORY_MIB", defaults.m_cost / 1024)
+                        .saturating_mul(1024),
+                    t_cost: env_or("KDF_MIN_ITERATIONS", defaults.t_cost),
+                    ..defaults
+                },
+                blob_padding: env_or("BLOB_PADDING", BlobPadding::Padme),
+                keyfile_path: env::var_os("KEYFILE_PATH").map(PathBuf::from),
+                idle_lock: env_minutes("IDLE_LOCK_MINUTES", 30),
+                max_unlock: env_minutes("MAX_UNLOCK_MINUTES", 0),
+                lockout_after: env_limit("UNLOCK_LOCKOUT_AFTER"),
+                self_destruct_after: env_limit("SELF_DESTRUCT_AFTER"),
+                gc_interval: env_minutes("GC_INTERVAL_MINUTES", 24 * 60),
+                gc_grace: env_minutes("GC_GRACE_MINUTES", 60).unwrap_or_default(),
+                trash_retention: env_days("TRASH_RETENTION_DAYS", 30),
+                backup_dir: env::var_os("BACKUP_DIR")
+                    .map_or_else(|| PathBuf::from("backups"), PathBuf::from),
+                mirror_dir: env::var_os("MIRROR_DIR").map(PathBuf::from),
+                mirror_retention: env_days("MIRROR_RETENTION_DAYS", 30).unwrap_or_default(),
+            },
+            max_upload: env_or::<usize>("MAX_UPLOAD_MIB", 50).saturating_mul(1024 * 1024),
+        }
+    }
+}
+
+/// Reads and parses an environment variable, falling back to `default` when it
+/// is unset or invalid.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring invalid value for {}: {}", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// An optional attempt limit, off when unset or 0.
+fn env_limit(name: &str) -> Option<u32> {
+    Some(env_or(name, 0)).filter(|limit| *limit > 0)
+}
+
+/// A duration in minutes, where 0 turns the feature off.
+fn env_minutes(name: &str, default: u64) -> Option<Duration> {
+    match env_or(name, default) {
+        0 => None,
+        minutes => Some(Duration::from_secs(minutes.saturating_mul(60))),
+    }
+}
+
+/// A duration in days, where 0 turns the feature off.
+fn env_days(name: &str, default: u64) -> Option<Duration> {
+    match env_or(name, default) {
+        0 => None,
+        days => Some(Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+    }
+}
@@ -1,12 +1,15 @@
 mod api;
 mod app_state;
+mod cli;
+mod config;
 mod image_processor;
 mod router;
+mod sessions;
 mod vault;
 
 use app_state::AppState;
-use std::env;
-use time::Duration;
+use config::Config;
+use std::{env, net::SocketAddr};
 use tokio::{net::TcpListener, signal};
 use tower_sessions::{Expiry, MemoryStore, SessionManagerLayer};
 
@@ -40,7 +43,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let host = env::var("HOST").unwrap_or("0.0.0.0".to_string());
     let addr = format!("{}:{}", host, port);
 
-    let state = match AppState::new() {
+    let config = Config::from_env();
+
+    // `vanta fsck` and friends run against the vault instead of serving it
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&config, &args).await;
+    }
+
+    let state = match AppState::new(&config) {
         Ok(state) => state,
         Err(e) => {
             eprintln!("Failed to initialize app state: {}", e);
@@ -48,13 +59,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    state.vault.read().await.spawn_auto_lock();
+    state.vault.read().await.spawn_gc();
+    state.vault.read().await.spawn_trash_purge();
+
     // Session Store
     let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store)
         .with_secure(false) // For local development. Set to true in prod with HTTPS
-        .with_expiry(Expiry::OnInactivity(Duration::minutes(30)));
+        .with_expiry(Expiry::OnInactivity(time::Duration::try_from(
+            sessions::SESSION_IDLE,
+        )?));
 
-    let router = router::get_router(state.clone()).layer(session_layer);
+    let router = router::get_router(state.clone(), &config).layer(session_layer);
 
     let listener = match TcpListener::bind(&addr).await {
         Ok(listener) => listener,
@@ -66,7 +83,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Listening at {}", addr);
 
-    if let Err(e) = axum::serve(listener, router)
+    // Client addresses end up in the audit log
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service)
         .with_graceful_shutdown(shutdown_signal())
         .await
     {
@@ -4,8 +4,9 @@ use tower_http::services::{ServeDir, ServeFile};
 
 use crate::api::get_api_router;
 use crate::app_state::AppState;
+use crate::config::Config;
 
-pub fn get_router(state: AppState) -> Router {
+pub fn get_router(state: AppState, config: &Config) -> Router {
     // Serve the SolidJS SPA from frontend/dist, with SPA fallback to index.html
     let spa = ServeDir::new("frontend/dist")
         .not_found_service(ServeFile::new("frontend/dist/index.html"));
@@ -13,5 +14,5 @@ pub fn get_router(state: AppState) -> Router {
     Router::new()
         .nest("/api", get_api_router(state))
         .fallback_service(spa)
-        .layer(DefaultBodyLimit::max(50 * 1024 * 1024)) // 50 MB max for uploads
+        .layer(DefaultBodyLimit::max(config.max_upload))
 }
@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// Sessions expire after this long without a request
+pub const SESSION_IDLE: Duration = Duration::from_secs(30 * 60);
+
+/// Every authenticated session, so they can be listed and revoked. Each one
+/// belongs to the vault unlock it was authenticated against and stops
+/// counting once the vault is locked. Sessions still waiting for their second
+/// factor are kept here too, so revoking sessions ends them as well.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<Uuid, SessionInfo>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+    #[serde(skip)]
+    generation: u64,
+    #[serde(skip)]
+    pending: bool,
+}
+
+impl SessionRegistry {
+    /// Registers a session authenticated during unlock `generation`. A
+    /// `pending` one has only given the password and counts once confirmed.
+    pub fn register(&self, generation: u64, user_agent: Option<String>, pending: bool) -> Uuid {
+        let now = unix_now();
+        let info = SessionInfo {
+            id: Uuid::new_v4(),
+            created_at: now,
+            last_seen: now,
+            user_agent,
+            generation,
+            pending,
+        };
+        let id = info.id;
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(id, info);
+        }
+        id
+    }
+
+    /// Whether `id` is still valid for unlock `generation`, recording the
+    /// request if so.
+    pub fn touch(&self, id: Uuid, generation: u64) -> bool {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return false;
+        };
+        Self::prune(&mut sessions, generation);
+        match sessions.get_mut(&id) {
+            Some(info) if !info.pending => {
+                info.last_seen = unix_now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `id` waits for its second factor during unlock `generation`.
+    pub fn is_pending(&self, id: Uuid, generation: u64) -> bool {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return false;
+        };
+        Self::prune(&mut sessions, generation);
+        sessions.get(&id).is_some_and(|info| info.pending)
+    }
+
+    /// Counts a pending session as authenticated once its second factor was
+    /// given. Fails if the vault was locked or unlocked again in between, or
+    /// the session revoked.
+    pub fn confirm(&self, id: Uuid, generation: u64) -> bool {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return false;
+        };
+        Self::prune(&mut sessions, generation);
+        match sessions.get_mut(&id) {
+            Some(info) if info.pending => {
+                info.pending = false;
+                info.last_seen = unix_now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The sessions still valid for unlock `generation`, oldest first.
+    pub fn list(&self, generation: u64) -> Vec<SessionInfo> {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return Vec::new();
+        };
+        Self::prune(&mut sessions, generation);
+        let mut list: Vec<SessionInfo> = sessions
+            .values()
+            .filter(|info| !info.pending)
+            .cloned()
+            .collect();
+        list.sort_by_key(|info| info.created_at);
+        list
+    }
+
+    /// Returns whether `id` was registered.
+    pub fn revoke(&self, id: Uuid) -> bool {
+        self.sessions
+            .lock()
+            .is_ok_and(|mut sessions| sessions.remove(&id).is_some())
+    }
+
+    /// Revokes every session but `keep`, e.g. after the password changed,
+    /// pending ones included.
+    pub fn revoke_others(&self, keep: Option<Uuid>) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.retain(|id, _| Some(*id) == keep);
+        }
+    }
+
+    /// Drops sessions from an earlier unlock and those the session store has
+    /// expired by now.
+    fn prune(sessions: &mut HashMap<Uuid, SessionInfo>, generation: u64) {
+        let cutoff = unix_now().saturating_sub(SESSION_IDLE.as_secs());
+        sessions.retain(|_, info| info.generation == generation && info.last_seen >= cutoff);
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_end_with_their_unlock() {
+        let registry = SessionRegistry::default();
+        let first = registry.register(1, Some("browser".into()), false);
+        let second = registry.register(1, None, false);
+        assert!(registry.touch(first, 1));
+        assert_eq!(registry.list(1).len(), 2);
+
+        registry.revoke_others(Some(second));
+        assert!(!registry.touch(first, 1));
+        assert!(registry.touch(second, 1));
+
+        // Locking the vault moves it to another generation
+        assert!(!registry.touch(second, 2));
+        assert!(registry.list(1).is_empty());
+        assert!(!registry.revoke(second));
+    }
+
+    #[test]
+    fn pending_sessions_count_once_confirmed() {
+        let registry = SessionRegistry::default();
+        let pending = registry.register(1, None, true);
+        assert!(registry.is_pending(pending, 1));
+        assert!(!registry.touch(pending, 1));
+        assert!(registry.list(1).is_empty());
+
+        // Not for a later unlock, nor once revoked
+        assert!(!registry.confirm(pending, 2));
+        let pending = registry.register(2, None, true);
+        registry.revoke_others(None);
+        assert!(!registry.confirm(pending, 2));
+
+        let pending = registry.register(2, None, true);
+        assert!(registry.confirm(pending, 2));
+        assert!(!registry.is_pending(pending, 2));
+        assert!(registry.touch(pending, 2));
+        assert_eq!(registry.list(2).len(), 1);
+    }
+}
@@ -0,0 +1,169 @@
+use super::{
+    Vault,
+    crypto::{self, VaultKeys},
+    error::VaultError,
+    types::{AuditCheck, AuditEvent, AuditRecord, AuditState, ChainedAuditRecord},
+};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+/// Name of the setting holding a library's audit log state
+pub(super) const AUDIT_SETTING: &str = "audit";
+
+impl Vault {
+    /// Appends `event` to the audit log of the unlocked library. Does nothing
+    /// while the vault is locked, and a record that cannot be written is
+    /// reported rather than failing what it records.
+    pub fn audit(&self, event: AuditEvent, ip: Option<IpAddr>) {
+        if let Ok(keys) = self.unlocked_keys() {
+            self.record_audit(&keys, event, ip);
+        }
+    }
+
+    /// Like `audit`, for the library `keys` belong to.
+    pub(super) fn record_audit(&self, keys: &VaultKeys, event: AuditEvent, ip: Option<IpAddr>) {
+        if let Err(e) = self.append_audit(keys, event, ip) {
+            eprintln!("Failed to write audit record: {}", e);
+        }
+    }
+
+    /// Up to `limit` records, newest first, starting below sequence number
+    /// `before` if given.
+    pub fn audit_log(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<AuditRecord>, VaultError> {
+        let keys = self.unlocked_keys()?;
+        let Some(state) = self.db.get_setting::<AuditState>(&keys, AUDIT_SETTING)? else {
+            return Ok(Vec::new());
+        };
+
+        let end = before.map_or(state.next_seq, |before| before.min(state.next_seq));
+        (0..end)
+            .rev()
+            .take(limit)
+            .map(|seq| Ok(self.read_audit_record(&state, seq)?.0.record))
+            .collect()
+    }
+
+    /// Walks the whole hash chain, checking that no record is missing,
+    /// altered or out of order and that none were cut off the end.
+    pub fn verify_audit_log(&self) -> Result<AuditCheck, VaultError> {
+        let keys = self.unlocked_keys()?;
+        let Some(state) = self.db.get_setting::<AuditState>(&keys, AUDIT_SETTING)? else {
+            return Ok(AuditCheck {
+                records: 0,
+                intact: true,
+                broken_at: None,
+            });
+        };
+
+        let broken = |seq| AuditCheck {
+            records: state.next_seq,
+            intact: false,
+            broken_at: Some(seq),
+        };
+        let mut prev_hash = [0; 32];
+        for seq in 0..state.next_seq {
+            match self.read_audit_record(&state, seq) {
+                Ok((chained, hash))
+                    if chained.record.seq == seq && chained.prev_hash == prev_hash =>
+                {
+                    prev_hash = hash
+                }
+                _ => return Ok(broken(seq)),
+            }
+        }
+        // Records past the end mean the state was rolled back
+        let after = crypto::audit_row(&state.key, state.next_seq)?;
+        if prev_hash != state.head || self.db.get_audit_record(&after)?.is_some() {
+            return Ok(broken(state.next_seq));
+        }
+
+        Ok(AuditCheck {
+            records: state.next_seq,
+            intact: true,
+            broken_at: None,
+        })
+    }
+
+    /// Removes the audit log of the library `keys` belong to, with its state.
+    pub(super) fn remove_audit_log(&self, keys: &VaultKeys) -> Result<(), VaultError> {
+        let _guard = self
+            .audit_lock
+            .lock()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+        if let Some(state) = self.db.get_setting::<AuditState>(keys, AUDIT_SETTING)? {
+            for seq in 0..state.next_seq {
+                self.db
+                    .remove_audit_record(&crypto::audit_row(&state.key, seq)?)?;
+            }
+        }
+        self.db.remove_setting(keys, AUDIT_SETTING)
+    }
+
+    fn append_audit(
+        &self,
+        keys: &VaultKeys,
+        event: AuditEvent,
+        ip: Option<IpAddr>,
+    ) -> Result<(), VaultError> {
+        // One append at a time, as each one builds on the state of the last
+        let _guard = self
+            .audit_lock
+            .lock()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+        let mut state = match self.db.get_setting::<AuditState>(keys, AUDIT_SETTING)? {
+            Some(state) => state,
+            None => AuditState {
+                key: rand::random(),
+                next_seq: 0,
+                head: [0; 32],
+            },
+        };
+
+        let chained = ChainedAuditRecord {
+            record: AuditRecord {
+                seq: state.next_seq,
+                at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                ip,
+                event,
+            },
+            prev_hash: state.head,
+        };
+        let bytes = Zeroizing::new(postcard::to_stdvec(&chained)?);
+        let row = crypto::audit_row(&state.key, state.next_seq)?;
+        let key = crypto::derive_subkey(&state.key, b"vanta/audit")?;
+        let encrypted = crypto::encrypt(key.expose_secret(), &bytes, &row)?;
+
+        state.head = Sha256::digest(&*bytes).into();
+        state.next_seq += 1;
+        self.db
+            .append_audit_record(keys, AUDIT_SETTING, &state, &row, &encrypted)
+    }
+
+    /// Record `seq` with the hash the next one chains to.
+    fn read_audit_record(
+        &self,
+        state: &AuditState,
+        seq: u64,
+    ) -> Result<(ChainedAuditRecord, [u8; 32]), VaultError> {
+        let row = crypto::audit_row(&state.key, seq)?;
+        let encrypted = self
+            .db
+            .get_audit_record(&row)?
+            .ok_or_else(|| VaultError::NotFound(format!("Audit record {}", seq)))?;
+        let key = crypto::derive_subkey(&state.key, b"vanta/audit")?;
+        let bytes = Zeroizing::new(crypto::decrypt(key.expose_secret(), &encrypted, &row)?);
+        Ok((
+            postcard::from_bytes(&bytes)?,
+            Sha256::digest(&*bytes).into(),
+        ))
+    }
+}
@@ -0,0 +1,89 @@
+use super::{Vault, VaultData, types::AuditEvent};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+/// How often the background task checks for an expired unlock
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Vault {
+    /// Records authenticated activity, which pushes the idle lock back.
+    pub fn touch(&self) {
+        if let Ok(mut last_activity) = self.last_activity.lock() {
+            *last_activity = Instant::now();
+        }
+    }
+
+    /// Time left at `now` before the vault locks itself, or `None` while it is
+    /// locked or no limit is configured.
+    pub fn auto_lock_remaining(&self, now: Instant) -> Option<Duration> {
+        let lock = self.data.read().ok()?;
+        self.remaining(lock.as_ref()?, now)
+    }
+
+    /// Locks the vault if its idle period or maximum unlock duration ran out by
+    /// `now`. Returns whether it did.
+    pub fn auto_lock(&self, now: Instant) -> bool {
+        if !self
+            .auto_lock_remaining(now)
+            .is_some_and(|left| left.is_zero())
+        {
+            return false;
+        }
+        // Checked again under the write lock, as a request may have come in
+        let Ok(mut lock) = self.data.write() else {
+            return false;
+        };
+        let expired = lock
+            .as_ref()
+            .and_then(|data| self.remaining(data, now))
+            .is_some_and(|left| left.is_zero());
+        if expired {
+            if let Some(data) = lock.as_ref() {
+                self.record_audit(&data.keys, AuditEvent::AutoLock, None);
+            }
+            *lock = None;
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+        expired
+    }
+
+    /// Starts the task that locks the vault once it expires. Does nothing when
+    /// neither limit is configured.
+    pub fn spawn_auto_lock(&self) {
+        if self.config.idle_lock.is_none() && self.config.max_unlock.is_none() {
+            return;
+        }
+
+        let vault = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if vault.auto_lock(Instant::now()) {
+                    println!("Vault locked automatically");
+                }
+            }
+        });
+    }
+
+    fn remaining(&self, data: &VaultData, now: Instant) -> Option<Duration> {
+        // Activity from before this unlock does not count
+        let last_activity = self
+            .last_activity
+            .lock()
+            .map_or(data.unlocked_at, |last| *last)
+            .max(data.unlocked_at);
+
+        let idle = self
+            .config
+            .idle_lock
+            .map(|limit| (last_activity + limit).saturating_duration_since(now));
+        let max = self
+            .config
+            .max_unlock
+            .map(|limit| (data.unlocked_at + limit).saturating_duration_since(now));
+        idle.into_iter().chain(max).min()
+    }
+}
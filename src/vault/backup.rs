@@ -0,0 +1,309 @@
+use super::{
+    DATA_DIR, DATABASE_DIR, Vault,
+    db::Database,
+    error::VaultError,
+    types::{BackupFile, BackupManifest, DbTree},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+use zip::{CompressionMethod, ZipArchive, ZipWriter, result::ZipError, write::SimpleFileOptions};
+
+/// Layout of the archives written, bumped whenever it changes
+const BACKUP_FORMAT: u32 = 1;
+const MANIFEST_NAME: &str = "manifest.json";
+/// The exported database trees
+const DATABASE_NAME: &str = "db.bin";
+/// Blobs are stored under this prefix, with their path in `DATA_DIR`
+const STORAGE_PREFIX: &str = "storage/";
+/// Holds `DATABASE_DIR` and `DATA_DIR`
+const VAULT_DIR: &str = "vault";
+/// Where a restore builds the vault before moving it into place
+const RESTORE_DIR: &str = "vault.restore";
+/// Holds the blobs of snapshots still being copied
+pub(super) const SNAPSHOT_DIR: &str = "vault/snapshots";
+
+/// The vault as it was at one point: its database trees, and a hard link to
+/// every blob, which later writes and removals leave alone. The links are
+/// removed once it is dropped.
+pub struct Snapshot {
+    pub(super) trees: Vec<DbTree>,
+    pub(super) dir: PathBuf,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Vault {
+    /// Takes a snapshot for `backup` or `mirror` to copy. Nothing may be
+    /// written to the vault meanwhile, but that only lasts as long as linking
+    /// the blobs and reading the database, not as long as copying them.
+    ///
+    /// Blobs are linked before the database is exported, so every entry in
+    /// the snapshot finds its blobs in it even if some are removed in
+    /// between; those are left over as orphans. A rotation of another
+    /// library waits meanwhile, as it rewrites blobs along with entries.
+    pub fn snapshot(&self) -> Result<Snapshot, VaultError> {
+        // A rotation renames blobs as it goes
+        if self.is_busy() {
+            return Err(VaultError::Busy("Re-encryption in progress".into()));
+        }
+        let _step = self
+            .rotation_step
+            .lock()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+        let mut snapshot = Snapshot {
+            trees: Vec::new(),
+            dir: Path::new(SNAPSHOT_DIR).join(Uuid::new_v4().to_string()),
+        };
+        for blob in Self::storage_files(Path::new(DATA_DIR))? {
+            let relative = blob.strip_prefix(DATA_DIR).map_err(io::Error::other)?;
+            let linked = snapshot.dir.join(relative);
+            if let Some(dir) = linked.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            match fs::hard_link(&blob, &linked) {
+                // Removed since it was listed
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                result => result?,
+            }
+        }
+        fs::create_dir_all(&snapshot.dir)?;
+        snapshot.trees = self.db.export_trees()?;
+        Ok(snapshot)
+    }
+
+    /// Writes `snapshot` to a zip archive at `path`: the database and every
+    /// blob exactly as stored, so the backup is encrypted under the keys of
+    /// the vault. A manifest lists every file in it with its checksum.
+    pub async fn backup(
+        &self,
+        snapshot: Snapshot,
+        path: PathBuf,
+    ) -> Result<BackupManifest, VaultError> {
+        tokio::task::spawn_blocking(move || Self::write_backup(&snapshot, &path))
+            .await
+            .map_err(|e| VaultError::Io(io::Error::other(e)))?
+    }
+
+    /// A new archive in `backup_dir`, named after the current time.
+    pub fn backup_path(&self) -> Result<PathBuf, VaultError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(self.config.backup_dir.join(format!("vanta-{}.zip", now)))
+    }
+
+    /// Rebuilds the vault directory from an archive `backup` wrote, after
+    /// checking every file in it against the manifest. Refuses to overwrite
+    /// an existing vault, and leaves nothing behind if the archive is bad.
+    pub async fn restore_backup(archive: PathBuf) -> Result<BackupManifest, VaultError> {
+        tokio::task::spawn_blocking(move || Self::read_backup(&archive))
+            .await
+            .map_err(|e| VaultError::Io(io::Error::other(e)))?
+    }
+
+    fn write_backup(snapshot: &Snapshot, path: &Path) -> Result<BackupManifest, VaultError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_extension("tmp");
+        let mut zip = ZipWriter::new(File::create(&temp)?);
+        let mut manifest = BackupManifest {
+            format: BACKUP_FORMAT,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            files: Vec::new(),
+        };
+
+        for blob in Self::storage_files(&snapshot.dir)? {
+            let mut file = File::open(&blob)?;
+            let relative = blob.strip_prefix(&snapshot.dir).map_err(io::Error::other)?;
+            let name = format!(
+                "{}{}",
+                STORAGE_PREFIX,
+                relative.to_string_lossy().replace('\\', "/")
+            );
+            let size = file.metadata()?.len();
+            zip.start_file(name.as_str(), Self::zip_options(size))
+                .map_err(zip_error)?;
+            manifest
+                .files
+                .push(Self::copy_hashed(name, &mut file, &mut zip)?);
+        }
+
+        let trees = postcard::to_stdvec(&snapshot.trees)?;
+        zip.start_file(DATABASE_NAME, Self::zip_options(trees.len() as u64))
+            .map_err(zip_error)?;
+        let database = Self::copy_hashed(DATABASE_NAME.into(), &mut &trees[..], &mut zip)?;
+        manifest.files.push(database);
+
+        zip.start_file(MANIFEST_NAME, Self::zip_options(0))
+            .map_err(zip_error)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest).map_err(io::Error::other)?;
+        zip.finish().map_err(zip_error)?.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(manifest)
+    }
+
+    fn read_backup(archive: &Path) -> Result<BackupManifest, VaultError> {
+        if Path::new(VAULT_DIR).exists() {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "Move the existing vault directory away before restoring",
+            )
+            .into());
+        }
+
+        let mut zip = ZipArchive::new(File::open(archive)?).map_err(zip_error)?;
+        let manifest: BackupManifest =
+            serde_json::from_reader(zip.by_name(MANIFEST_NAME).map_err(zip_error)?)
+                .map_err(|e| VaultError::Corruption(format!("Invalid manifest: {}", e)))?;
+        if manifest.format != BACKUP_FORMAT {
+            return Err(VaultError::Corruption(format!(
+                "Unsupported backup format {}",
+                manifest.format
+            )));
+        }
+        let listed: HashSet<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        if listed.len() != manifest.files.len()
+            || !listed.contains(DATABASE_NAME)
+            || zip
+                .file_names()
+                .any(|name| name != MANIFEST_NAME && !listed.contains(name))
+        {
+            return Err(VaultError::Corruption(
+                "The archive does not match its manifest".into(),
+            ));
+        }
+
+        // Left over by an interrupted restore
+        if let Err(e) = fs::remove_dir_all(RESTORE_DIR)
+            && e.kind() != ErrorKind::NotFound
+        {
+            return Err(e.into());
+        }
+        if let Err(e) = Self::extract_backup(&mut zip, &manifest) {
+            let _ = fs::remove_dir_all(RESTORE_DIR);
+            return Err(e);
+        }
+        fs::rename(RESTORE_DIR, VAULT_DIR)?;
+        Ok(manifest)
+    }
+
+    /// Extracts the blobs and the database into `RESTORE_DIR`, verifying
+    /// each against the manifest.
+    fn extract_backup(
+        zip: &mut ZipArchive<File>,
+        manifest: &BackupManifest,
+    ) -> Result<(), VaultError> {
+        fs::create_dir_all(Self::restored_path(DATA_DIR))?;
+        let mut trees = Vec::new();
+        for expected in &manifest.files {
+            let mut file = zip.by_name(&expected.path).map_err(zip_error)?;
+            let copied = if expected.path == DATABASE_NAME {
+                Self::copy_hashed(expected.path.clone(), &mut file, &mut trees)?
+            } else {
+                let target = Self::restored_blob_path(&expected.path)?;
+                if let Some(dir) = target.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut out = File::create(&target)?;
+                let copied = Self::copy_hashed(expected.path.clone(), &mut file, &mut out)?;
+                out.sync_all()?;
+                copied
+            };
+            if copied.size != expected.size || copied.sha256 != expected.sha256 {
+                return Err(VaultError::Corruption(format!(
+                    "{} does not match its checksum",
+                    expected.path
+                )));
+            }
+        }
+
+        let trees: Vec<DbTree> = postcard::from_bytes(&trees)?;
+        Database::import_trees(&Self::restored_path(DATABASE_DIR), trees)
+    }
+
+    /// Where a blob listed under `name` in the archive is restored to.
+    /// Names that would lead out of the storage directory are rejected.
+    fn restored_blob_path(name: &str) -> Result<PathBuf, VaultError> {
+        let relative = name
+            .strip_prefix(STORAGE_PREFIX)
+            .map(Path::new)
+            .filter(|path| {
+                path.components().next().is_some()
+                    && path.components().all(|c| matches!(c, Component::Normal(_)))
+            })
+            .ok_or_else(|| VaultError::Corruption(format!("Invalid path in archive: {}", name)))?;
+        Ok(Path::new(&Self::restored_path(DATA_DIR)).join(relative))
+    }
+
+    fn restored_path(path: &str) -> String {
+        path.replacen(VAULT_DIR, RESTORE_DIR, 1)
+    }
+
+    /// Every file below `dir`, except the temporary ones of writes in progress.
+    pub(super) fn storage_files(dir: &Path) -> Result<Vec<PathBuf>, VaultError> {
+        let mut files = Vec::new();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(files),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                files.extend(Self::storage_files(&path)?);
+            } else if path.extension().is_none_or(|ext| ext != "tmp") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Copies `reader` into `writer`, describing what was copied as `path`.
+    pub(super) fn copy_hashed(
+        path: String,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> Result<BackupFile, VaultError> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            writer.write_all(&buf[..read])?;
+            size += read as u64;
+        }
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(BackupFile { path, size, sha256 })
+    }
+
+    /// Blobs are encrypted and would not compress anyway.
+    fn zip_options(size: u64) -> SimpleFileOptions {
+        SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(size >= u32::MAX as u64)
+    }
+}
+
+fn zip_error(e: ZipError) -> VaultError {
+    VaultError::Zip(e.to_string())
+}
@@ -0,0 +1,358 @@
+use super::{
+    crypto::{
+        self, BlobPadding, STREAM_CHUNK_SIZE, STREAM_HEADER_LEN, StreamDecryptor, StreamEncryptor,
+    },
+    error::VaultError,
+};
+use futures_util::Stream;
+use std::{io::Write, ops::Range, path::Path};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+use zeroize::Zeroizing;
+
+/// Writes an encrypted blob chunk by chunk, so a variant never has to exist
+/// in memory as ciphertext alongside its plaintext. The blob only appears at
+/// its path once finished and synced, so a crash never leaves a truncated one.
+pub struct BlobWriter {
+    file: File,
+    path: String,
+    encryptor: StreamEncryptor,
+    padding: BlobPadding,
+}
+
+impl BlobWriter {
+    pub async fn create(
+        path: &str,
+        key: &[u8],
+        aad: &[u8],
+        padding: BlobPadding,
+    ) -> Result<Self, VaultError> {
+        let encryptor = StreamEncryptor::new(key, aad)?;
+        let mut file = File::create(temp_path(path)).await?;
+        file.write_all(encryptor.header()).await?;
+        Ok(Self {
+            file,
+            path: path.to_string(),
+            encryptor,
+            padding,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), VaultError> {
+        for part in data.chunks(STREAM_CHUNK_SIZE) {
+            let sealed = self.encryptor.update(part)?;
+            self.file.write_all(&sealed).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<(), VaultError> {
+        let mut zeros = self.encryptor.padding_len(self.padding);
+        while zeros > 0 {
+            let part = zeros.min(STREAM_CHUNK_SIZE as u64);
+            let sealed = self.encryptor.pad(part as usize)?;
+            self.file.write_all(&sealed).await?;
+            zeros -= part;
+        }
+
+        let sealed = self.encryptor.finish()?;
+        self.file.write_all(&sealed).await?;
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        tokio::fs::rename(temp_path(&self.path), &self.path).await?;
+        sync_dir(&self.path)
+    }
+}
+
+/// Replaces the file at `path` with `bytes` the same way `BlobWriter` does,
+/// for callers outside the async runtime.
+pub fn replace_file(path: &str, bytes: &[u8]) -> Result<(), VaultError> {
+    let temp = temp_path(path);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    sync_dir(path)
+}
+
+/// Where a blob is written before it is renamed into place. Left behind by a
+/// crash, it is an orphan like any other file no entry refers to.
+pub(super) fn temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+/// Syncs the directory holding `path`, so a rename into it survives a crash.
+/// Only possible on Unix; elsewhere directories cannot be opened as files.
+fn sync_dir(path: &str) -> Result<(), VaultError> {
+    #[cfg(unix)]
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes a whole in-memory variant as a chunked blob.
+pub async fn write_blob(
+    path: &str,
+    key: &[u8],
+    aad: &[u8],
+    data: &[u8],
+    padding: BlobPadding,
+) -> Result<(), VaultError> {
+    let mut writer = BlobWriter::create(path, key, aad, padding).await?;
+    writer.write(data).await?;
+    writer.finish().await
+}
+
+enum BlobFormat {
+    Chunked {
+        decryptor: StreamDecryptor,
+        chunks: u64,
+        next: u64,
+        // Bytes to drop from the front of the next chunk
+        skip: usize,
+    },
+    // Blobs written before the chunked format are decrypted whole on open
+    SingleShot,
+}
+
+/// Decrypts a blob, or a byte range of it, one chunk at a time as it is read.
+/// Only the chunks covering the selected range are ever decrypted, and each is
+/// wiped once its last holder drops it.
+pub struct BlobReader {
+    file: File,
+    format: BlobFormat,
+    size: u64,
+    // Tells this write of the blob apart from any earlier or later one
+    revision: String,
+    // Plaintext bytes left to return
+    remaining: u64,
+    pending: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl BlobReader {
+    pub async fn open(path: &str, key: &[u8], aad: &[u8]) -> Result<Self, VaultError> {
+        let mut file = File::open(path).await?;
+        let blob_len = file.metadata().await?.len();
+
+        let mut header = vec![0u8; STREAM_HEADER_LEN.min(blob_len as usize)];
+        file.read_exact(&mut header).await?;
+        let revision = crypto::blob_nonce(&header)
+            .iter()
+            .take(8)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        if !crypto::is_stream(&header) {
+            let mut encrypted = header;
+            file.read_to_end(&mut encrypted).await?;
+            let decrypted = Zeroizing::new(crypto::decrypt(key, &encrypted, aad)?);
+            let size = decrypted.len() as u64;
+            return Ok(Self {
+                file,
+                format: BlobFormat::SingleShot,
+                size,
+                revision,
+                remaining: size,
+                pending: Some(decrypted),
+            });
+        }
+
+        let decryptor = StreamDecryptor::new(key, aad, &header)?;
+        let (chunks, stream_len) = decryptor.layout(blob_len)?;
+        let size = if decryptor.has_length_trailer() {
+            // The true length is in the last 8 bytes, which may span two chunks
+            let mut tail = Zeroizing::new(Vec::new());
+            let mut index = chunks;
+            while tail.len() < 8 && index > 0 {
+                index -= 1;
+                let mut plain = read_sealed_chunk(&mut file, &decryptor, index, chunks).await?;
+                plain.extend_from_slice(&tail);
+                tail = plain;
+            }
+            decryptor.trailer_len(&tail, stream_len)?
+        } else {
+            stream_len
+        };
+
+        Ok(Self {
+            file,
+            format: BlobFormat::Chunked {
+                decryptor,
+                chunks,
+                next: 0,
+                skip: 0,
+            },
+            size,
+            revision,
+            remaining: size,
+            pending: None,
+        })
+    }
+
+    /// Plaintext size in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Changes whenever the blob is written again, as a repair does, even
+    /// with the same content.
+    pub fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    /// Limits reading to `range` of the plaintext and decrypts its first chunk
+    /// up front, so a wrong key or corrupt blob fails before anything is sent.
+    pub async fn select(&mut self, range: Range<u64>) -> Result<(), VaultError> {
+        if range.start > range.end || range.end > self.size {
+            return Err(VaultError::Corruption("Range out of bounds".into()));
+        }
+        self.remaining = range.end - range.start;
+
+        match &mut self.format {
+            BlobFormat::Chunked {
+                decryptor,
+                next,
+                skip,
+                ..
+            } => {
+                let chunk_size = decryptor.chunk_size() as u64;
+                *next = range.start / chunk_size;
+                *skip = (range.start % chunk_size) as usize;
+                self.pending = self.read_chunk().await?;
+            }
+            BlobFormat::SingleShot => {
+                if let Some(data) = self.pending.as_mut() {
+                    data.truncate(range.end as usize);
+                    data.drain(..range.start as usize);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next decrypted chunk, or `None` once the selection is exhausted.
+    pub async fn next_chunk(&mut self) -> Result<Option<Zeroizing<Vec<u8>>>, VaultError> {
+        if let Some(chunk) = self.pending.take() {
+            return Ok(Some(chunk));
+        }
+        self.read_chunk().await
+    }
+
+    async fn read_chunk(&mut self) -> Result<Option<Zeroizing<Vec<u8>>>, VaultError> {
+        let BlobFormat::Chunked {
+            decryptor,
+            chunks,
+            next,
+            skip,
+        } = &mut self.format
+        else {
+            return Ok(None);
+        };
+        if self.remaining == 0 || *next >= *chunks {
+            return Ok(None);
+        }
+
+        let mut decrypted = read_sealed_chunk(&mut self.file, decryptor, *next, *chunks).await?;
+        let start = (*skip).min(decrypted.len());
+        decrypted.drain(..start);
+        let len = self.remaining.min(decrypted.len() as u64) as usize;
+        decrypted.truncate(len);
+        *next += 1;
+        *skip = 0;
+        self.remaining -= decrypted.len() as u64;
+        Ok(Some(decrypted))
+    }
+
+    pub async fn read_to_end(mut self) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+        let mut out = Zeroizing::new(Vec::with_capacity(self.size as usize));
+        while let Some(chunk) = self.next_chunk().await? {
+            out.extend_from_slice(&chunk);
+        }
+        Ok(out)
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Zeroizing<Vec<u8>>, VaultError>> + Send {
+        futures_util::stream::try_unfold(self, |mut reader| async move {
+            Ok(reader.next_chunk().await?.map(|chunk| (chunk, reader)))
+        })
+    }
+}
+
+/// Reads and decrypts chunk `index` of `chunks`.
+async fn read_sealed_chunk(
+    file: &mut File,
+    decryptor: &StreamDecryptor,
+    index: u64,
+    chunks: u64,
+) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+    let mut chunk = Vec::with_capacity(decryptor.encrypted_chunk_len());
+    file.seek(SeekFrom::Start(decryptor.chunk_offset(index)))
+        .await?;
+    file.take(decryptor.encrypted_chunk_len() as u64)
+        .read_to_end(&mut chunk)
+        .await?;
+
+    decryptor.decrypt_chunk(index, &chunk, index + 1 == chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const AAD: &[u8] = b"0123456789abcdeforiginal";
+
+    fn temp_blob() -> String {
+        let name = format!("vanta-blob-{}.enc", uuid::Uuid::new_v4());
+        std::env::temp_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    async fn read_range(path: &str, range: Range<u64>) -> Vec<u8> {
+        let mut reader = BlobReader::open(path, &KEY, AAD).await.unwrap();
+        reader.select(range).await.unwrap();
+        reader.read_to_end().await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_across_chunks() {
+        let path = temp_blob();
+        let data: Vec<u8> = (0..2 * STREAM_CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        write_blob(&path, &KEY, AAD, &data, BlobPadding::Padme)
+            .await
+            .unwrap();
+
+        let reader = BlobReader::open(&path, &KEY, AAD).await.unwrap();
+        assert_eq!(reader.size(), data.len() as u64);
+        assert_eq!(*reader.read_to_end().await.unwrap(), data);
+        let across = STREAM_CHUNK_SIZE as u64 - 3..2 * STREAM_CHUNK_SIZE as u64 + 2;
+        assert_eq!(
+            read_range(&path, across.clone()).await,
+            data[across.start as usize..across.end as usize]
+        );
+        let end = data.len() as u64;
+        assert!(read_range(&path, end..end).await.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_single_shot_blobs() {
+        let path = temp_blob();
+        let data = vec![5u8; 1000];
+        std::fs::write(&path, crypto::encrypt(&KEY, &data, AAD).unwrap()).unwrap();
+
+        let reader = BlobReader::open(&path, &KEY, AAD).await.unwrap();
+        assert_eq!(reader.size(), 1000);
+        assert_eq!(*reader.read_to_end().await.unwrap(), data);
+        assert_eq!(read_range(&path, 10..20).await, data[10..20]);
+        assert!(BlobReader::open(&path, &KEY, b"other").await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
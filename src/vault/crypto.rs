@@ -1,38 +1,184 @@
-use super::error::VaultError;
-use argon2::{
-    Algorithm, Argon2, Params, Version,
-    password_hash::{PasswordHasher, SaltString},
+use super::{
+    error::VaultError,
+    secret::SecretKey,
+    types::{KdfAlgorithm, KdfParams},
 };
+use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{
     XChaCha20Poly1305, XNonce,
     aead::{Aead, KeyInit, Payload},
 };
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::{RngExt, rngs::StdRng};
-use secrecy::SecretBox;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
 
-/// Derives a 32-byte key from a password and salt.
-pub fn derive_key(password: &str, salt: &[u8; 16]) -> Result<SecretBox<[u8]>, VaultError> {
-    let params =
-        Params::new(65536, 3, 4, Some(32)).map_err(|e| VaultError::Argon2(e.to_string()))?;
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+/// Derives a 32-byte key from a password and salt. A keyfile secret is fed to
+/// Argon2 as its secret input, so the key depends on both.
+pub fn derive_key(
+    password: &str,
+    keyfile: Option<&SecretKey>,
+    salt: &[u8; 16],
+    kdf: &KdfParams,
+) -> Result<SecretKey, VaultError> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| VaultError::Argon2(e.to_string()))?;
+    let argon2 = match (kdf.algorithm, keyfile) {
+        (KdfAlgorithm::Argon2id, None) => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        (KdfAlgorithm::Argon2id, Some(keyfile)) => Argon2::new_with_secret(
+            keyfile.expose_secret(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )
+        .map_err(|e| VaultError::Argon2(e.to_string()))?,
+    };
 
-    let salt_str = SaltString::encode_b64(salt).map_err(|e| VaultError::Argon2(e.to_string()))?;
+    // Hashed straight into the key, so no copy of it is left behind
+    SecretKey::new_with(|key| {
+        argon2
+            .hash_password_into(password.as_bytes(), salt, key)
+            .map_err(|e| VaultError::Argon2(e.to_string()))
+    })
+}
 
-    let password_hash = argon2
-        .hash_password(password.as_bytes(), &salt_str)
-        .map_err(|e| VaultError::Argon2(e.to_string()))?;
+/// Picks KDF parameters that take roughly `target` to derive a key on this host.
+/// Memory is doubled first (up to `max_m_cost` KiB) since it is what makes
+/// Argon2 expensive on GPUs, then iterations are scaled to close the gap (up
+/// to `max_t_cost`). Never returns anything weaker than `min`.
+pub fn calibrate_kdf(
+    target: Duration,
+    min: &KdfParams,
+    max_m_cost: u32,
+    max_t_cost: u32,
+) -> Result<KdfParams, VaultError> {
+    let time = |params: &KdfParams| -> Result<Duration, VaultError> {
+        let start = Instant::now();
+        derive_key("calibration", None, &[0u8; 16], params)?;
+        Ok(start.elapsed())
+    };
 
-    let hash_output = password_hash
-        .hash
-        .ok_or_else(|| VaultError::Argon2("No hash output".into()))?;
+    let mut params = *min;
+    let mut elapsed = time(&params)?;
 
-    let mut key = [0u8; 32];
-    if hash_output.as_bytes().len() < 32 {
-        return Err(VaultError::Argon2("Hash output too short".into()));
+    while elapsed < target && params.m_cost.saturating_mul(2) <= max_m_cost {
+        params.m_cost *= 2;
+        elapsed = time(&params)?;
     }
-    key.copy_from_slice(&hash_output.as_bytes()[..32]);
 
-    Ok(SecretBox::from(key.to_vec()))
+    if elapsed < target && !elapsed.is_zero() {
+        let scale = target.as_secs_f64() / elapsed.as_secs_f64();
+        let t_cost = (params.t_cost as f64 * scale).ceil() as u32;
+        // A host timing far below the target, e.g. on a coarse clock, must
+        // not leave unlocking to take minutes
+        params.t_cost = t_cost.min(max_t_cost).max(min.t_cost);
+    }
+
+    Ok(params)
+}
+
+/// Hashes a keyfile of any size down to the secret mixed into key derivation.
+pub fn keyfile_secret(contents: &[u8]) -> SecretKey {
+    SecretKey::new_with(|key| {
+        // Straight into the key, like `derive_key`
+        Sha256::new_with_prefix(b"vanta keyfile")
+            .chain_update(contents)
+            .finalize_into(key.into());
+        Ok::<_, VaultError>(())
+    })
+    .expect("hashing a keyfile cannot fail")
+}
+
+/// Version of the scheme deriving subkeys from the master key. Vaults at
+/// version 0 used the master key directly for everything.
+pub const CURRENT_KEY_DERIVATION: u32 = 1;
+
+/// The master key and the per-purpose subkeys derived from it, so no key is
+/// ever used for more than one kind of data.
+#[derive(Clone)]
+pub struct VaultKeys {
+    /// Only wraps itself into key slots and seals slot info
+    pub master: SecretKey,
+    /// Entry metadata in the database
+    pub entries: SecretKey,
+    /// Image blobs on disk
+    pub blobs: SecretKey,
+    /// Keyed hashes naming entries and blobs
+    pub index: SecretKey,
+    /// Root for later features, which derive their own keys from it
+    pub extensions: SecretKey,
+}
+
+impl VaultKeys {
+    pub fn derive(master: &[u8], derivation: u32) -> Result<Self, VaultError> {
+        let subkey = |label: &[u8]| match derivation {
+            0 => SecretKey::from_slice(master),
+            CURRENT_KEY_DERIVATION => derive_subkey(master, label),
+            found => Err(VaultError::InvalidVersion {
+                expected: CURRENT_KEY_DERIVATION,
+                found,
+            }),
+        };
+
+        Ok(Self {
+            master: SecretKey::from_slice(master)?,
+            entries: subkey(b"vanta/entries")?,
+            blobs: subkey(b"vanta/blobs")?,
+            index: subkey(b"vanta/index")?,
+            extensions: subkey(b"vanta/extensions")?,
+        })
+    }
+}
+
+/// Derives a 32-byte subkey from `key` with HKDF-SHA256, separated by `label`.
+pub fn derive_subkey(key: &[u8], label: &[u8]) -> Result<SecretKey, VaultError> {
+    SecretKey::new_with(|subkey| {
+        Hkdf::<Sha256>::new(None, key)
+            .expand(label, subkey)
+            .map_err(|_| VaultError::EncryptionError)
+    })
+}
+
+/// Opaque on-disk name for one variant of an image: a keyed hash of its id and
+/// variant, so file names reveal neither which image they belong to nor what
+/// variant they hold.
+pub fn blob_name(key: &[u8], id: &[u8], variant: &str) -> Result<String, VaultError> {
+    Ok(keyed_hash(key, &[b"blob", id, variant.as_bytes()])?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Database key of an entry: a keyed hash of its id, so the entries tree does
+/// not list the ids of every image.
+pub fn entry_key(key: &[u8], id: &[u8]) -> Result<Vec<u8>, VaultError> {
+    keyed_hash(key, &[b"entry", id])
+}
+
+/// Database key of a per-library setting, so each library finds only its own.
+pub fn setting_key(key: &[u8], name: &str) -> Result<Vec<u8>, VaultError> {
+    keyed_hash(key, &[b"setting", name.as_bytes()])
+}
+
+/// Row name of audit record `seq`, under the log's own key.
+pub fn audit_row(key: &[u8], seq: u64) -> Result<Vec<u8>, VaultError> {
+    keyed_hash(key, &[b"audit", &seq.to_be_bytes()])
+}
+
+/// HMAC-SHA256 over `parts` in order.
+fn keyed_hash(key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, VaultError> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(|_| VaultError::EncryptionError)?;
+    for part in parts {
+        mac.update(part);
+    }
+    Ok(mac.finalize().into_bytes().to_vec())
 }
 
 /// Encrypts data, prepending the 24-byte nonce to the output.
@@ -75,3 +221,563 @@ pub fn decrypt(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, VaultErro
         )
         .map_err(|_| VaultError::EncryptionError)
 }
+
+/// Marks a chunked blob. Single-shot blobs start with a random nonce instead.
+const STREAM_MAGIC: &[u8; 4] = b"VNTS";
+/// Version 2 ends the plaintext with padding and the true length
+const STREAM_VERSION: u8 = 2;
+const STREAM_NONCE_PREFIX_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+const LENGTH_TRAILER_LEN: usize = 8;
+/// Magic, version, chunk size and nonce prefix.
+pub const STREAM_HEADER_LEN: usize = 4 + 1 + 4 + STREAM_NONCE_PREFIX_LEN;
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Upper bound on the chunk size a header may claim, so a corrupt header
+/// cannot make a reader allocate without limit.
+const STREAM_MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// True if `header` starts a chunked blob rather than a single-shot one.
+pub fn is_stream(header: &[u8]) -> bool {
+    header.len() >= STREAM_HEADER_LEN
+        && header.starts_with(STREAM_MAGIC)
+        && matches!(header[STREAM_MAGIC.len()], 1 | STREAM_VERSION)
+}
+
+/// The random nonce, or nonce prefix, a blob starting with `header` was
+/// encrypted under. Every write of a blob draws a new one.
+pub fn blob_nonce(header: &[u8]) -> &[u8] {
+    if is_stream(header) {
+        &header[STREAM_HEADER_LEN - STREAM_NONCE_PREFIX_LEN..STREAM_HEADER_LEN]
+    } else {
+        &header[..header.len().min(24)]
+    }
+}
+
+/// How blob plaintext is padded before encryption, so file sizes do not give
+/// away exact image sizes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobPadding {
+    None,
+    /// Padmé: at most 12% overhead, and a padded length reveals only
+    /// O(log log n) bits of the true one
+    Padme,
+}
+
+impl BlobPadding {
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            Self::None => len,
+            Self::Padme if len < 2 => len,
+            Self::Padme => {
+                // Keep the top floor(log2(e)) + 1 bits of the length, round up the rest
+                let e = 63 - len.leading_zeros() as u64;
+                let s = 64 - e.leading_zeros() as u64;
+                let mask = (1u64 << (e - s)) - 1;
+                (len + mask) & !mask
+            }
+        }
+    }
+}
+
+impl FromStr for BlobPadding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "padme" => Ok(Self::Padme),
+            _ => Err(format!("Unknown padding scheme: {s}")),
+        }
+    }
+}
+
+/// Nonce for one chunk: the blob's random prefix, the chunk counter and a flag
+/// set only on the final chunk, so chunks cannot be reordered, dropped or
+/// truncated away without failing authentication (the STREAM construction).
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..STREAM_NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[STREAM_NONCE_PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+/// Every chunk authenticates the header as well as the caller's AAD.
+fn chunk_aad(header: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut chunk_aad = Vec::with_capacity(header.len() + aad.len());
+    chunk_aad.extend_from_slice(header);
+    chunk_aad.extend_from_slice(aad);
+    chunk_aad
+}
+
+/// Encrypts a blob incrementally as fixed-size chunks. Feed plaintext to
+/// `update` as it arrives and write out the header followed by whatever each
+/// call returns; memory use is bounded by the chunk size.
+///
+/// The plaintext is followed by `padding_len` zero bytes, fed through `pad`,
+/// and then its true length, so only the padded size is visible on disk.
+pub struct StreamEncryptor {
+    cipher: XChaCha20Poly1305,
+    header: [u8; STREAM_HEADER_LEN],
+    aad: Vec<u8>,
+    counter: u32,
+    // Plaintext of the chunk being filled, wiped when the encryptor is dropped
+    buffer: Zeroizing<Vec<u8>>,
+    len: u64,
+}
+
+impl StreamEncryptor {
+    pub fn new(key: &[u8], aad: &[u8]) -> Result<Self, VaultError> {
+        let cipher =
+            XChaCha20Poly1305::new_from_slice(key).map_err(|_| VaultError::EncryptionError)?;
+
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        header[..4].copy_from_slice(STREAM_MAGIC);
+        header[4] = STREAM_VERSION;
+        header[5..9].copy_from_slice(&(STREAM_CHUNK_SIZE as u32).to_be_bytes());
+        rand::make_rng::<StdRng>().fill(&mut header[9..]);
+
+        Ok(Self {
+            aad: chunk_aad(&header, aad),
+            cipher,
+            header,
+            counter: 0,
+            buffer: Zeroizing::new(Vec::with_capacity(STREAM_CHUNK_SIZE)),
+            len: 0,
+        })
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Buffers `data` and returns the chunks it completed, sealed.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, VaultError> {
+        self.len += data.len() as u64;
+        self.push(data)
+    }
+
+    /// Zero bytes to `pad` with before finishing.
+    pub fn padding_len(&self, padding: BlobPadding) -> u64 {
+        let len = self.len + LENGTH_TRAILER_LEN as u64;
+        padding.padded_len(len) - len
+    }
+
+    /// Appends `zeros` bytes of padding. Like `update`, returns completed chunks.
+    /// The zeros are fed a chunk at a time rather than allocated all at once.
+    pub fn pad(&mut self, mut zeros: usize) -> Result<Vec<u8>, VaultError> {
+        static ZEROS: [u8; STREAM_CHUNK_SIZE] = [0; STREAM_CHUNK_SIZE];
+
+        let mut out = Vec::new();
+        while zeros > 0 {
+            let part = zeros.min(STREAM_CHUNK_SIZE);
+            out.extend_from_slice(&self.push(&ZEROS[..part])?);
+            zeros -= part;
+        }
+        Ok(out)
+    }
+
+    /// Appends the true length and seals what is left as the final chunk.
+    pub fn finish(mut self) -> Result<Vec<u8>, VaultError> {
+        let mut out = self.push(&self.len.to_be_bytes())?;
+        out.extend_from_slice(&self.seal(true)?);
+        Ok(out)
+    }
+
+    fn push(&mut self, mut data: &[u8]) -> Result<Vec<u8>, VaultError> {
+        let mut out = Vec::new();
+
+        // A full chunk is only sealed once more data follows, so the final
+        // chunk is never empty unless the whole blob is
+        while self.buffer.len() + data.len() > STREAM_CHUNK_SIZE {
+            let take = STREAM_CHUNK_SIZE - self.buffer.len();
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            out.extend_from_slice(&self.seal(false)?);
+        }
+        self.buffer.extend_from_slice(data);
+
+        Ok(out)
+    }
+
+    fn seal(&mut self, last: bool) -> Result<Vec<u8>, VaultError> {
+        let nonce = chunk_nonce(&self.header[9..], self.counter, last);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &self.buffer,
+                    aad: &self.aad,
+                },
+            )
+            .map_err(|_| VaultError::EncryptionError)?;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| VaultError::Corruption("Blob too large".into()))?;
+        self.buffer.clear();
+        Ok(sealed)
+    }
+}
+
+/// Decrypts individual chunks of a chunked blob, in any order. The caller
+/// does the IO: chunk `i` starts at `chunk_offset(i)` and is
+/// `encrypted_chunk_len()` bytes long, except the last which may be shorter.
+pub struct StreamDecryptor {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; STREAM_NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    chunk_size: usize,
+    version: u8,
+}
+
+impl StreamDecryptor {
+    pub fn new(key: &[u8], aad: &[u8], header: &[u8]) -> Result<Self, VaultError> {
+        if !is_stream(header) {
+            return Err(VaultError::Corruption("Not a chunked blob".into()));
+        }
+        let header = &header[..STREAM_HEADER_LEN];
+
+        let chunk_size = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        if chunk_size == 0 || chunk_size > STREAM_MAX_CHUNK_SIZE {
+            return Err(VaultError::Corruption("Invalid chunk size".into()));
+        }
+
+        let cipher =
+            XChaCha20Poly1305::new_from_slice(key).map_err(|_| VaultError::EncryptionError)?;
+        let mut nonce_prefix = [0u8; STREAM_NONCE_PREFIX_LEN];
+        nonce_prefix.copy_from_slice(&header[9..]);
+
+        Ok(Self {
+            cipher,
+            nonce_prefix,
+            aad: chunk_aad(header, aad),
+            chunk_size,
+            version: header[4],
+        })
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn encrypted_chunk_len(&self) -> usize {
+        self.chunk_size + TAG_LEN
+    }
+
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        STREAM_HEADER_LEN as u64 + index * self.encrypted_chunk_len() as u64
+    }
+
+    /// Whether the plaintext ends with padding and the true length. Only
+    /// blobs written before padding existed lack it.
+    pub fn has_length_trailer(&self) -> bool {
+        self.version >= 2
+    }
+
+    /// Reads the true length from the last `tail` bytes of a blob's
+    /// plaintext, `stream_len` bytes in total.
+    pub fn trailer_len(&self, tail: &[u8], stream_len: u64) -> Result<u64, VaultError> {
+        let trailer = tail
+            .len()
+            .checked_sub(LENGTH_TRAILER_LEN)
+            .map(|start| &tail[start..])
+            .ok_or_else(|| VaultError::Corruption("Blob truncated".into()))?;
+        let mut bytes = [0u8; LENGTH_TRAILER_LEN];
+        bytes.copy_from_slice(trailer);
+        let len = u64::from_be_bytes(bytes);
+
+        if len > stream_len.saturating_sub(LENGTH_TRAILER_LEN as u64) {
+            return Err(VaultError::Corruption("Invalid blob length".into()));
+        }
+        Ok(len)
+    }
+
+    /// Number of chunks and plaintext length of a blob `blob_len` bytes long,
+    /// including any padding and length trailer.
+    pub fn layout(&self, blob_len: u64) -> Result<(u64, u64), VaultError> {
+        let body = blob_len
+            .checked_sub(STREAM_HEADER_LEN as u64)
+            .filter(|body| *body >= TAG_LEN as u64)
+            .ok_or_else(|| VaultError::Corruption("Blob truncated".into()))?;
+
+        let chunk_len = self.encrypted_chunk_len() as u64;
+        let chunks = body.div_ceil(chunk_len);
+        let last_len = body - (chunks - 1) * chunk_len;
+        if last_len < TAG_LEN as u64 {
+            return Err(VaultError::Corruption("Blob truncated".into()));
+        }
+
+        Ok((chunks, body - chunks * TAG_LEN as u64))
+    }
+
+    pub fn decrypt_chunk(
+        &self,
+        index: u64,
+        chunk: &[u8],
+        last: bool,
+    ) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+        let counter =
+            u32::try_from(index).map_err(|_| VaultError::Corruption("Blob too large".into()))?;
+        let nonce = chunk_nonce(&self.nonce_prefix, counter, last);
+
+        self.cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: chunk,
+                    aad: &self.aad,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| VaultError::EncryptionError)
+    }
+}
+
+/// Encrypts a whole in-memory blob in the chunked format.
+pub fn encrypt_blob(
+    key: &[u8],
+    data: &[u8],
+    aad: &[u8],
+    padding: BlobPadding,
+) -> Result<Vec<u8>, VaultError> {
+    let mut encryptor = StreamEncryptor::new(key, aad)?;
+    let mut out = Vec::with_capacity(
+        STREAM_HEADER_LEN + data.len() + (data.len() / STREAM_CHUNK_SIZE + 1) * TAG_LEN,
+    );
+    out.extend_from_slice(encryptor.header());
+    out.extend_from_slice(&encryptor.update(data)?);
+    let zeros = encryptor.padding_len(padding) as usize;
+    out.extend_from_slice(&encryptor.pad(zeros)?);
+    out.extend_from_slice(&encryptor.finish()?);
+    Ok(out)
+}
+
+/// Decrypts a whole in-memory blob in either the chunked or single-shot format.
+/// The plaintext is wiped when dropped.
+pub fn decrypt_blob(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+    if !is_stream(data) {
+        return decrypt(key, data, aad).map(Zeroizing::new);
+    }
+
+    let decryptor = StreamDecryptor::new(key, aad, data)?;
+    let (chunks, plaintext_len) = decryptor.layout(data.len() as u64)?;
+    let mut out = Zeroizing::new(Vec::with_capacity(plaintext_len as usize));
+    for (i, chunk) in data[STREAM_HEADER_LEN..]
+        .chunks(decryptor.encrypted_chunk_len())
+        .enumerate()
+    {
+        let i = i as u64;
+        out.extend_from_slice(&decryptor.decrypt_chunk(i, chunk, i + 1 == chunks)?);
+    }
+
+    if decryptor.has_length_trailer() {
+        let len = decryptor.trailer_len(&out, plaintext_len)?;
+        out.truncate(len as usize);
+    }
+    Ok(out)
+}
+
+const RECOVERY_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const RECOVERY_KEY_BYTES: usize = 20;
+const RECOVERY_GROUP_LEN: usize = 4;
+
+/// Generates a 160-bit recovery key as base32 in dash-separated groups of four,
+/// e.g. `K7QX-M2PA-...`, so it can be printed and typed back by hand.
+pub fn generate_recovery_key() -> String {
+    let bytes: [u8; RECOVERY_KEY_BYTES] = rand::random();
+    let encoded = base32_encode(&bytes);
+
+    encoded
+        .as_bytes()
+        .chunks(RECOVERY_GROUP_LEN)
+        .map(|g| std::str::from_utf8(g).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Canonicalizes a typed recovery key: case, dashes and whitespace are ignored.
+/// Returns `None` if what remains cannot be a recovery key.
+pub fn normalize_recovery_key(input: &str) -> Option<String> {
+    let key: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    let expected_len = RECOVERY_KEY_BYTES * 8 / 5;
+    if key.len() == expected_len && key.bytes().all(|b| RECOVERY_ALPHABET.contains(&b)) {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// RFC 4648 base32 without padding.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(RECOVERY_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(RECOVERY_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    // `make_aad` is the entry id followed by the variant name
+    const AAD: &[u8] = b"0123456789abcdeforiginal";
+
+    fn blob_of(len: usize) -> (Vec<u8>, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let blob = encrypt_blob(&KEY, &data, AAD, BlobPadding::None).unwrap();
+        (data, blob)
+    }
+
+    /// Where each sealed chunk of `blob` starts.
+    fn chunk_offsets(blob: &[u8]) -> Vec<usize> {
+        let decryptor = StreamDecryptor::new(&KEY, AAD, blob).unwrap();
+        let (chunks, _) = decryptor.layout(blob.len() as u64).unwrap();
+        (0..chunks)
+            .map(|i| decryptor.chunk_offset(i) as usize)
+            .collect()
+    }
+
+    #[test]
+    fn stream_round_trips_around_chunk_boundaries() {
+        for len in [0, 1, STREAM_CHUNK_SIZE, STREAM_CHUNK_SIZE + 1] {
+            let (data, blob) = blob_of(len);
+            assert!(is_stream(&blob));
+            assert_eq!(
+                *decrypt_blob(&KEY, &blob, AAD).unwrap(),
+                data,
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn stream_rejects_truncated_or_reordered_chunks() {
+        let (_, blob) = blob_of(3 * STREAM_CHUNK_SIZE);
+        let offsets = chunk_offsets(&blob);
+        assert_eq!(offsets.len(), 4);
+
+        // Without its final chunk, the one before is not marked last
+        let last = *offsets.last().unwrap();
+        assert!(decrypt_blob(&KEY, &blob[..last], AAD).is_err());
+        assert!(decrypt_blob(&KEY, &blob[..blob.len() - 1], AAD).is_err());
+
+        let mut reordered = blob.clone();
+        let (first, second) = (offsets[0], offsets[1]);
+        reordered[first..offsets[2]].rotate_left(second - first);
+        assert!(decrypt_blob(&KEY, &reordered, AAD).is_err());
+    }
+
+    #[test]
+    fn stream_rejects_a_final_chunk_not_marked_last() {
+        let (_, mut blob) = blob_of(STREAM_CHUNK_SIZE + 1);
+        let offsets = chunk_offsets(&blob);
+        let (index, last) = (offsets.len() as u64 - 1, *offsets.last().unwrap());
+        let decryptor = StreamDecryptor::new(&KEY, AAD, &blob).unwrap();
+        let plaintext = decryptor.decrypt_chunk(index, &blob[last..], true).unwrap();
+
+        // Sealed again as if more chunks followed
+        let nonce = chunk_nonce(blob_nonce(&blob), index as u32, false);
+        let aad = chunk_aad(&blob[..STREAM_HEADER_LEN], AAD);
+        let resealed = XChaCha20Poly1305::new_from_slice(&KEY)
+            .unwrap()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        blob.truncate(last);
+        blob.extend_from_slice(&resealed);
+        assert!(decrypt_blob(&KEY, &blob, AAD).is_err());
+    }
+
+    #[test]
+    fn stream_is_bound_to_its_entry_and_variant() {
+        let (_, blob) = blob_of(100);
+        assert!(decrypt_blob(&KEY, &blob, b"0123456789abcdefthumbnail").is_err());
+        assert!(decrypt_blob(&KEY, &blob, b"fedcba9876543210original").is_err());
+        assert!(decrypt_blob(&[8; 32], &blob, AAD).is_err());
+    }
+
+    #[test]
+    fn padme_rounds_up_by_at_most_12_percent() {
+        for (len, padded) in [
+            (0, 0),
+            (1, 1),
+            (8, 8),
+            (9, 10),
+            (100, 104),
+            (1000, 1024),
+            (1025, 1088),
+            (1_000_000, 1_015_808),
+        ] {
+            assert_eq!(BlobPadding::Padme.padded_len(len), padded, "{} bytes", len);
+        }
+        for len in (2..200_000).step_by(7) {
+            let padded = BlobPadding::Padme.padded_len(len);
+            assert!(padded >= len && (padded - len) * 100 <= len * 12);
+            assert_eq!(BlobPadding::Padme.padded_len(padded), padded);
+            assert_eq!(BlobPadding::None.padded_len(len), len);
+        }
+    }
+
+    #[test]
+    fn padded_blobs_hide_the_exact_size() {
+        let (short, long) = (vec![1u8; 1000], vec![2u8; 1010]);
+        let short_blob = encrypt_blob(&KEY, &short, AAD, BlobPadding::Padme).unwrap();
+        let long_blob = encrypt_blob(&KEY, &long, AAD, BlobPadding::Padme).unwrap();
+        assert_eq!(short_blob.len(), long_blob.len());
+        assert_eq!(*decrypt_blob(&KEY, &short_blob, AAD).unwrap(), short);
+        assert_eq!(*decrypt_blob(&KEY, &long_blob, AAD).unwrap(), long);
+    }
+
+    #[test]
+    fn calibration_stays_within_the_limits() {
+        let min = KdfParams {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+            ..KdfParams::default()
+        };
+        let kdf = calibrate_kdf(Duration::from_secs(3600), &min, 16, 4).unwrap();
+        assert_eq!((kdf.m_cost, kdf.t_cost), (16, 4));
+
+        // The minimum wins over a lower maximum
+        let kdf = calibrate_kdf(Duration::from_secs(3600), &min, 8, 0).unwrap();
+        assert_eq!((kdf.m_cost, kdf.t_cost), (8, 1));
+    }
+
+    #[test]
+    fn single_shot_blobs_stay_readable() {
+        let data = vec![5u8; 1000];
+        let blob = encrypt(&KEY, &data, AAD).unwrap();
+        assert!(!is_stream(&blob));
+        assert_eq!(*decrypt_blob(&KEY, &blob, AAD).unwrap(), data);
+        assert!(decrypt_blob(&KEY, &blob, b"other").is_err());
+    }
+}
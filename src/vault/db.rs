@@ -1,16 +1,29 @@
 use crate::vault::{
-    crypto,
+    crypto::{self, CURRENT_KEY_DERIVATION, VaultKeys},
     error::VaultError,
-    types::{ImageEntry, ImageVariant, VaultMetadata},
+    secret::SecretKey,
+    types::{
+        AuditState, DbTree, FailedAttempts, ImageEntry, ImageVariant, JournalRecord, KdfParams,
+        KeySlot, LinkedImage, RotationState, RotationStateV1, StoredBlob, VaultMetadata,
+    },
 };
-use sled::{Config, Db, Tree};
+use sled::{
+    Batch, Config, Db, IVec, Transactional, Tree, transaction::ConflictableTransactionError,
+};
+use std::ops::Bound;
 use std::str::from_utf8;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use rayon::prelude::*;
-use serde::Deserialize;
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
-const CURRENT_VAULT_VERSION: u32 = 2;
+const CURRENT_VAULT_VERSION: u32 = 7;
+// Opening a mirror or restored database still locked is retried this often,
+// this far apart
+const OPEN_ATTEMPTS: u32 = 50;
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(20);
 
 /// V1 format used only for migration deserialization.
 #[derive(Deserialize)]
@@ -24,20 +37,96 @@ struct ImageEntryV1 {
     tags: Vec<String>,
 }
 
+/// Entry format from before blobs had opaque names, used only as a
+/// deserialization fallback.
+#[derive(Deserialize)]
+struct ImageEntryV2 {
+    id: Uuid,
+    original_mime: String,
+    original_size: u64,
+    created_at: u64,
+    variants: Vec<ImageVariant>,
+    tags: Vec<String>,
+    linked_images: Vec<LinkedImageV1>,
+}
+
+/// Entry format from before the trash, used only as a deserialization fallback.
+#[derive(Deserialize)]
+struct ImageEntryV3 {
+    id: Uuid,
+    original_mime: String,
+    original_size: u64,
+    created_at: u64,
+    variants: Vec<ImageVariant>,
+    tags: Vec<String>,
+    linked_images: Vec<LinkedImage>,
+    blobs: Vec<StoredBlob>,
+}
+
+#[derive(Deserialize)]
+struct LinkedImageV1 {
+    id: Uuid,
+    original_mime: String,
+    original_size: u64,
+    variants: Vec<ImageVariant>,
+}
+
+/// Key slot format from before keyfiles, used only as a deserialization fallback.
+#[derive(Deserialize)]
+struct KeySlotV2 {
+    id: u32,
+    salt: [u8; 16],
+    kdf: KdfParams,
+    wrapped_key: Vec<u8>,
+    info: Vec<u8>,
+}
+
+/// Key slot format from before the KDF algorithm was stored, used only as a
+/// deserialization fallback.
+#[derive(Deserialize)]
+struct KeySlotV1 {
+    id: u32,
+    salt: [u8; 16],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    wrapped_key: Vec<u8>,
+    info: Vec<u8>,
+}
+
 #[derive(Clone)]
 pub struct Database {
     db: Db,
     // The tree specifically for image entries
     entries_tree: Tree,
+    // Wrapped copies of the master key, keyed by big-endian slot id
+    key_slots_tree: Tree,
+    // Encrypted per-library settings, keyed by a keyed hash of their name
+    settings_tree: Tree,
+    // Encrypted audit records of every library, keyed by a keyed hash of
+    // their sequence number
+    audit_tree: Tree,
+    // Encrypted records of operations in progress, keyed by random ids
+    journal_tree: Tree,
 }
 
 impl Database {
     pub fn open(path: &str) -> Result<Self, VaultError> {
-        let config = Config::new().path(path);
-        let db = config.open()?;
+        let db = Config::new().path(path).open()?;
         let entries_tree = db.open_tree("entries")?;
+        let key_slots_tree = db.open_tree("key_slots")?;
+        let settings_tree = db.open_tree("settings")?;
+        let audit_tree = db.open_tree("audit")?;
+        let journal_tree = db.open_tree("journal")?;
 
-        Ok(Self { db, entries_tree })
+        Ok(Self {
+            db,
+            entries_tree,
+            key_slots_tree,
+            settings_tree,
+            audit_tree,
+            journal_tree,
+        })
     }
 
     pub fn flush(&self) -> Result<(), VaultError> {
@@ -47,7 +136,8 @@ impl Database {
 
     // --- Metadata Operations ---
 
-    /// Loads metadata (version, salt, check) or creates it if new.
+    /// Checks the vault version and loads the settings new slots use, or
+    /// records the version and creation date of a new vault.
     pub fn load_or_init_metadata(&self) -> Result<VaultMetadata, VaultError> {
         match self.db.get("vault_version")? {
             Some(v) => {
@@ -59,20 +149,8 @@ impl Database {
                     });
                 }
 
-                let created_at_bytes = self
-                    .db
-                    .get("created_at")?
-                    .ok_or(VaultError::Corruption("No date".into()))?;
-                let created_at = from_utf8(&created_at_bytes)?.parse::<u64>()?;
-
                 Ok(VaultMetadata {
-                    vault_version: ver,
-                    created_at,
-                    vault_salt: self
-                        .db
-                        .get("vault_salt")?
-                        .and_then(|x| x.as_ref().try_into().ok()),
-                    master_key_check: self.db.get("master_key_check")?.map(|x| x.to_vec()),
+                    kdf: self.get_kdf_params()?,
                 })
             }
             None => {
@@ -82,49 +160,627 @@ impl Database {
                     CURRENT_VAULT_VERSION.to_string().as_bytes(),
                 )?;
                 self.db.insert("created_at", ts.to_string().as_bytes())?;
+                self.db.insert(
+                    "key_derivation",
+                    CURRENT_KEY_DERIVATION.to_string().as_bytes(),
+                )?;
                 self.flush()?;
 
                 Ok(VaultMetadata {
-                    vault_version: CURRENT_VAULT_VERSION,
-                    created_at: ts,
-                    vault_salt: None,
-                    master_key_check: None,
+                    kdf: KdfParams::default(),
                 })
             }
         }
     }
 
-    pub fn save_salt_and_check(&self, salt: &[u8], check: &[u8]) -> Result<(), VaultError> {
-        self.db.insert("vault_salt", salt)?;
-        self.db.insert("master_key_check", check)?;
+    // --- Key Slot Operations ---
+
+    /// Returns every key slot. Vaults that predate key slots have their single
+    /// salt and wrapped key surfaced as slot 0 until the v3 migration runs.
+    pub fn get_key_slots(&self) -> Result<Vec<KeySlot>, VaultError> {
+        let mut slots = Vec::new();
+        for res in self.key_slots_tree.iter() {
+            let (_, v) = res?;
+            slots.push(Self::decode_key_slot(&v)?);
+        }
+
+        if slots.is_empty()
+            && let Some(slot) = self.legacy_key_slot()?
+        {
+            slots.push(slot);
+        }
+
+        Ok(slots)
+    }
+
+    /// Returns the KDF parameters new slots are wrapped with. Vaults that never
+    /// stored any were created with the defaults.
+    pub fn get_kdf_params(&self) -> Result<KdfParams, VaultError> {
+        match self.db.get("kdf_params")? {
+            Some(bytes) => Ok(postcard::from_bytes(&bytes)?),
+            None => Ok(KdfParams::default()),
+        }
+    }
+
+    /// Vaults created before subkeys existed have no stored version and use
+    /// the master key directly.
+    pub fn get_key_derivation(&self) -> Result<u32, VaultError> {
+        match self.db.get("key_derivation")? {
+            Some(v) => Ok(from_utf8(&v)?.parse::<u32>()?),
+            None => Ok(0),
+        }
+    }
+
+    pub fn save_kdf_params(&self, kdf: &KdfParams) -> Result<(), VaultError> {
+        self.db.insert("kdf_params", postcard::to_stdvec(kdf)?)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Inserts or replaces a key slot. A single sled insert is atomic, so a
+    /// slot is never observed half-written.
+    pub fn put_key_slot(&self, slot: &KeySlot) -> Result<(), VaultError> {
+        let bytes = postcard::to_stdvec(slot)?;
+        self.key_slots_tree.insert(slot.id.to_be_bytes(), bytes)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_key_slot(&self, id: u32) -> Result<(), VaultError> {
+        self.key_slots_tree
+            .remove(id.to_be_bytes())?
+            .ok_or_else(|| VaultError::NotFound(format!("Key slot {}", id)))?;
         self.flush()?;
         Ok(())
     }
 
+    pub fn next_key_slot_id(&self) -> Result<u32, VaultError> {
+        match self.key_slots_tree.last()? {
+            Some((k, _)) => {
+                let bytes: [u8; 4] = k
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| VaultError::Corruption("Bad key slot id".into()))?;
+                Ok(u32::from_be_bytes(bytes) + 1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn decode_key_slot(bytes: &[u8]) -> Result<KeySlot, VaultError> {
+        // Try the current format first, then the one without keyfiles, then
+        // the one without a KDF algorithm
+        if let Ok(slot) = postcard::from_bytes::<KeySlot>(bytes) {
+            return Ok(slot);
+        }
+        if let Ok(v2) = postcard::from_bytes::<KeySlotV2>(bytes) {
+            return Ok(KeySlot {
+                id: v2.id,
+                salt: v2.salt,
+                kdf: v2.kdf,
+                wrapped_key: v2.wrapped_key,
+                info: v2.info,
+                keyfile: false,
+            });
+        }
+        let v1: KeySlotV1 = postcard::from_bytes(bytes)?;
+        Ok(KeySlot {
+            id: v1.id,
+            salt: v1.salt,
+            kdf: KdfParams {
+                m_cost: v1.m_cost,
+                t_cost: v1.t_cost,
+                p_cost: v1.p_cost,
+                ..KdfParams::default()
+            },
+            wrapped_key: v1.wrapped_key,
+            info: v1.info,
+            keyfile: false,
+        })
+    }
+
+    fn legacy_key_slot(&self) -> Result<Option<KeySlot>, VaultError> {
+        let (Some(salt), Some(check)) =
+            (self.db.get("vault_salt")?, self.db.get("master_key_check")?)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(KeySlot {
+            id: 0,
+            salt: salt
+                .as_ref()
+                .try_into()
+                .map_err(|_| VaultError::Corruption("Bad vault salt".into()))?,
+            kdf: KdfParams::default(),
+            wrapped_key: check.to_vec(),
+            info: Vec::new(),
+            keyfile: false,
+        }))
+    }
+
+    // --- Failed Attempt Operations ---
+
+    pub fn get_failed_attempts(&self) -> Result<FailedAttempts, VaultError> {
+        match self.db.get("failed_attempts")? {
+            Some(bytes) => Ok(postcard::from_bytes(&bytes)?),
+            None => Ok(FailedAttempts::default()),
+        }
+    }
+
+    /// Flushed right away, so killing the process does not reset the count.
+    pub fn save_failed_attempts(&self, attempts: &FailedAttempts) -> Result<(), VaultError> {
+        self.db
+            .insert("failed_attempts", postcard::to_stdvec(attempts)?)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn clear_failed_attempts(&self) -> Result<(), VaultError> {
+        self.db.remove("failed_attempts")?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Removes every key slot of every library, leaving all data unreadable.
+    pub fn remove_all_key_slots(&self) -> Result<(), VaultError> {
+        self.key_slots_tree.clear()?;
+        self.flush()?;
+        Ok(())
+    }
+
+    // --- Rotation Operations ---
+
+    pub fn get_rotation(&self) -> Result<Option<RotationState>, VaultError> {
+        let Some(bytes) = self.db.get("rotation")? else {
+            return Ok(None);
+        };
+        if let Ok(state) = postcard::from_bytes(&bytes) {
+            return Ok(Some(state));
+        }
+
+        // Checkpoints from before key derivation versions are always version 0
+        let v1: RotationStateV1 = postcard::from_bytes(&bytes)?;
+        Ok(Some(RotationState {
+            wrapped_old_key: Some(v1.wrapped_old_key),
+            old_derivation: 0,
+            cursor: v1.cursor,
+            done: v1.done,
+            total: v1.total,
+            failed: v1.failed,
+            started_at: v1.started_at,
+        }))
+    }
+
+    /// Atomically writes the re-wrapped key slots, removes the `removed` ones
+    /// and records the rotation checkpoint, so the vault is never left with
+    /// slots for one key and no way to reach the other.
+    pub fn begin_rotation(
+        &self,
+        slots: &[KeySlot],
+        removed: &[u32],
+        state: &RotationState,
+    ) -> Result<(), VaultError> {
+        let slot_bytes = slots
+            .iter()
+            .map(|slot| Ok((slot.id.to_be_bytes(), postcard::to_stdvec(slot)?)))
+            .collect::<Result<Vec<_>, VaultError>>()?;
+        let state_bytes = postcard::to_stdvec(state)?;
+
+        let meta: &Tree = &self.db;
+        (&self.key_slots_tree, meta).transaction(|(slots_tree, meta)| {
+            for id in removed {
+                slots_tree.remove(&id.to_be_bytes())?;
+            }
+            for (id, bytes) in &slot_bytes {
+                slots_tree.insert(id, bytes.as_slice())?;
+            }
+            meta.insert("rotation", state_bytes.as_slice())?;
+            Ok::<_, ConflictableTransactionError<VaultError>>(())
+        })?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Raw entry rows after `cursor` (or from the start), in key order.
+    pub fn entries_after(&self, cursor: Option<&[u8]>) -> Vec<(IVec, IVec)> {
+        let iter = match cursor {
+            Some(cursor) => self
+                .entries_tree
+                .range::<&[u8], _>((Bound::Excluded(cursor), Bound::Unbounded)),
+            None => self.entries_tree.iter(),
+        };
+        iter.filter_map(|res| res.ok()).collect()
+    }
+
+    pub fn count_entries(&self) -> u64 {
+        self.entries_tree.len() as u64
+    }
+
+    /// Stores one re-encrypted entry under its new key, removing the row at
+    /// its old key, together with the advanced checkpoint.
+    pub fn commit_rotated_entry(
+        &self,
+        old_key: &[u8],
+        new_key: &[u8],
+        encrypted: &[u8],
+        state: &RotationState,
+    ) -> Result<(), VaultError> {
+        let state_bytes = postcard::to_stdvec(state)?;
+        let meta: &Tree = &self.db;
+        (&self.entries_tree, meta).transaction(|(entries, meta)| {
+            if old_key != new_key {
+                entries.remove(old_key)?;
+            }
+            entries.insert(new_key, encrypted)?;
+            meta.insert("rotation", state_bytes.as_slice())?;
+            Ok::<_, ConflictableTransactionError<VaultError>>(())
+        })?;
+        Ok(())
+    }
+
+    pub fn finish_rotation(&self) -> Result<(), VaultError> {
+        self.db.remove("rotation")?;
+        self.flush()?;
+        Ok(())
+    }
+
+    // --- Setting Operations ---
+
+    pub fn get_setting<T: DeserializeOwned>(
+        &self,
+        keys: &VaultKeys,
+        name: &str,
+    ) -> Result<Option<T>, VaultError> {
+        let (row, key) = Self::setting_row(keys, name)?;
+        let Some(encrypted) = self.settings_tree.get(&row)? else {
+            return Ok(None);
+        };
+        let decrypted = Zeroizing::new(crypto::decrypt(key.expose_secret(), &encrypted, &row)?);
+        Ok(Some(postcard::from_bytes(&decrypted)?))
+    }
+
+    pub fn put_setting<T: Serialize>(
+        &self,
+        keys: &VaultKeys,
+        name: &str,
+        value: &T,
+    ) -> Result<(), VaultError> {
+        let (row, key) = Self::setting_row(keys, name)?;
+        let bytes = Zeroizing::new(postcard::to_stdvec(value)?);
+        let encrypted = crypto::encrypt(key.expose_secret(), &bytes, &row)?;
+        self.settings_tree.insert(row, encrypted)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_setting(&self, keys: &VaultKeys, name: &str) -> Result<(), VaultError> {
+        let (row, _) = Self::setting_row(keys, name)?;
+        self.settings_tree.remove(row)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Re-encrypts a setting from the old keys of a rotation under the new
+    /// ones. A copy already under the new keys is newer and left alone.
+    pub fn copy_setting(
+        &self,
+        old_keys: &VaultKeys,
+        new_keys: &VaultKeys,
+        name: &str,
+    ) -> Result<(), VaultError> {
+        let (old_row, old_key) = Self::setting_row(old_keys, name)?;
+        let (new_row, new_key) = Self::setting_row(new_keys, name)?;
+        if old_row == new_row || self.settings_tree.contains_key(&new_row)? {
+            return Ok(());
+        }
+        let Some(encrypted) = self.settings_tree.get(&old_row)? else {
+            return Ok(());
+        };
+
+        let bytes = Zeroizing::new(crypto::decrypt(
+            old_key.expose_secret(),
+            &encrypted,
+            &old_row,
+        )?);
+        let encrypted = crypto::encrypt(new_key.expose_secret(), &bytes, &new_row)?;
+        self.settings_tree.insert(new_row, encrypted)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Removes the copy of a setting under the old keys once a rotation is done.
+    pub fn remove_rotated_setting(
+        &self,
+        old_keys: &VaultKeys,
+        new_keys: &VaultKeys,
+        name: &str,
+    ) -> Result<(), VaultError> {
+        let (old_row, _) = Self::setting_row(old_keys, name)?;
+        let (new_row, _) = Self::setting_row(new_keys, name)?;
+        if old_row != new_row {
+            self.settings_tree.remove(old_row)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Settings are keyed and encrypted like entries, with a key of their own
+    /// derived from the extension root.
+    fn setting_row(keys: &VaultKeys, name: &str) -> Result<(Vec<u8>, SecretKey), VaultError> {
+        Ok((
+            crypto::setting_key(keys.index.expose_secret(), name)?,
+            crypto::derive_subkey(keys.extensions.expose_secret(), b"vanta/settings")?,
+        ))
+    }
+
+    // --- Journal Operations ---
+
+    /// Stores a journal record and flushes, so it is on disk before the
+    /// operation it describes touches anything.
+    pub fn put_journal_record(
+        &self,
+        keys: &VaultKeys,
+        row: &[u8],
+        record: &JournalRecord,
+    ) -> Result<(), VaultError> {
+        let bytes = postcard::to_stdvec(record)?;
+        let encrypted = crypto::encrypt(Self::journal_key(keys)?.expose_secret(), &bytes, row)?;
+        self.journal_tree.insert(row, encrypted)?;
+        self.flush()
+    }
+
+    /// The journal records of the library `keys` belong to, with their rows.
+    /// Those of other libraries do not decrypt and are skipped.
+    pub fn get_journal_records(
+        &self,
+        keys: &VaultKeys,
+    ) -> Result<Vec<(Vec<u8>, JournalRecord)>, VaultError> {
+        let key = Self::journal_key(keys)?;
+        let mut records = Vec::new();
+        for row in self.journal_tree.iter() {
+            let (k, v) = row?;
+            if let Ok(decrypted) = crypto::decrypt(key.expose_secret(), &v, &k) {
+                records.push((k.to_vec(), postcard::from_bytes(&decrypted)?));
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn remove_journal_record(&self, row: &[u8]) -> Result<(), VaultError> {
+        self.journal_tree.remove(row)?;
+        Ok(())
+    }
+
+    fn journal_key(keys: &VaultKeys) -> Result<SecretKey, VaultError> {
+        crypto::derive_subkey(keys.extensions.expose_secret(), b"vanta/journal")
+    }
+
+    // --- Backup Operations ---
+
+    /// Every tree with its records as stored, so nothing is decrypted.
+    pub fn export_trees(&self) -> Result<Vec<DbTree>, VaultError> {
+        let mut trees = Vec::new();
+        for name in self.db.tree_names() {
+            let mut records = Vec::new();
+            for row in self.db.open_tree(&name)?.iter() {
+                let (k, v) = row?;
+                records.push((k.to_vec(), v.to_vec()));
+            }
+            trees.push(DbTree {
+                name: name.to_vec(),
+                records,
+            });
+        }
+        Ok(trees)
+    }
+
+    /// Brings the database at `path`, created if need be, in line with
+    /// exported trees, writing only the records that differ. Each tree is
+    /// updated in one batch. Returns how many records were written or removed.
+    pub fn mirror_trees(path: &str, trees: &[DbTree]) -> Result<u64, VaultError> {
+        let target = Self::open_once(path)?;
+        let mut changed = 0;
+        for tree in trees {
+            let mirror = target.open_tree(&tree.name)?;
+            let mut batch = Batch::default();
+            for (k, v) in &tree.records {
+                if mirror.get(k)?.as_deref() != Some(v.as_slice()) {
+                    batch.insert(k.as_slice(), v.as_slice());
+                    changed += 1;
+                }
+            }
+            // Exported in key order
+            for k in mirror.iter().keys() {
+                let k = k?;
+                if tree
+                    .records
+                    .binary_search_by(|(key, _)| key.as_slice().cmp(&k))
+                    .is_err()
+                {
+                    batch.remove(k);
+                    changed += 1;
+                }
+            }
+            mirror.apply_batch(batch)?;
+        }
+        for name in target.tree_names() {
+            if !trees.iter().any(|tree| tree.name == *name) {
+                target.drop_tree(&name)?;
+            }
+        }
+        target.flush()?;
+        Ok(changed)
+    }
+
+    /// Creates a database at `path` from exported trees.
+    pub fn import_trees(path: &str, trees: Vec<DbTree>) -> Result<(), VaultError> {
+        let db = Self::open_once(path)?;
+        for tree in trees {
+            let mut batch = Batch::default();
+            for (k, v) in tree.records {
+                batch.insert(k, v);
+            }
+            db.open_tree(&tree.name)?.apply_batch(batch)?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Opens a database that is flushed by hand and closed right after use,
+    /// such as a mirror or one being restored. sled lets go of its lock only
+    /// once its threads are done with it, a moment after it is closed, so a
+    /// database the last run just closed is waited for a while.
+    fn open_once(path: &str) -> Result<Db, VaultError> {
+        let config = Config::new().path(path).flush_every_ms(None);
+        let mut attempts = 0;
+        loop {
+            match config.open() {
+                Err(e) if Self::is_locked(&e) && attempts < OPEN_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(OPEN_RETRY_DELAY);
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    /// Whether opening failed because the database is locked by another
+    /// handle, which sled only tells by its message.
+    pub(super) fn is_locked(e: &sled::Error) -> bool {
+        matches!(e, sled::Error::Io(e) if e.kind() == std::io::ErrorKind::Other
+            && e.to_string().starts_with("could not acquire lock"))
+    }
+
+    // --- Audit Operations ---
+
+    /// Stores an encrypted audit record together with the log's state, in one
+    /// transaction so the state always describes the records there are.
+    pub fn append_audit_record(
+        &self,
+        keys: &VaultKeys,
+        name: &str,
+        state: &AuditState,
+        row: &[u8],
+        encrypted: &[u8],
+    ) -> Result<(), VaultError> {
+        let (state_row, key) = Self::setting_row(keys, name)?;
+        let bytes = Zeroizing::new(postcard::to_stdvec(state)?);
+        let state_encrypted = crypto::encrypt(key.expose_secret(), &bytes, &state_row)?;
+        (&self.audit_tree, &self.settings_tree).transaction(|(audit, settings)| {
+            audit.insert(row, encrypted)?;
+            settings.insert(state_row.as_slice(), state_encrypted.as_slice())?;
+            Ok::<_, ConflictableTransactionError<VaultError>>(())
+        })?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn get_audit_record(&self, row: &[u8]) -> Result<Option<IVec>, VaultError> {
+        Ok(self.audit_tree.get(row)?)
+    }
+
+    pub fn remove_audit_record(&self, row: &[u8]) -> Result<(), VaultError> {
+        self.audit_tree.remove(row)?;
+        Ok(())
+    }
+
     // --- Entry Operations (Encrypt/Decrypt + Read/Write) ---
 
     /// Serializes, Encrypts, and Saves an entry
-    pub fn insert_entry(&self, key: &[u8], entry: &ImageEntry) -> Result<(), VaultError> {
+    pub fn insert_entry(&self, keys: &VaultKeys, entry: &ImageEntry) -> Result<(), VaultError> {
         let bytes = postcard::to_stdvec(entry)?;
-        // We use the ID as AAD (Additional Authenticated Data) to bind the encryption to this specific UUID
-        let encrypted = crypto::encrypt(key, &bytes, entry.id.as_bytes())?;
-        self.entries_tree.insert(entry.id.as_bytes(), encrypted)?;
+        let row = Self::entry_key(keys, entry.id)?;
+        // We use the row key as AAD (Additional Authenticated Data) to bind the encryption to this specific entry
+        let encrypted = crypto::encrypt(keys.entries.expose_secret(), &bytes, &row)?;
+        self.entries_tree.insert(row, encrypted)?;
+        Ok(())
+    }
+
+    /// Stores several entries in one batch, so either all of them change or
+    /// none does.
+    pub fn insert_entries(
+        &self,
+        keys: &VaultKeys,
+        entries: &[ImageEntry],
+    ) -> Result<(), VaultError> {
+        let mut batch = Batch::default();
+        for entry in entries {
+            let bytes = postcard::to_stdvec(entry)?;
+            let row = Self::entry_key(keys, entry.id)?;
+            let encrypted = crypto::encrypt(keys.entries.expose_secret(), &bytes, &row)?;
+            batch.insert(row, encrypted);
+        }
+        self.entries_tree.apply_batch(batch)?;
         Ok(())
     }
 
     /// Reads, Decrypts, and Deserializes an entry
-    pub fn get_entry(&self, key: &[u8], id: Uuid) -> Result<ImageEntry, VaultError> {
+    pub fn get_entry(&self, keys: &VaultKeys, id: Uuid) -> Result<ImageEntry, VaultError> {
+        let row = Self::entry_key(keys, id)?;
         let encrypted = self
             .entries_tree
-            .get(id.as_bytes())?
+            .get(&row)?
             .ok_or_else(|| VaultError::NotFound(id.to_string()))?;
 
-        let decrypted = crypto::decrypt(key, &encrypted, id.as_bytes())?;
-        Ok(postcard::from_bytes(&decrypted)?)
+        let decrypted = crypto::decrypt(keys.entries.expose_secret(), &encrypted, &row)?;
+        Self::decode_entry(&decrypted)
+    }
+
+    /// Entries are stored under a keyed hash of their id, which only appears
+    /// inside the ciphertext. Rows from before v7 are keyed by the raw id; as
+    /// that was also their AAD, every row decrypts with its own key as AAD.
+    pub fn entry_key(keys: &VaultKeys, id: Uuid) -> Result<Vec<u8>, VaultError> {
+        crypto::entry_key(keys.index.expose_secret(), id.as_bytes())
+    }
+
+    /// Deserializes an entry, falling back to the formats without a deletion
+    /// time and without blob names. Entries without blob names keep their
+    /// blobs at the legacy per-image paths until the v6 migration moves them.
+    pub fn decode_entry(bytes: &[u8]) -> Result<ImageEntry, VaultError> {
+        if let Ok(entry) = postcard::from_bytes::<ImageEntry>(bytes) {
+            return Ok(entry);
+        }
+        if let Ok(v3) = postcard::from_bytes::<ImageEntryV3>(bytes) {
+            return Ok(ImageEntry {
+                id: v3.id,
+                original_mime: v3.original_mime,
+                original_size: v3.original_size,
+                created_at: v3.created_at,
+                variants: v3.variants,
+                tags: v3.tags,
+                linked_images: v3.linked_images,
+                blobs: v3.blobs,
+                deleted_at: None,
+            });
+        }
+
+        let v2: ImageEntryV2 = postcard::from_bytes(bytes)?;
+        Ok(ImageEntry {
+            id: v2.id,
+            original_mime: v2.original_mime,
+            original_size: v2.original_size,
+            created_at: v2.created_at,
+            variants: v2.variants,
+            tags: v2.tags,
+            linked_images: v2
+                .linked_images
+                .into_iter()
+                .map(|l| LinkedImage {
+                    id: l.id,
+                    original_mime: l.original_mime,
+                    original_size: l.original_size,
+                    variants: l.variants,
+                    blobs: Vec::new(),
+                })
+                .collect(),
+            blobs: Vec::new(),
+            deleted_at: None,
+        })
     }
 
-    pub fn remove_entry(&self, id: Uuid) -> Result<(), VaultError> {
-        self.entries_tree.remove(id.as_bytes())?;
+    pub fn remove_entry(&self, keys: &VaultKeys, id: Uuid) -> Result<(), VaultError> {
+        self.entries_tree.remove(Self::entry_key(keys, id)?)?;
         Ok(())
     }
 
@@ -142,12 +798,10 @@ impl Database {
         let mut list: Vec<ImageEntry> = raw_rows
             .par_iter()
             .filter_map(|(k, v)| {
-                if let Ok(id) = Uuid::from_slice(k) {
-                    if let Ok(decrypted) = crypto::decrypt(key, v, id.as_bytes()) {
-                        if let Ok(entry) = postcard::from_bytes::<ImageEntry>(&decrypted) {
-                            return Some(entry);
-                        }
-                    }
+                if let Ok(decrypted) = crypto::decrypt(key, v, k)
+                    && let Ok(entry) = Self::decode_entry(&decrypted)
+                {
+                    return Some(entry);
                 }
                 None
             })
@@ -159,6 +813,24 @@ impl Database {
         Ok(list)
     }
 
+    /// Like `get_all_entries`, but also counts the rows that decrypt under
+    /// `key` and still fail to decode. Rows that do not decrypt at all cannot
+    /// be told apart from those of other libraries, so they are not counted.
+    pub fn check_entries(&self, key: &[u8]) -> Result<(Vec<ImageEntry>, u64), VaultError> {
+        let mut entries = Vec::new();
+        let mut corrupt = 0;
+        for row in self.entries_tree.iter() {
+            let (k, v) = row?;
+            if let Ok(decrypted) = crypto::decrypt(key, &v, &k) {
+                match Self::decode_entry(&decrypted) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => corrupt += 1,
+                }
+            }
+        }
+        Ok((entries, corrupt))
+    }
+
     // --- Migration ---
 
     /// Returns the current vault version stored in the DB.
@@ -179,7 +851,7 @@ impl Database {
             let decrypted = crypto::decrypt(key, &v, id.as_bytes())?;
 
             // Try v2 format first (handles interrupted migration)
-            let entry = match postcard::from_bytes::<ImageEntry>(&decrypted) {
+            let entry = match Self::decode_entry(&decrypted) {
                 Ok(e) => e,
                 Err(_) => {
                     // Must be v1 format — convert
@@ -192,6 +864,8 @@ impl Database {
                         variants: v1.variants,
                         tags: v1.tags,
                         linked_images: Vec::new(),
+                        blobs: Vec::new(),
+                        deleted_at: None,
                     }
                 }
             };
@@ -203,8 +877,83 @@ impl Database {
         }
 
         // Update version
-        self.db
-            .insert("vault_version", CURRENT_VAULT_VERSION.to_string().as_bytes())?;
+        self.db.insert("vault_version", "2".as_bytes())?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Moves the single legacy salt/wrapped key pair into the key slot table.
+    /// `info` is the already-encrypted slot label for the migrated slot.
+    pub fn migrate_v2_to_v3(&self, info: Vec<u8>) -> Result<(), VaultError> {
+        if let Some(mut slot) = self.legacy_key_slot()? {
+            slot.info = info;
+            let bytes = postcard::to_stdvec(&slot)?;
+
+            // The slot and the removal of the legacy keys land together
+            let meta: &Tree = &self.db;
+            (&self.key_slots_tree, meta).transaction(|(slots, meta)| {
+                slots.insert(&slot.id.to_be_bytes(), bytes.as_slice())?;
+                meta.remove("vault_salt")?;
+                meta.remove("master_key_check")?;
+                meta.insert("vault_version", "3".as_bytes())?;
+                Ok::<_, ConflictableTransactionError<VaultError>>(())
+            })?;
+        } else {
+            self.db.insert("vault_version", "3".as_bytes())?;
+        }
+
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Moves the vault to derived subkeys. Nothing is re-encrypted here: the
+    /// checkpoint hands the work to the rotation worker, which resumes it
+    /// across restarts like any other rotation.
+    pub fn migrate_v3_to_v4(&self, state: &RotationState) -> Result<(), VaultError> {
+        let state_bytes = postcard::to_stdvec(state)?;
+        let derivation = CURRENT_KEY_DERIVATION.to_string();
+
+        self.db.transaction(|meta| {
+            meta.insert("rotation", state_bytes.as_slice())?;
+            meta.insert("key_derivation", derivation.as_bytes())?;
+            meta.insert("vault_version", "4".as_bytes())?;
+            Ok::<_, ConflictableTransactionError<VaultError>>(())
+        })?;
+
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Pads existing blobs by handing them to the rotation worker, which
+    /// rewrites every blob in the current format.
+    pub fn migrate_v4_to_v5(&self, state: &RotationState) -> Result<(), VaultError> {
+        self.begin_rewrite(state, "5")
+    }
+
+    /// Moves blobs from `{id}/{variant}.enc` to opaque names. The rotation
+    /// worker names and moves each blob as it rewrites it, and stores the
+    /// names in the entry.
+    pub fn migrate_v5_to_v6(&self, state: &RotationState) -> Result<(), VaultError> {
+        self.begin_rewrite(state, "6")
+    }
+
+    /// Moves entries from their raw ids to keyed hashes of them. The rotation
+    /// worker re-keys each row as it re-encrypts it.
+    pub fn migrate_v6_to_v7(&self, state: &RotationState) -> Result<(), VaultError> {
+        self.begin_rewrite(state, "7")
+    }
+
+    /// Records a checkpoint for the rotation worker along with the version it
+    /// brings the vault to.
+    fn begin_rewrite(&self, state: &RotationState, version: &str) -> Result<(), VaultError> {
+        let state_bytes = postcard::to_stdvec(state)?;
+
+        self.db.transaction(|meta| {
+            meta.insert("rotation", state_bytes.as_slice())?;
+            meta.insert("vault_version", version.as_bytes())?;
+            Ok::<_, ConflictableTransactionError<VaultError>>(())
+        })?;
+
         self.flush()?;
         Ok(())
     }
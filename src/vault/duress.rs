@@ -0,0 +1,142 @@
+use super::{
+    PRIMARY_SLOT_LABEL, RECOVERY_SLOT_LABEL, Vault,
+    crypto::{self, VaultKeys},
+    error::VaultError,
+    rotation::{ROTATION_AAD, SETTINGS},
+    secret::SecretKey,
+    types::{Credentials, SlotKind},
+};
+use secrecy::ExposeSecret;
+use std::{io, sync::atomic::Ordering};
+use zeroize::Zeroizing;
+
+impl Vault {
+    /// Creates a decoy library that `duress_password` unlocks instead of this
+    /// one. It has its own master key, password slot and recovery slot, kept
+    /// in the same trees and directories as everything else, so neither the
+    /// files nor the API tell the two libraries apart. Returns the decoy's
+    /// recovery key, which is never stored and cannot be shown again.
+    ///
+    /// With `destroy_others`, unlocking with the duress password removes the
+    /// key slots of every other library, leaving their data unreadable. The
+    /// duress password requires the same keyfile as `credentials`, if any.
+    pub fn create_decoy(
+        &self,
+        credentials: Credentials,
+        duress_password: &str,
+        destroy_others: bool,
+    ) -> Result<String, VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (slot, _) = self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+        let keyfile = keyfile.filter(|_| slot.keyfile);
+        if self
+            .open_key_slot(duress_password, keyfile.as_ref(), SlotKind::Password)
+            .is_ok()
+        {
+            return Err(VaultError::Corruption("Password is already in use".into()));
+        }
+
+        let master_key = SecretKey::random();
+        let kdf = self.slot_kdf();
+        let id = self.db.next_key_slot_id()?;
+        let kind = if destroy_others {
+            SlotKind::Duress
+        } else {
+            SlotKind::Password
+        };
+        let password_slot = Self::wrap_key_slot(
+            id,
+            duress_password,
+            keyfile.as_ref(),
+            master_key.expose_secret(),
+            &kdf,
+            PRIMARY_SLOT_LABEL,
+            kind,
+        )?;
+
+        let recovery_key = crypto::generate_recovery_key();
+        let recovery_slot = Self::wrap_key_slot(
+            id + 1,
+            &Self::recovery_secret(&recovery_key)?,
+            None,
+            master_key.expose_secret(),
+            &kdf,
+            RECOVERY_SLOT_LABEL,
+            SlotKind::Recovery,
+        )?;
+
+        self.db.put_key_slot(&password_slot)?;
+        self.db.put_key_slot(&recovery_slot)?;
+
+        Ok(recovery_key)
+    }
+
+    /// Deletes the library `duress_password` unlocks, with the same keyfile
+    /// as `credentials`: its entries, blobs, settings and key slots. The unlocked
+    /// library cannot remove itself this way.
+    pub async fn remove_decoy(
+        &self,
+        credentials: Credentials<'_>,
+        duress_password: &str,
+    ) -> Result<(), VaultError> {
+        if self.is_busy() {
+            return Err(VaultError::Busy("Re-encryption in progress".into()));
+        }
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let password = Zeroizing::new(credentials.password.to_string());
+        let duress_password = Zeroizing::new(duress_password.to_string());
+        // Both checks derive a key per slot, off the async runtime
+        let vault = self.clone();
+        let master_key = tokio::task::spawn_blocking(move || {
+            vault.open_unlocked_key_slot(&password, keyfile.as_ref())?;
+            vault
+                .open_key_slot(&duress_password, keyfile.as_ref(), SlotKind::Password)
+                .map(|(_, master_key)| master_key)
+        })
+        .await
+        .map_err(|e| VaultError::Io(io::Error::other(e)))??;
+        let key = master_key.expose_secret();
+        if self.unlocked_keys()?.master.expose_secret() == key {
+            return Err(VaultError::Corruption(
+                "Cannot remove the unlocked library".into(),
+            ));
+        }
+
+        // Entries first, so an interrupted removal can be retried while the
+        // slots still open the library
+        let keys = VaultKeys::derive(key, self.db.get_key_derivation()?)?;
+        self.replay_journal(&keys)?;
+        for entry in self.db.get_all_entries(keys.entries.expose_secret())? {
+            self.db.remove_entry(&keys, entry.id)?;
+            Self::remove_blobs(&Self::entry_blobs(&entry)).await?;
+        }
+        self.remove_audit_log(&keys)?;
+        for name in SETTINGS {
+            self.db.remove_setting(&keys, name)?;
+        }
+        for slot in self.own_key_slots(key)? {
+            self.db.remove_key_slot(slot.id)?;
+        }
+        Ok(())
+    }
+
+    /// Run when a duress slot unlocks: removes every slot `master_key` does
+    /// not open, and the checkpoint of another library's rotation, which could
+    /// never finish without them.
+    pub(super) fn remove_foreign_key_slots(&self, master_key: &[u8]) -> Result<(), VaultError> {
+        for slot in self.db.get_key_slots()? {
+            if Self::slot_kind(master_key, &slot).is_none() {
+                self.db.remove_key_slot(slot.id)?;
+            }
+        }
+
+        if let Some(state) = self.db.get_rotation()?
+            && !self.rotation_worker.load(Ordering::SeqCst)
+            && let Some(wrapped) = &state.wrapped_old_key
+            && crypto::decrypt(master_key, wrapped, ROTATION_AAD).is_err()
+        {
+            self.db.finish_rotation()?;
+        }
+        Ok(())
+    }
+}
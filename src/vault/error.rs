@@ -1,3 +1,4 @@
+use sled::transaction::TransactionError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -42,4 +43,22 @@ pub enum VaultError {
 
     #[error("Zip error: {0}")]
     Zip(String),
+
+    #[error("Vault is busy: {0}")]
+    Busy(String),
+
+    #[error("Too many failed attempts, retry in {0} seconds")]
+    Throttled(u64),
+
+    #[error("Too many failed attempts, unlock with the recovery key")]
+    LockedOut,
+}
+
+impl From<TransactionError<VaultError>> for VaultError {
+    fn from(e: TransactionError<VaultError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => VaultError::Db(e),
+        }
+    }
 }
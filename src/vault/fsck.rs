@@ -0,0 +1,305 @@
+use super::{
+    DATA_DIR, Vault,
+    blob::BlobReader,
+    crypto::VaultKeys,
+    error::VaultError,
+    types::{FsckIssue, FsckProblem, FsckReport, ImageEntry, ImageVariant, StoredBlob},
+};
+use crate::image_processor;
+use secrecy::ExposeSecret;
+use std::{collections::HashSet, path::Path};
+use tokio::fs;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Where orphans are moved, under the same relative path they had in `DATA_DIR`
+const QUARANTINE_DIR: &str = "vault/quarantine";
+
+/// Every image is stored with all of these
+const VARIANTS: [ImageVariant; 4] = [
+    ImageVariant::Original,
+    ImageVariant::High,
+    ImageVariant::Low,
+    ImageVariant::Thumbnail,
+];
+
+/// The cover of an entry or one of its linked images, as checked.
+struct CheckedImage<'a> {
+    entry: Uuid,
+    linked: Option<Uuid>,
+    mime: &'a str,
+    variants: &'a mut Vec<ImageVariant>,
+    blobs: &'a mut Vec<StoredBlob>,
+}
+
+impl CheckedImage<'_> {
+    fn id(&self) -> Uuid {
+        self.linked.unwrap_or(self.entry)
+    }
+
+    fn problem(&self, variant: ImageVariant, issue: FsckIssue, repaired: bool) -> FsckProblem {
+        FsckProblem {
+            entry: self.entry,
+            linked: self.linked,
+            variant,
+            issue,
+            repaired,
+        }
+    }
+}
+
+impl Vault {
+    /// Checks the unlocked library against `vault/storage`: decrypts every
+    /// entry and every blob it lists, and looks for storage none of its
+    /// entries refer to, such as the directories of deleted linked images.
+    ///
+    /// With `repair`, missing or unreadable variants are regenerated from the
+    /// original, or moved back if an earlier check quarantined them, and
+    /// orphans are quarantined. The blobs of other libraries look like
+    /// orphans as well, which is why they are only ever moved: checking their
+    /// own library brings them back.
+    pub async fn check(&self, repair: bool) -> Result<FsckReport, VaultError> {
+        let keys = self.with_data(|data| Ok(data.keys.clone()))?;
+        let (entries, corrupt_entries) = self.db.check_entries(keys.entries.expose_secret())?;
+        let mut report = FsckReport {
+            entries: entries.len() as u64,
+            corrupt_entries,
+            ..FsckReport::default()
+        };
+
+        let mut checked = Vec::with_capacity(entries.len());
+        for mut entry in entries {
+            let cover = CheckedImage {
+                entry: entry.id,
+                linked: None,
+                mime: &entry.original_mime,
+                variants: &mut entry.variants,
+                blobs: &mut entry.blobs,
+            };
+            let mut changed = self.check_image(&keys, cover, repair, &mut report).await?;
+            for linked in &mut entry.linked_images {
+                let image = CheckedImage {
+                    entry: entry.id,
+                    linked: Some(linked.id),
+                    mime: &linked.original_mime,
+                    variants: &mut linked.variants,
+                    blobs: &mut linked.blobs,
+                };
+                changed |= self.check_image(&keys, image, repair, &mut report).await?;
+            }
+            if changed {
+                self.db.insert_entry(&keys, &entry)?;
+            }
+            checked.push(entry);
+        }
+
+        report.orphans = Self::find_orphans(&checked).await?;
+        if repair {
+            for orphan in &report.orphans {
+                Self::quarantine(orphan).await?;
+            }
+            report.quarantined = true;
+        }
+        Ok(report)
+    }
+
+    /// Checks every variant of one image, repairing what it can. Returns
+    /// whether the entry changed and has to be saved.
+    async fn check_image(
+        &self,
+        keys: &VaultKeys,
+        image: CheckedImage<'_>,
+        repair: bool,
+        report: &mut FsckReport,
+    ) -> Result<bool, VaultError> {
+        let id = image.id();
+        let mut original = None;
+        let mut broken = Vec::new();
+
+        for variant in VARIANTS {
+            let listed = image.variants.contains(&variant);
+            let path = Self::blob_path(id, variant, StoredBlob::find(image.blobs, variant));
+            let mut read = match listed {
+                true => Self::read_variant(keys, id, variant, &path).await,
+                false => Err(FsckIssue::Missing),
+            };
+
+            let mut restored = false;
+            if repair
+                && listed
+                && read == Err(FsckIssue::Missing)
+                && Self::restore_quarantined(&path).await?
+            {
+                read = Self::read_variant(keys, id, variant, &path).await;
+                restored = read.is_ok();
+            }
+
+            match read {
+                Ok(data) => {
+                    report.blobs += 1;
+                    if restored {
+                        report
+                            .problems
+                            .push(image.problem(variant, FsckIssue::Missing, true));
+                    }
+                    if variant == ImageVariant::Original {
+                        original = Some(data);
+                    }
+                }
+                Err(issue) => broken.push((variant, issue, path)),
+            }
+        }
+
+        // Only derived variants can be regenerated, and only from a readable original
+        let mut regenerated = match &original {
+            Some(original) if repair && !broken.is_empty() => {
+                match image_processor::process_upload(original, image.mime) {
+                    Ok(processed) => processed.variants,
+                    Err(e) => {
+                        eprintln!("Failed to regenerate variants of {}: {}", id, e);
+                        Vec::new()
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+        regenerated.retain(|(v, _)| *v != ImageVariant::Original);
+
+        let mut changed = false;
+        for (variant, issue, path) in broken {
+            let repaired = match regenerated.iter().position(|(v, _)| *v == variant) {
+                Some(pos) => {
+                    if issue == FsckIssue::Unreadable {
+                        Self::quarantine(&path).await?;
+                    }
+                    let bytes = regenerated.swap_remove(pos);
+                    let stored = self.write_variants(keys, id, &[bytes]).await?;
+                    image.blobs.retain(|b| b.variant != variant);
+                    image.blobs.extend(stored);
+                    if !image.variants.contains(&variant) {
+                        image.variants.push(variant);
+                    }
+                    report.blobs += 1;
+                    changed = true;
+                    true
+                }
+                None => false,
+            };
+            report
+                .problems
+                .push(image.problem(variant, issue, repaired));
+        }
+        Ok(changed)
+    }
+
+    /// Decrypts one blob in full.
+    async fn read_variant(
+        keys: &VaultKeys,
+        id: Uuid,
+        variant: ImageVariant,
+        path: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, FsckIssue> {
+        let aad = Self::make_aad(id, variant.filename());
+        match BlobReader::open(path, keys.blobs.expose_secret(), &aad).await {
+            Ok(reader) => reader
+                .read_to_end()
+                .await
+                .map_err(|_| FsckIssue::Unreadable),
+            Err(VaultError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(FsckIssue::Missing)
+            }
+            Err(_) => Err(FsckIssue::Unreadable),
+        }
+    }
+
+    /// Everything in `DATA_DIR` that is neither a blob of `entries` nor the
+    /// directory of one of their images stored before opaque names.
+    pub(super) async fn find_orphans(entries: &[ImageEntry]) -> Result<Vec<String>, VaultError> {
+        let mut paths = HashSet::new();
+        let mut ids = HashSet::new();
+        for entry in entries {
+            ids.insert(entry.id);
+            ids.extend(entry.linked_images.iter().map(|l| l.id));
+            paths.extend(
+                Self::entry_blobs(entry)
+                    .into_iter()
+                    .map(|(id, variant, name)| Self::blob_path(id, variant, name.as_deref())),
+            );
+        }
+
+        let mut orphans = Vec::new();
+        let mut dirs = match fs::read_dir(DATA_DIR).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(orphans),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(dir) = dirs.next_entry().await? {
+            let name = dir.file_name().to_string_lossy().into_owned();
+            let path = format!("{}/{}", DATA_DIR, name);
+            if !dir.file_type().await?.is_dir() {
+                orphans.push(path);
+                continue;
+            }
+            // The directory of an image that is gone is an orphan as a whole
+            if let Ok(id) = Uuid::parse_str(&name)
+                && !ids.contains(&id)
+            {
+                orphans.push(path);
+                continue;
+            }
+
+            let mut files = fs::read_dir(&path).await?;
+            while let Some(file) = files.next_entry().await? {
+                let file = format!("{}/{}", path, file.file_name().to_string_lossy());
+                if !paths.contains(&file) {
+                    orphans.push(file);
+                }
+            }
+        }
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    /// Moves a file or directory from `DATA_DIR` into quarantine. A directory
+    /// is moved one file at a time, so it can join one quarantined before.
+    async fn quarantine(path: &str) -> Result<(), VaultError> {
+        let target = Self::quarantine_path(path);
+        if !fs::metadata(path).await?.is_dir() {
+            Self::move_file(path, &target).await?;
+            return Ok(());
+        }
+
+        fs::create_dir_all(&target).await?;
+        let mut files = fs::read_dir(path).await?;
+        while let Some(file) = files.next_entry().await? {
+            let name = file.file_name().to_string_lossy().into_owned();
+            fs::rename(file.path(), format!("{}/{}", target, name)).await?;
+        }
+        fs::remove_dir(path).await?;
+        Ok(())
+    }
+
+    /// Moves the quarantined copy of `path` back, if there is one. Returns
+    /// whether there was.
+    async fn restore_quarantined(path: &str) -> Result<bool, VaultError> {
+        let quarantined = Self::quarantine_path(path);
+        if !fs::try_exists(&quarantined).await? {
+            return Ok(false);
+        }
+        Self::move_file(&quarantined, path).await?;
+        Ok(true)
+    }
+
+    fn quarantine_path(path: &str) -> String {
+        path.replacen(DATA_DIR, QUARANTINE_DIR, 1)
+    }
+
+    async fn move_file(from: &str, to: &str) -> Result<(), VaultError> {
+        if let Some(parent) = Path::new(to).parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(from, to).await?;
+        Ok(())
+    }
+}
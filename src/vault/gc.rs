@@ -0,0 +1,117 @@
+use super::{Vault, error::VaultError, types::GcReport};
+use secrecy::ExposeSecret;
+use std::{
+    io::ErrorKind,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::fs;
+
+/// How often the background task looks whether a collection is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+impl Vault {
+    /// Removes what `vault/storage` still holds for images no entry refers
+    /// to, such as the blobs of an upload or delete that was interrupted.
+    /// Orphans modified within the grace period are left alone, as they may
+    /// belong to an upload still in progress.
+    ///
+    /// The blobs of other libraries cannot be told apart from orphans, so
+    /// beyond replaying the journal of the unlocked library nothing is
+    /// collected while a key slot of another library exists. A library
+    /// without slots can never be opened again, and its blobs are garbage as
+    /// well.
+    pub async fn collect_garbage(&self) -> Result<GcReport, VaultError> {
+        let keys = self.with_data(|data| Ok(data.keys.clone()))?;
+        self.replay_journal(&keys)?;
+        let mut report = GcReport::default();
+        let master = keys.master.expose_secret();
+        if self
+            .db
+            .get_key_slots()?
+            .iter()
+            .any(|slot| Self::slot_kind(master, slot).is_none())
+        {
+            report.skipped = true;
+            return Ok(report);
+        }
+
+        let (entries, corrupt) = self.db.check_entries(keys.entries.expose_secret())?;
+        if corrupt > 0 {
+            // Their blobs would look like orphans
+            return Err(VaultError::Corruption(format!(
+                "{} entries do not decode",
+                corrupt
+            )));
+        }
+
+        let cutoff = SystemTime::now()
+            .checked_sub(self.config.gc_grace)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        for orphan in Self::find_orphans(&entries).await? {
+            // A rotation renames blobs, which entries read before it do not know
+            if self.is_busy() {
+                return Err(VaultError::Busy("Re-encryption in progress".into()));
+            }
+            let metadata = match fs::metadata(&orphan).await {
+                Ok(metadata) => metadata,
+                // Deleted in the meantime
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if metadata.modified()? > cutoff {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                let mut size = 0;
+                let mut files = fs::read_dir(&orphan).await?;
+                while let Some(file) = files.next_entry().await? {
+                    size += file.metadata().await?.len();
+                }
+                fs::remove_dir_all(&orphan).await?;
+                report.reclaimed += size;
+            } else {
+                fs::remove_file(&orphan).await?;
+                report.reclaimed += metadata.len();
+            }
+            report.removed += 1;
+        }
+        Ok(report)
+    }
+
+    /// Starts the task that collects garbage after every unlock, and again
+    /// each `gc_interval` the vault stays unlocked. Does nothing when no
+    /// interval is configured.
+    pub fn spawn_gc(&self) {
+        let Some(period) = self.config.gc_interval else {
+            return;
+        };
+
+        let vault = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            // Unlock generation and time of the last collection
+            let mut last: Option<(u64, Instant)> = None;
+            loop {
+                interval.tick().await;
+                let generation = vault.unlock_generation();
+                let due = last.is_none_or(|(g, at)| g != generation || at.elapsed() >= period);
+                if !due || !vault.is_unlocked() {
+                    continue;
+                }
+
+                last = Some((generation, Instant::now()));
+                match vault.collect_garbage().await {
+                    Ok(report) if report.removed > 0 => println!(
+                        "Garbage collection removed {} orphans, reclaiming {} bytes",
+                        report.removed, report.reclaimed
+                    ),
+                    Ok(_) => {}
+                    // Tried again once the rotation is done
+                    Err(VaultError::Busy(_)) => last = None,
+                    Err(e) => eprintln!("Garbage collection failed: {}", e),
+                }
+            }
+        });
+    }
+}
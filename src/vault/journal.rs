@@ -0,0 +1,100 @@
+use super::{
+    Vault, blob,
+    crypto::{self, VaultKeys},
+    error::VaultError,
+    types::{ImageVariant, JournalRecord},
+};
+use secrecy::ExposeSecret;
+use std::{collections::HashSet, io::ErrorKind, sync::MutexGuard};
+use uuid::Uuid;
+
+impl Vault {
+    /// Journals that `blobs` are about to be written for `entry`, or removed
+    /// from it. Returns the record's row, to pass to `end_blobs` once the
+    /// entry and storage agree again.
+    pub(super) fn begin_blobs(
+        &self,
+        keys: &VaultKeys,
+        entry: Uuid,
+        blobs: Vec<(Uuid, ImageVariant, Option<String>)>,
+    ) -> Result<Vec<u8>, VaultError> {
+        let row = Uuid::new_v4().as_bytes().to_vec();
+        self.running_operations()?.insert(row.clone());
+        let record = JournalRecord { entry, blobs };
+        if let Err(e) = self.db.put_journal_record(keys, &row, &record) {
+            self.running_operations()?.remove(&row);
+            return Err(e);
+        }
+        Ok(row)
+    }
+
+    pub(super) fn end_blobs(&self, row: &[u8]) -> Result<(), VaultError> {
+        self.db.remove_journal_record(row)?;
+        self.running_operations()?.remove(row);
+        Ok(())
+    }
+
+    /// Undoes the interrupted writes and finishes the interrupted removals of
+    /// the library `keys` opens: of the blobs each journal record lists, those
+    /// its entry does not refer to are removed. Operations still running are
+    /// left alone.
+    pub(super) fn replay_journal(&self, keys: &VaultKeys) -> Result<(), VaultError> {
+        let running = self.running_operations()?.clone();
+        for (row, record) in self.db.get_journal_records(keys)? {
+            if running.contains(&row) {
+                continue;
+            }
+
+            let kept = match self.db.get_entry(keys, record.entry) {
+                Ok(entry) => Self::entry_blobs(&entry),
+                Err(VaultError::NotFound(_)) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            for (id, variant, name) in record.blobs {
+                if kept.contains(&(id, variant, name.clone())) {
+                    continue;
+                }
+                let path = Self::blob_path(id, variant, name.as_deref());
+                for path in [blob::temp_path(&path), path] {
+                    if let Err(e) = std::fs::remove_file(&path)
+                        && e.kind() != ErrorKind::NotFound
+                    {
+                        return Err(e.into());
+                    }
+                }
+                if name.is_none() {
+                    // Best effort, as in remove_blobs
+                    let _ = std::fs::remove_dir(Self::legacy_dir(id));
+                }
+            }
+            self.db.remove_journal_record(&row)?;
+        }
+        Ok(())
+    }
+
+    /// The blobs `write_variants` creates for `variants` of image `id`.
+    pub(super) fn new_blobs(
+        keys: &VaultKeys,
+        id: Uuid,
+        variants: &[ImageVariant],
+    ) -> Result<Vec<(Uuid, ImageVariant, Option<String>)>, VaultError> {
+        variants
+            .iter()
+            .map(|variant| {
+                let name = crypto::blob_name(
+                    keys.index.expose_secret(),
+                    id.as_bytes(),
+                    variant.filename(),
+                )?;
+                Ok((id, *variant, Some(name)))
+            })
+            .collect()
+    }
+
+    /// Rows of the journal records whose operations are still running.
+    fn running_operations(&self) -> Result<MutexGuard<'_, HashSet<Vec<u8>>>, VaultError> {
+        self.journal
+            .lock()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))
+    }
+}
@@ -0,0 +1,194 @@
+use super::{
+    Vault,
+    backup::Snapshot,
+    blob,
+    db::Database,
+    error::VaultError,
+    types::{MirrorReport, MirrorState},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Blobs removed from the vault and when that was first seen, as JSON
+const MIRROR_STATE: &str = "mirror.json";
+/// One line per blob copied and not verified yet, so an interrupted run
+/// still verifies what it copied
+const MIRROR_PROGRESS: &str = "mirror.progress";
+/// FAT keeps modification times to 2 seconds only
+const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+impl Vault {
+    /// Brings the mirror in `target` up to date with `snapshot`: copies the
+    /// blobs that are new or changed since the last run, and the database
+    /// records that differ. The mirror has the layout of the vault
+    /// directory, so it can take the place of it as is.
+    ///
+    /// Blobs gone from the vault are kept for `mirror_retention`, and the
+    /// copied ones read back once the database is up to date. A run that was
+    /// interrupted leaves a consistent mirror behind, and the next one
+    /// picks up where it stopped.
+    pub async fn mirror(
+        &self,
+        snapshot: Snapshot,
+        target: PathBuf,
+    ) -> Result<MirrorReport, VaultError> {
+        let vault = self.clone();
+        tokio::task::spawn_blocking(move || vault.write_mirror(&snapshot, &target))
+            .await
+            .map_err(|e| VaultError::Io(io::Error::other(e)))?
+    }
+
+    /// The mirror the API keeps up to date, if one is configured.
+    pub fn mirror_dir(&self) -> Option<PathBuf> {
+        self.config.mirror_dir.clone()
+    }
+
+    fn write_mirror(&self, snapshot: &Snapshot, target: &Path) -> Result<MirrorReport, VaultError> {
+        let storage = target.join("storage");
+        fs::create_dir_all(&storage)?;
+        let mut report = MirrorReport::default();
+        let progress_path = target.join(MIRROR_PROGRESS);
+        let mut unverified = Self::read_progress(&progress_path)?;
+        let mut progress = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&progress_path)?;
+
+        // 1. Blobs, before the records that refer to them
+        let mut present = HashSet::new();
+        for blob in Self::storage_files(&snapshot.dir)? {
+            let relative = blob.strip_prefix(&snapshot.dir).map_err(io::Error::other)?;
+            let mirrored = storage.join(relative);
+            let name = relative.to_string_lossy().into_owned();
+            present.insert(name.clone());
+            let mut source = File::open(&blob)?;
+            let metadata = source.metadata()?;
+            if Self::is_mirrored(&mirrored, &metadata)? {
+                continue;
+            }
+
+            if let Some(dir) = mirrored.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let temp = blob::temp_path(&mirrored.to_string_lossy());
+            let mut file = File::create(&temp)?;
+            let copied = Self::copy_hashed(name, &mut source, &mut file)?;
+            file.sync_all()?;
+            fs::rename(&temp, &mirrored)?;
+
+            // Only once it is in the progress file does the copy count as
+            // done, which the modification time of the source marks
+            writeln!(progress, "{} {}", copied.sha256, copied.path)?;
+            progress.sync_data()?;
+            File::options()
+                .write(true)
+                .open(&mirrored)?
+                .set_modified(metadata.modified()?)?;
+
+            report.copied += 1;
+            report.bytes += copied.size;
+            unverified.insert(copied.path, copied.sha256);
+        }
+
+        // 2. Records
+        let database = target.join("db");
+        report.records = Database::mirror_trees(&database.to_string_lossy(), &snapshot.trees)?;
+
+        // 3. Verification of what was copied, by this run or an interrupted one
+        for (name, sha256) in unverified {
+            let path = storage.join(&name);
+            let intact = match File::open(&path) {
+                Ok(mut file) => {
+                    Self::copy_hashed(name.clone(), &mut file, &mut io::sink())?.sha256 == sha256
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+            };
+            if intact {
+                report.verified += 1;
+            } else {
+                // Copied again by the next run
+                if let Err(e) = fs::remove_file(&path)
+                    && e.kind() != ErrorKind::NotFound
+                {
+                    return Err(e.into());
+                }
+                report.corrupt.push(name);
+            }
+        }
+        drop(progress);
+        fs::remove_file(&progress_path)?;
+
+        // 4. Blobs gone from the vault, now that no record refers to them
+        let state_path = target.join(MIRROR_STATE);
+        let mut state: MirrorState = match fs::read(&state_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| VaultError::Corruption(format!("Invalid mirror state: {}", e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => MirrorState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let retention = self.config.mirror_retention.as_secs();
+        let mut removed = BTreeMap::new();
+        for mirrored in Self::storage_files(&storage)? {
+            let relative = mirrored.strip_prefix(&storage).map_err(io::Error::other)?;
+            let name = relative.to_string_lossy().into_owned();
+            if present.contains(&name) {
+                continue;
+            }
+            let since = state.removed.get(&name).copied().unwrap_or(now);
+            if now.saturating_sub(since) < retention {
+                removed.insert(name, since);
+                report.retained += 1;
+                continue;
+            }
+            fs::remove_file(&mirrored)?;
+            if let Some(dir) = mirrored.parent()
+                && dir != storage
+            {
+                // Best effort, only empty once its last blob is gone
+                let _ = fs::remove_dir(dir);
+            }
+            report.removed += 1;
+        }
+        state.removed = removed;
+        let bytes = serde_json::to_vec_pretty(&state).map_err(io::Error::other)?;
+        blob::replace_file(&state_path.to_string_lossy(), &bytes)?;
+        Ok(report)
+    }
+
+    /// Whether `mirrored` is a complete copy of a blob with `source` metadata.
+    fn is_mirrored(mirrored: &Path, source: &fs::Metadata) -> Result<bool, VaultError> {
+        let metadata = match fs::metadata(mirrored) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let (source_time, mirrored_time) = (source.modified()?, metadata.modified()?);
+        let difference = source_time
+            .duration_since(mirrored_time)
+            .or_else(|_| mirrored_time.duration_since(source_time))
+            .unwrap_or_default();
+        Ok(metadata.len() == source.len() && difference < MTIME_TOLERANCE)
+    }
+
+    /// The blobs an interrupted run copied, with their checksums. A line cut
+    /// short by the interruption belongs to a copy not yet counted as done.
+    fn read_progress(path: &Path) -> Result<BTreeMap<String, String>, VaultError> {
+        let progress = match fs::read_to_string(path) {
+            Ok(progress) => progress,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(progress
+            .split_inclusive('\n')
+            .filter_map(|line| line.strip_suffix('\n')?.split_once(' '))
+            .map(|(sha256, name)| (name.to_string(), sha256.to_string()))
+            .collect())
+    }
+}
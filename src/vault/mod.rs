@@ -1,90 +1,234 @@
+mod audit;
+mod autolock;
+mod backup;
+mod blob;
 mod crypto;
 mod db;
+mod duress;
 mod error;
+mod fsck;
+mod gc;
+mod journal;
+mod mirror;
+mod rotation;
+mod secret;
+mod throttle;
+mod totp;
+mod trash;
 mod types;
 
+pub use backup::Snapshot;
+pub use blob::BlobReader;
+pub use crypto::BlobPadding;
 pub use error::VaultError;
-pub use types::{ImageEntry, ImageVariant, LinkedImage, mime_to_ext};
+pub use types::{
+    AuditEvent, Credentials, FsckReport, ImageEntry, ImageVariant, KdfParams, KeySlotSummary,
+    LinkedImage, mime_to_ext,
+};
 
+use crate::config::VaultConfig;
+use crate::vault::crypto::VaultKeys;
 use crate::vault::db::Database;
-use crate::vault::types::VaultMetadata;
+use crate::vault::secret::SecretKey;
+use crate::vault::types::{
+    KeySlot, RotationState, SlotInfo, SlotInfoV1, SlotKind, StoredBlob, VaultMetadata,
+};
 use rayon::prelude::*;
-use secrecy::{ExposeSecret, SecretBox};
+use secrecy::ExposeSecret;
 use std::{
     collections::{HashMap, HashSet},
     io::{Cursor, Write},
-    sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::fs;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 use zip::write::{SimpleFileOptions, ZipWriter};
 
 const DATABASE_DIR: &str = "vault/db";
 const DATA_DIR: &str = "vault/storage";
-const SALT_PATH: &str = "vault/.salt";
+// Only vaults created before key slots have this file; the v3 migration removes it
+const LEGACY_SALT_PATH: &str = "vault/.salt";
+const PRIMARY_SLOT_LABEL: &str = "Primary";
+const RECOVERY_SLOT_LABEL: &str = "Recovery key";
+// Upper bound on the headers a stored zip entry adds besides its data and name
+const ZIP_ENTRY_OVERHEAD: usize = 256;
 
 #[derive(Clone)]
 struct VaultData {
     // We keep the tag index in memory
     tag_index: HashMap<String, HashSet<Uuid>>,
-    encryption_key: SecretBox<[u8]>,
+    keys: VaultKeys,
+    unlocked_at: Instant,
 }
 
 #[derive(Clone)]
 pub struct Vault {
+    config: VaultConfig,
     metadata: VaultMetadata,
     // Database handle is thread-safe and can be held outside the lock
     db: Database,
     // Only mutable in-memory state needs the lock
     data: Arc<RwLock<Option<VaultData>>>,
+    // Set while a master key rotation holds the vault, cleared once it completes
+    rotating: Arc<AtomicBool>,
+    // Unlock generation of the library the rotation belongs to. Other
+    // libraries unlocked meanwhile are not held by it.
+    rotation_generation: Arc<AtomicU64>,
+    // Held while the rotation worker rewrites an entry and its blobs, so a
+    // snapshot never sees half of that
+    rotation_step: Arc<Mutex<()>>,
+    // Set while a rotation worker is running, so only one is ever spawned
+    rotation_worker: Arc<AtomicBool>,
+    // Last authenticated API request, for the idle auto-lock
+    last_activity: Arc<Mutex<Instant>>,
+    // Bumped whenever the keys in memory are dropped or replaced
+    generation: Arc<AtomicU64>,
+    // Held while a password, recovery key or code is checked
+    attempts: Arc<Mutex<()>>,
+    // Held while appending to an audit log
+    audit_lock: Arc<Mutex<()>>,
+    // Journal rows of the operations still running
+    journal: Arc<Mutex<HashSet<Vec<u8>>>>,
 }
 
 impl Vault {
-    pub fn new() -> Result<Self, VaultError> {
+    pub fn new(config: VaultConfig) -> Result<Self, VaultError> {
         let db = Database::open(DATABASE_DIR)?;
         let metadata = db.load_or_init_metadata()?;
         std::fs::create_dir_all(DATA_DIR)?;
+        // Left over by a backup or mirror that was interrupted
+        if let Err(e) = std::fs::remove_dir_all(backup::SNAPSHOT_DIR)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(e.into());
+        }
 
         Ok(Vault {
+            config,
             metadata,
             db,
             data: Arc::new(RwLock::new(None)),
+            rotating: Arc::new(AtomicBool::new(false)),
+            rotation_generation: Arc::new(AtomicU64::new(0)),
+            rotation_step: Arc::new(Mutex::new(())),
+            rotation_worker: Arc::new(AtomicBool::new(false)),
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            generation: Arc::new(AtomicU64::new(0)),
+            attempts: Arc::new(Mutex::new(())),
+            audit_lock: Arc::new(Mutex::new(())),
+            journal: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
     // --- Core Lifecycle ---
 
-    pub fn unlock(&self, password: &str) -> Result<(), VaultError> {
-        let salt = self
-            .metadata
-            .vault_salt
-            .as_ref()
-            .ok_or(VaultError::EncryptionError)?;
-        let check_val = self
-            .metadata
-            .master_key_check
-            .as_ref()
-            .ok_or(VaultError::EncryptionError)?;
+    /// Unlocks the library `credentials` open. If the vault is already unlocked
+    /// with another library, that one is locked and this one takes its place,
+    /// so a duress password never reaches the library it hides.
+    pub fn unlock(&self, credentials: Credentials) -> Result<(), VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let failures = self.db.get_failed_attempts()?;
+        let (slot, master_key) = self.throttled(false, || {
+            self.open_key_slot(credentials.password, keyfile.as_ref(), SlotKind::Password)
+        })?;
+        let key = master_key.expose_secret();
+        if Self::slot_kind(key, &slot) == Some(SlotKind::Duress) {
+            self.remove_foreign_key_slots(key)?;
+        }
+        self.upgrade_key_slot(slot, credentials.password, keyfile.as_ref(), key);
 
-        let wrapping_key = crypto::derive_key(password, salt)?;
+        if !self
+            .unlocked_keys()
+            .is_ok_and(|unlocked| unlocked.master.expose_secret() == key)
+        {
+            self.unlock_with_key(master_key)?;
+        }
 
-        // Decrypt master key
-        let master_key_bytes = crypto::decrypt(wrapping_key.expose_secret(), check_val, &[])?;
-        if master_key_bytes.len() != 32 {
-            return Err(VaultError::EncryptionError);
+        if failures.count > 0 {
+            let event = AuditEvent::FailedAttempts {
+                count: failures.count,
+                last_at: failures.last_failure,
+            };
+            self.audit(event, None);
         }
-        let master_key = SecretBox::from(master_key_bytes);
+        Ok(())
+    }
 
-        // Run DB migration if needed (v1 → v2)
+    fn unlock_with_key(&self, master_key: SecretKey) -> Result<(), VaultError> {
+        // Run DB migrations if needed (v1 → v2 → v3 → v4 → v5 → v6 → v7)
         let db_version = self.db.get_version()?;
         if db_version < 2 {
             self.db.migrate_v1_to_v2(master_key.expose_secret())?;
         }
+        if db_version < 3 {
+            let info = Self::seal_slot_info(
+                master_key.expose_secret(),
+                0,
+                PRIMARY_SLOT_LABEL,
+                SlotKind::Password,
+            )?;
+            self.db.migrate_v2_to_v3(info)?;
+            // The salt now lives in the key slot table
+            if let Err(e) = std::fs::remove_file(LEGACY_SALT_PATH)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                return Err(e.into());
+            }
+        }
+
+        // Moving to subkeys (v4), padding blobs (v5), renaming blobs (v6) and
+        // blinding entry keys (v7) all rewrite everything, which the rotation
+        // worker does in the background. They wait for an interrupted rotation
+        // to finish, as its checkpoint must not be replaced.
+        if db_version < 7 && self.db.get_rotation()?.is_none() {
+            let state = RotationState {
+                wrapped_old_key: None,
+                old_derivation: self.db.get_key_derivation()?,
+                cursor: None,
+                done: 0,
+                total: self.db.count_entries(),
+                failed: 0,
+                started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            };
+            if db_version < 4 {
+                self.db.migrate_v3_to_v4(&state)?;
+            }
+            if db_version < 5 {
+                self.db.migrate_v4_to_v5(&state)?;
+            }
+            if db_version < 6 {
+                self.db.migrate_v5_to_v6(&state)?;
+            }
+            self.db.migrate_v6_to_v7(&state)?;
+        }
+
+        let keys = VaultKeys::derive(master_key.expose_secret(), self.db.get_key_derivation()?)?;
+        if !keys.master.is_locked() {
+            eprintln!("Could not lock key memory, keys may be swapped to disk");
+        }
 
-        // Load all entries to build the tag index
-        let all_entries = self.db.get_all_entries(master_key.expose_secret())?;
-        let tag_index = Self::build_tag_index(&all_entries);
+        // Entries are under mixed keys until an interrupted rotation finishes,
+        // which rebuilds the index itself. A rotation of another library
+        // leaves the entries of this one alone.
+        let resume = match self.db.get_rotation()? {
+            Some(state) => self.rotation_old_keys(&state, &keys)?,
+            None => None,
+        };
+        let tag_index = if resume.is_some() {
+            HashMap::new()
+        } else {
+            if let Err(e) = self.replay_journal(&keys) {
+                eprintln!("Failed to replay the journal: {}", e);
+            }
+            // Load all entries to build the tag index
+            let all_entries = self.db.get_all_entries(keys.entries.expose_secret())?;
+            Self::build_tag_index(&all_entries)
+        };
 
         let mut lock = self
             .data
@@ -92,35 +236,36 @@ impl Vault {
             .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
         *lock = Some(VaultData {
             tag_index,
-            encryption_key: master_key,
+            keys: keys.clone(),
+            unlocked_at: Instant::now(),
         });
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // Busy before any request gets to the mixed entries
+        if resume.is_some() {
+            self.hold_for_rotation();
+        }
+        drop(lock);
 
-        Ok(())
-    }
+        if let Some(old_keys) = resume {
+            self.spawn_rotation(old_keys, keys);
+        }
 
-    pub fn verify_password(&self, password: &str) -> Result<(), VaultError> {
-        let salt = self
-            .metadata
-            .vault_salt
-            .as_ref()
-            .ok_or(VaultError::EncryptionError)?;
-        let check_val = self
-            .metadata
-            .master_key_check
-            .as_ref()
-            .ok_or(VaultError::EncryptionError)?;
-
-        let wrapping_key = crypto::derive_key(password, salt)?;
-        crypto::decrypt(wrapping_key.expose_secret(), check_val, &[])?;
         Ok(())
     }
 
     pub fn lock(&self) {
         if let Ok(mut lock) = self.data.write() {
             *lock = None;
+            self.generation.fetch_add(1, Ordering::SeqCst);
         }
     }
 
+    /// Changes every time the vault is locked or unlocked, so sessions can
+    /// tell whether they were authenticated against the current unlock.
+    pub fn unlock_generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
     pub fn is_unlocked(&self) -> bool {
         self.data.read().map(|d| d.is_some()).unwrap_or(false)
     }
@@ -133,36 +278,278 @@ impl Vault {
 
     // --- Setup ---
 
-    pub async fn setup(&mut self, master_password: &str) -> Result<(), VaultError> {
-        if !self.needs_setup() {
+    /// Creates the master key, its password slot and a recovery slot. Returns the
+    /// recovery key, which is never stored and cannot be shown again. With a
+    /// keyfile, the password slot needs it as well; the recovery key never does.
+    pub async fn setup(&mut self, credentials: Credentials<'_>) -> Result<String, VaultError> {
+        if !self.db.get_key_slots()?.is_empty() {
             return Err(VaultError::Corruption("Vault already set up".into()));
         }
 
-        let master_key = SecretBox::from(rand::random::<[u8; 32]>().to_vec());
-        let salt = rand::random::<[u8; 16]>();
+        // Calibrating runs the KDF for about `kdf_target` per trial, and each
+        // slot costs as much again, so none of it runs on the async runtime
+        let config = self.config.clone();
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let password = Zeroizing::new(credentials.password.to_string());
+        let (kdf, master_key, password_slot, recovery_key, recovery_slot) =
+            tokio::task::spawn_blocking(move || -> Result<_, VaultError> {
+                // Calibrate the KDF for this host before wrapping anything with it
+                let kdf = crypto::calibrate_kdf(
+                    config.kdf_target,
+                    &config.kdf_min,
+                    config.kdf_max_memory,
+                    config.kdf_max_iterations,
+                )?;
+
+                let master_key = SecretKey::random();
+                let password_slot = Self::wrap_key_slot(
+                    0,
+                    &password,
+                    keyfile.as_ref(),
+                    master_key.expose_secret(),
+                    &kdf,
+                    PRIMARY_SLOT_LABEL,
+                    SlotKind::Password,
+                )?;
+
+                let recovery_key = crypto::generate_recovery_key();
+                let recovery_slot = Self::wrap_key_slot(
+                    1,
+                    &Self::recovery_secret(&recovery_key)?,
+                    None,
+                    master_key.expose_secret(),
+                    &kdf,
+                    RECOVERY_SLOT_LABEL,
+                    SlotKind::Recovery,
+                )?;
+                Ok((kdf, master_key, password_slot, recovery_key, recovery_slot))
+            })
+            .await
+            .map_err(|e| VaultError::Io(std::io::Error::other(e)))??;
+        self.db.save_kdf_params(&kdf)?;
+        self.metadata.kdf = kdf;
+
+        // DB Operations
+        self.db.put_key_slot(&password_slot)?;
+        self.db.put_key_slot(&recovery_slot)?;
+
+        let keys = VaultKeys::derive(master_key.expose_secret(), self.db.get_key_derivation()?)?;
+        self.record_audit(&keys, AuditEvent::Setup, None);
+
+        Ok(recovery_key)
+    }
+
+    pub fn needs_setup(&self) -> bool {
+        self.db
+            .get_key_slots()
+            .map(|slots| slots.is_empty())
+            .unwrap_or(false)
+    }
 
-        let wrapping_key = crypto::derive_key(master_password, &salt)?;
-        let key_check = crypto::encrypt(
-            wrapping_key.expose_secret(),
+    // --- Key Slot Operations ---
+
+    /// Re-wraps the master key held by the slot that `old_credentials` open
+    /// under a new password. Entries and blobs stay encrypted under the same
+    /// master key, so nothing else is rewritten. A keyfile the slot requires
+    /// stays required.
+    pub fn change_password(
+        &self,
+        old_credentials: Credentials,
+        new_password: &str,
+    ) -> Result<(), VaultError> {
+        let keyfile = self.keyfile_secret(old_credentials.keyfile)?;
+        let (slot, master_key) =
+            self.open_unlocked_key_slot(old_credentials.password, keyfile.as_ref())?;
+
+        // Same slot id and label, so the swap is a single atomic insert
+        let keyfile = keyfile.filter(|_| slot.keyfile);
+        let slot = Self::rewrap_key_slot(
+            slot,
+            new_password,
+            keyfile.as_ref(),
             master_key.expose_secret(),
-            &[],
+            &self.slot_kdf(),
         )?;
+        self.db.put_key_slot(&slot)
+    }
 
-        // DB Operations
-        self.db.save_salt_and_check(&salt, &key_check)?;
+    /// Makes the slot `credentials` open require `new_keyfile`, or no keyfile
+    /// at all if it is `None`.
+    pub fn change_keyfile(
+        &self,
+        credentials: Credentials,
+        new_keyfile: Option<&[u8]>,
+    ) -> Result<(), VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (slot, master_key) =
+            self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+
+        let new_keyfile = new_keyfile.map(crypto::keyfile_secret);
+        let slot = Self::rewrap_key_slot(
+            slot,
+            credentials.password,
+            new_keyfile.as_ref(),
+            master_key.expose_secret(),
+            &self.slot_kdf(),
+        )?;
+        self.db.put_key_slot(&slot)
+    }
 
-        // File System Operations
-        fs::write(SALT_PATH, &salt).await?;
+    /// Unlocks with the recovery key and immediately replaces a password. The
+    /// password slot `slot_id` is re-wrapped (defaulting to the oldest password
+    /// slot); if no password slot is left, a new primary one is created. The
+    /// slot requires the keyfile in `new_credentials`, if any, so a lost
+    /// keyfile is recovered from like a lost password.
+    pub fn recover(
+        &self,
+        recovery_key: &str,
+        new_credentials: Credentials,
+        slot_id: Option<u32>,
+    ) -> Result<(), VaultError> {
+        let secret = Self::recovery_secret(recovery_key)?;
+        let (recovery_slot, master_key) = self.throttled(true, || {
+            self.open_key_slot(&secret, None, SlotKind::Recovery)
+        })?;
+        let key = master_key.expose_secret();
+        self.upgrade_key_slot(recovery_slot, &secret, None, key);
+        let kdf = self.slot_kdf();
+        let new_password = new_credentials.password;
+        let keyfile = self.keyfile_secret(new_credentials.keyfile)?;
+        let keyfile = keyfile.as_ref();
+
+        let password_slots: Vec<KeySlot> = self
+            .db
+            .get_key_slots()?
+            .into_iter()
+            .filter(|slot| {
+                Self::slot_kind(key, slot).is_some_and(|kind| kind.opens_as(SlotKind::Password))
+            })
+            .collect();
+
+        let slot = match slot_id {
+            Some(id) => {
+                let slot = password_slots
+                    .into_iter()
+                    .find(|s| s.id == id)
+                    .ok_or_else(|| VaultError::NotFound(format!("Key slot {}", id)))?;
+                Self::rewrap_key_slot(slot, new_password, keyfile, key, &kdf)?
+            }
+            None => match password_slots.into_iter().next() {
+                Some(slot) => Self::rewrap_key_slot(slot, new_password, keyfile, key, &kdf)?,
+                None => Self::wrap_key_slot(
+                    self.db.next_key_slot_id()?,
+                    new_password,
+                    keyfile,
+                    key,
+                    &kdf,
+                    PRIMARY_SLOT_LABEL,
+                    SlotKind::Password,
+                )?,
+            },
+        };
+        self.db.put_key_slot(&slot)?;
 
-        // Update Local State
-        self.metadata.vault_salt = Some(salt);
-        self.metadata.master_key_check = Some(key_check);
+        self.unlock_with_key(master_key)
+    }
 
-        Ok(())
+    /// Replaces the recovery slot with a freshly generated recovery key. The old
+    /// key stops working as soon as the new slot is written.
+    pub fn regenerate_recovery_key(&self, credentials: Credentials) -> Result<String, VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (_, master_key) =
+            self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+        let key = master_key.expose_secret();
+
+        let recovery_key = crypto::generate_recovery_key();
+        let secret = Self::recovery_secret(&recovery_key)?;
+        let kdf = self.slot_kdf();
+        let existing = self
+            .db
+            .get_key_slots()?
+            .into_iter()
+            .find(|slot| Self::slot_kind(key, slot) == Some(SlotKind::Recovery));
+
+        let slot = match existing {
+            // Reusing the slot id makes the swap a single atomic insert
+            Some(slot) => Self::rewrap_key_slot(slot, &secret, None, key, &kdf)?,
+            None => Self::wrap_key_slot(
+                self.db.next_key_slot_id()?,
+                &secret,
+                None,
+                key,
+                &kdf,
+                RECOVERY_SLOT_LABEL,
+                SlotKind::Recovery,
+            )?,
+        };
+        self.db.put_key_slot(&slot)?;
+
+        Ok(recovery_key)
     }
 
-    pub fn needs_setup(&self) -> bool {
-        self.metadata.vault_salt.is_none() || self.metadata.master_key_check.is_none()
+    pub fn list_key_slots(&self) -> Result<Vec<KeySlotSummary>, VaultError> {
+        self.with_data(|data| {
+            let key = data.keys.master.expose_secret();
+            let slots = self.db.get_key_slots()?;
+            Ok(slots
+                .iter()
+                .filter_map(|slot| {
+                    let info = Self::open_slot_info(key, slot).ok()?;
+                    Some(Self::slot_summary(slot.id, info))
+                })
+                .collect())
+        })
+    }
+
+    /// Adds a new slot for `new_password`. The caller must prove knowledge of an
+    /// existing password, mirroring how LUKS guards `luksAddKey`. The new slot
+    /// requires the same keyfile as the one it was added with, if any.
+    pub fn add_key_slot(
+        &self,
+        credentials: Credentials,
+        new_password: &str,
+        label: &str,
+    ) -> Result<KeySlotSummary, VaultError> {
+        let label = Self::normalize_slot_label(label)?;
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (slot, master_key) =
+            self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+
+        let id = self.db.next_key_slot_id()?;
+        let slot = Self::wrap_key_slot(
+            id,
+            new_password,
+            keyfile.filter(|_| slot.keyfile).as_ref(),
+            master_key.expose_secret(),
+            &self.slot_kdf(),
+            &label,
+            SlotKind::Password,
+        )?;
+        let info = Self::open_slot_info(master_key.expose_secret(), &slot)?;
+        self.db.put_key_slot(&slot)?;
+
+        Ok(Self::slot_summary(id, info))
+    }
+
+    /// Removes a key slot. The last remaining slot can never be removed, since
+    /// that would make the master key unrecoverable. Slots of other libraries
+    /// are treated as missing.
+    pub fn remove_key_slot(&self, credentials: Credentials, id: u32) -> Result<(), VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (_, master_key) =
+            self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+
+        let slots = self.own_key_slots(master_key.expose_secret())?;
+        if !slots.iter().any(|s| s.id == id) {
+            return Err(VaultError::NotFound(format!("Key slot {}", id)));
+        }
+        if slots.len() <= 1 {
+            return Err(VaultError::Corruption(
+                "Cannot remove the last key slot".into(),
+            ));
+        }
+
+        self.db.remove_key_slot(id)
     }
 
     // --- Image Operations ---
@@ -174,9 +561,9 @@ impl Vault {
         variants: Vec<(ImageVariant, Vec<u8>)>,
     ) -> Result<ImageEntry, VaultError> {
         // 1. Prepare data (synchronous part)
-        let (id, key, entry) = self.with_data(|data| {
+        let (id, keys, mut entry) = self.with_data(|data| {
             let id = Uuid::new_v4();
-            let key = data.encryption_key.expose_secret().to_vec();
+            let keys = data.keys.clone();
 
             let entry = ImageEntry {
                 id,
@@ -186,24 +573,20 @@ impl Vault {
                 variants: variants.iter().map(|(v, _)| *v).collect(),
                 tags: Vec::new(),
                 linked_images: Vec::new(),
+                blobs: Vec::new(),
+                deleted_at: None,
             };
-            Ok((id, key, entry))
+            Ok((id, keys, entry))
         })?;
 
         // 2. Perform IO (File system + DB)
         // We do this outside the read lock so we don't block other readers during IO
-        let dir_path = format!("{}/{}", DATA_DIR, id);
-        fs::create_dir_all(&dir_path).await?;
-
-        for (variant, bytes) in &variants {
-            let aad = Self::make_aad(id, variant.filename());
-            let encrypted = crypto::encrypt(&key, bytes, &aad)?;
-            let path = format!("{}/{}.enc", dir_path, variant.filename());
-            fs::write(&path, encrypted).await?;
-        }
+        let row = self.begin_blobs(&keys, id, Self::new_blobs(&keys, id, &entry.variants)?)?;
+        entry.blobs = self.write_variants(&keys, id, &variants).await?;
 
         // Save metadata to DB
-        self.db.insert_entry(&key, &entry)?;
+        self.db.insert_entry(&keys, &entry)?;
+        self.end_blobs(&row)?;
 
         Ok(entry)
     }
@@ -212,66 +595,26 @@ impl Vault {
         &self,
         id: Uuid,
         variant: ImageVariant,
-    ) -> Result<(Vec<u8>, String), VaultError> {
+    ) -> Result<(BlobReader, String), VaultError> {
         // 1. Get Key and Metadata
-        let (key_vec, mime) = self.with_data(|data| {
-            let key = data.encryption_key.expose_secret();
-            let entry = self.db.get_entry(key, id)?;
+        let (blob_key, mime, path) = self.with_data(|data| {
+            let entry = self.db.get_entry(&data.keys, id)?;
 
             if !entry.variants.contains(&variant) {
                 return Err(VaultError::NotFound(format!("Variant missing: {}", id)));
             }
-            Ok((key.to_vec(), entry.original_mime))
+            Ok((
+                data.keys.blobs.clone(),
+                entry.original_mime,
+                Self::blob_path(id, variant, StoredBlob::find(&entry.blobs, variant)),
+            ))
         })?;
 
         // 2. Read File
-        let path = format!("{}/{}/{}.enc", DATA_DIR, id, variant.filename());
-        let encrypted_data = fs::read(&path).await?;
-
         let aad = Self::make_aad(id, variant.filename());
-        let decrypted = crypto::decrypt(&key_vec, &encrypted_data, &aad)?;
-
-        Ok((decrypted, variant.mime(&mime)))
-    }
-
-    pub async fn delete_image(&self, id: Uuid) -> Result<(), VaultError> {
-        // 1. Update Index and DB, collect linked image IDs
-        let linked_ids = self.with_data_mut(|data| {
-            let mut sub_ids = Vec::new();
-            if let Ok(entry) = self.db.get_entry(data.encryption_key.expose_secret(), id) {
-                sub_ids = entry.linked_images.iter().map(|l| l.id).collect();
-                for tag in entry.tags {
-                    if let Some(set) = data.tag_index.get_mut(&tag) {
-                        set.remove(&id);
-                        if set.is_empty() {
-                            data.tag_index.remove(&tag);
-                        }
-                    }
-                }
-            }
-            self.db.remove_entry(id)?;
-            Ok(sub_ids)
-        })?;
-
-        // 2. Delete cover files
-        let dir = format!("{}/{}", DATA_DIR, id);
-        if let Err(e) = fs::remove_dir_all(&dir).await {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                return Err(e.into());
-            }
-        }
-
-        // 3. Delete linked image files
-        for sub_id in linked_ids {
-            let sub_dir = format!("{}/{}", DATA_DIR, sub_id);
-            if let Err(e) = fs::remove_dir_all(&sub_dir).await {
-                if e.kind() != std::io::ErrorKind::NotFound {
-                    return Err(e.into());
-                }
-            }
-        }
+        let reader = BlobReader::open(&path, blob_key.expose_secret(), &aad).await?;
 
-        Ok(())
+        Ok((reader, variant.mime(&mime)))
     }
 
     // --- Tag Operations ---
@@ -280,12 +623,12 @@ impl Vault {
         let tag = ImageEntry::normalize_tag(tag)?;
 
         self.with_data_mut(|data| {
-            let key = data.encryption_key.expose_secret();
-            let mut entry = self.db.get_entry(key, id)?;
+            let keys = &data.keys;
+            let mut entry = self.live_entry(keys, id)?;
 
             if !entry.tags.contains(&tag) {
                 entry.tags.push(tag.clone());
-                self.db.insert_entry(key, &entry)?;
+                self.db.insert_entry(keys, &entry)?;
                 data.tag_index.entry(tag).or_default().insert(id);
             }
             Ok(entry)
@@ -296,12 +639,12 @@ impl Vault {
         let tag = ImageEntry::normalize_tag(tag)?;
 
         self.with_data_mut(|data| {
-            let key = data.encryption_key.expose_secret();
-            let mut entry = self.db.get_entry(key, id)?;
+            let keys = &data.keys;
+            let mut entry = self.live_entry(keys, id)?;
 
             if let Some(pos) = entry.tags.iter().position(|t| t == &tag) {
                 entry.tags.remove(pos);
-                self.db.insert_entry(key, &entry)?;
+                self.db.insert_entry(keys, &entry)?;
 
                 if let Some(set) = data.tag_index.get_mut(&tag) {
                     set.remove(&id);
@@ -334,40 +677,45 @@ impl Vault {
                 return Ok(0);
             }
 
-            let key = data.encryption_key.expose_secret();
-            let mut count = 0;
+            let keys = &data.keys;
+            let mut renamed = Vec::new();
 
             for id in &image_ids {
                 // We use get_entry from DB
-                if let Ok(mut entry) = self.db.get_entry(key, *id) {
-                    if let Some(pos) = entry.tags.iter().position(|t| t == &old_tag) {
-                        entry.tags.remove(pos);
-                        if !entry.tags.contains(&new_tag) {
-                            entry.tags.push(new_tag.clone());
-                        }
-                        self.db.insert_entry(key, &entry)?;
-                        count += 1;
+                if let Ok(mut entry) = self.db.get_entry(keys, *id)
+                    && let Some(pos) = entry.tags.iter().position(|t| t == &old_tag)
+                {
+                    entry.tags.remove(pos);
+                    if !entry.tags.contains(&new_tag) {
+                        entry.tags.push(new_tag.clone());
                     }
+                    renamed.push(entry);
                 }
             }
+            // All at once, so a crash never leaves the tag half renamed
+            self.db.insert_entries(keys, &renamed)?;
 
             // Update Memory Index
             if let Some(old_ids) = data.tag_index.remove(&old_tag) {
                 data.tag_index.entry(new_tag).or_default().extend(old_ids);
             }
 
-            Ok(count)
+            Ok(renamed.len() as u32)
         })
     }
 
     // --- Search/List ---
 
     pub fn get_entry(&self, id: Uuid) -> Result<ImageEntry, VaultError> {
-        self.with_data(|data| self.db.get_entry(data.encryption_key.expose_secret(), id))
+        self.with_data(|data| self.db.get_entry(&data.keys, id))
     }
 
+    /// Every entry not in the trash, newest first.
     pub fn list_images(&self) -> Result<Vec<ImageEntry>, VaultError> {
-        self.with_data(|data| self.db.get_all_entries(data.encryption_key.expose_secret()))
+        let mut entries =
+            self.with_data(|data| self.db.get_all_entries(data.keys.entries.expose_secret()))?;
+        entries.retain(|entry| entry.deleted_at.is_none());
+        Ok(entries)
     }
 
     pub fn search_by_tags(
@@ -390,9 +738,7 @@ impl Vault {
             // Filter logic (same as before)
             let mut candidates = if include.is_empty() {
                 // If we have to search all, we fetch all from DB first
-                let all = self
-                    .db
-                    .get_all_entries(data.encryption_key.expose_secret())?;
+                let all = self.db.get_all_entries(data.keys.entries.expose_secret())?;
                 all.into_iter().map(|e| e.id).collect::<HashSet<_>>()
             } else {
                 sets.sort_by_key(|s| s.len());
@@ -418,13 +764,12 @@ impl Vault {
             }
 
             // Fetch specific entries
-            let key = data.encryption_key.expose_secret();
+            let keys = &data.keys;
             let candidate_list: Vec<Uuid> = candidates.into_iter().collect();
             let mut entries: Vec<ImageEntry> = candidate_list
                 .par_iter()
-                .filter_map(|&id| {
-                    self.db.get_entry(key, id).ok()
-                })
+                .filter_map(|&id| self.db.get_entry(keys, id).ok())
+                .filter(|entry| entry.deleted_at.is_none())
                 .collect();
 
             entries.par_sort_unstable_by(|a, b| b.created_at.cmp(&a.created_at));
@@ -452,31 +797,26 @@ impl Vault {
         size: u64,
         variants: Vec<(ImageVariant, Vec<u8>)>,
     ) -> Result<ImageEntry, VaultError> {
-        let (key, mut entry) = self.with_data(|data| {
-            let key = data.encryption_key.expose_secret().to_vec();
-            let entry = self.db.get_entry(data.encryption_key.expose_secret(), entry_id)?;
-            Ok((key, entry))
+        let (keys, mut entry) = self.with_data(|data| {
+            let entry = self.live_entry(&data.keys, entry_id)?;
+            Ok((data.keys.clone(), entry))
         })?;
 
         let sub_id = Uuid::new_v4();
-        let dir_path = format!("{}/{}", DATA_DIR, sub_id);
-        fs::create_dir_all(&dir_path).await?;
-
-        for (variant, bytes) in &variants {
-            let aad = Self::make_aad(sub_id, variant.filename());
-            let encrypted = crypto::encrypt(&key, bytes, &aad)?;
-            let path = format!("{}/{}.enc", dir_path, variant.filename());
-            fs::write(&path, encrypted).await?;
-        }
+        let kinds: Vec<ImageVariant> = variants.iter().map(|(v, _)| *v).collect();
+        let row = self.begin_blobs(&keys, entry_id, Self::new_blobs(&keys, sub_id, &kinds)?)?;
+        let blobs = self.write_variants(&keys, sub_id, &variants).await?;
 
         let linked = LinkedImage {
             id: sub_id,
             original_mime,
             original_size: size,
-            variants: variants.iter().map(|(v, _)| *v).collect(),
+            variants: kinds,
+            blobs,
         };
         entry.linked_images.push(linked);
-        self.db.insert_entry(&key, &entry)?;
+        self.db.insert_entry(&keys, &entry)?;
+        self.end_blobs(&row)?;
 
         Ok(entry)
     }
@@ -487,10 +827,9 @@ impl Vault {
         entry_id: Uuid,
         sub_id: Uuid,
     ) -> Result<ImageEntry, VaultError> {
-        let (key, mut entry) = self.with_data(|data| {
-            let key = data.encryption_key.expose_secret().to_vec();
-            let entry = self.db.get_entry(data.encryption_key.expose_secret(), entry_id)?;
-            Ok((key, entry))
+        let (keys, mut entry) = self.with_data(|data| {
+            let entry = self.live_entry(&data.keys, entry_id)?;
+            Ok((data.keys.clone(), entry))
         })?;
 
         let pos = entry
@@ -502,16 +841,15 @@ impl Vault {
                 sub_id
             )))?;
 
-        entry.linked_images.remove(pos);
-        self.db.insert_entry(&key, &entry)?;
+        let linked = entry.linked_images.remove(pos);
+        let blobs = Self::image_blobs(sub_id, &linked.variants, &linked.blobs);
+        let row = self.begin_blobs(&keys, entry_id, blobs.clone())?;
+        self.db.insert_entry(&keys, &entry)?;
+        self.db.flush()?;
 
         // Delete sub-image files
-        let dir = format!("{}/{}", DATA_DIR, sub_id);
-        if let Err(e) = fs::remove_dir_all(&dir).await {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                return Err(e.into());
-            }
-        }
+        Self::remove_blobs(&blobs).await?;
+        self.end_blobs(&row)?;
 
         Ok(entry)
     }
@@ -522,60 +860,66 @@ impl Vault {
         entry_id: Uuid,
         sub_id: Uuid,
         variant: ImageVariant,
-    ) -> Result<(Vec<u8>, String), VaultError> {
-        let (key_vec, mime) = self.with_data(|data| {
-            let key = data.encryption_key.expose_secret();
-            let entry = self.db.get_entry(key, entry_id)?;
-
-            let linked = entry
-                .linked_images
-                .iter()
-                .find(|l| l.id == sub_id)
-                .ok_or(VaultError::NotFound(format!(
-                    "Linked image {} not found in set {}",
-                    sub_id, entry_id
-                )))?;
-
-            if !linked.variants.contains(&variant) {
-                return Err(VaultError::NotFound(format!(
-                    "Variant missing: {}",
-                    sub_id
-                )));
-            }
-            Ok((key.to_vec(), linked.original_mime.clone()))
-        })?;
+    ) -> Result<(BlobReader, String), VaultError> {
+        let (blob_key, mime, path) =
+            self.with_data(|data| {
+                let entry = self.db.get_entry(&data.keys, entry_id)?;
+
+                let linked = entry.linked_images.iter().find(|l| l.id == sub_id).ok_or(
+                    VaultError::NotFound(format!(
+                        "Linked image {} not found in set {}",
+                        sub_id, entry_id
+                    )),
+                )?;
+
+                if !linked.variants.contains(&variant) {
+                    return Err(VaultError::NotFound(format!("Variant missing: {}", sub_id)));
+                }
+                Ok((
+                    data.keys.blobs.clone(),
+                    linked.original_mime.clone(),
+                    Self::blob_path(sub_id, variant, StoredBlob::find(&linked.blobs, variant)),
+                ))
+            })?;
 
-        let path = format!("{}/{}/{}.enc", DATA_DIR, sub_id, variant.filename());
-        let encrypted_data = fs::read(&path).await?;
         let aad = Self::make_aad(sub_id, variant.filename());
-        let decrypted = crypto::decrypt(&key_vec, &encrypted_data, &aad)?;
+        let reader = BlobReader::open(&path, blob_key.expose_secret(), &aad).await?;
 
-        Ok((decrypted, variant.mime(&mime)))
+        Ok((reader, variant.mime(&mime)))
     }
 
     /// Downloads a linked set as a zip archive containing all original images.
-    pub async fn download_linked_set(&self, id: Uuid) -> Result<Vec<u8>, VaultError> {
+    /// The archive and the images in it are wiped when dropped.
+    pub async fn download_linked_set(&self, id: Uuid) -> Result<Zeroizing<Vec<u8>>, VaultError> {
         let entry = self.get_entry(id)?;
 
         // Collect all original images
-        let mut images: Vec<(String, Vec<u8>)> = Vec::new();
+        let mut images: Vec<(String, Zeroizing<Vec<u8>>)> = Vec::new();
 
         // Cover image
-        let (cover_data, _) = self.retrieve_image(id, ImageVariant::Original).await?;
+        let (cover, _) = self.retrieve_image(id, ImageVariant::Original).await?;
+        let cover_data = cover.read_to_end().await?;
         let ext = mime_to_ext(&entry.original_mime);
         images.push((format!("1_cover.{ext}"), cover_data));
 
         // Linked images
         for (i, linked) in entry.linked_images.iter().enumerate() {
-            let (data, _) = self
+            let (reader, _) = self
                 .retrieve_linked_image(id, linked.id, ImageVariant::Original)
                 .await?;
+            let data = reader.read_to_end().await?;
             let lext = mime_to_ext(&linked.original_mime);
             images.push((format!("{}.{lext}", i + 2), data));
         }
 
-        // Build zip synchronously
-        let buf = Cursor::new(Vec::new());
+        // Build zip synchronously, into a buffer sized up front so it is never
+        // reallocated, which would leave unwiped copies behind
+        let capacity = images
+            .iter()
+            .map(|(name, data)| data.len() + 2 * name.len() + ZIP_ENTRY_OVERHEAD)
+            .sum::<usize>()
+            + ZIP_ENTRY_OVERHEAD;
+        let buf = Cursor::new(Vec::with_capacity(capacity));
         let mut zip = ZipWriter::new(buf);
         let options =
             SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
@@ -590,7 +934,7 @@ impl Vault {
         let result = zip
             .finish()
             .map_err(|e| VaultError::Zip(e.to_string()))?;
-        Ok(result.into_inner())
+        Ok(Zeroizing::new(result.into_inner()))
     }
 
     // --- Core Helpers ---
@@ -599,6 +943,9 @@ impl Vault {
     where
         F: FnOnce(&VaultData) -> Result<R, VaultError>,
     {
+        if self.is_busy() {
+            return Err(VaultError::Busy("Re-encryption in progress".into()));
+        }
         let lock = self
             .data
             .read()
@@ -613,6 +960,9 @@ impl Vault {
     where
         F: FnOnce(&mut VaultData) -> Result<R, VaultError>,
     {
+        if self.is_busy() {
+            return Err(VaultError::Busy("Re-encryption in progress".into()));
+        }
         let mut lock = self
             .data
             .write()
@@ -623,15 +973,316 @@ impl Vault {
         }
     }
 
+    /// Finds the slot of the given kind that `secret` opens, together with
+    /// `keyfile` for slots that require one. Every slot is tried even after a
+    /// match, so how long this takes does not depend on which slot, or which
+    /// library, the secret belongs to.
+    fn open_key_slot(
+        &self,
+        secret: &str,
+        keyfile: Option<&SecretKey>,
+        kind: SlotKind,
+    ) -> Result<(KeySlot, SecretKey), VaultError> {
+        let mut opened = None;
+        for slot in self.db.get_key_slots()? {
+            // Without the keyfile a slot requiring one costs the same to fail
+            let keyfile = keyfile.filter(|_| slot.keyfile);
+            let wrapping_key = crypto::derive_key(secret, keyfile, &slot.salt, &slot.kdf)?;
+            if let Ok(master_key_bytes) =
+                crypto::decrypt(wrapping_key.expose_secret(), &slot.wrapped_key, &[])
+            {
+                let master_key = SecretKey::from_slice(&Zeroizing::new(master_key_bytes))?;
+                if opened.is_none()
+                    && Self::slot_kind(master_key.expose_secret(), &slot)
+                        .is_some_and(|k| k.opens_as(kind))
+                {
+                    opened = Some((slot, master_key));
+                }
+            }
+        }
+        opened.ok_or(VaultError::EncryptionError)
+    }
+
+    /// Like `open_key_slot` for a password, but only accepts slots of the
+    /// unlocked library. A password of another library is as wrong as any
+    /// other, so a session never reaches slots it cannot list.
+    fn open_unlocked_key_slot(
+        &self,
+        password: &str,
+        keyfile: Option<&SecretKey>,
+    ) -> Result<(KeySlot, SecretKey), VaultError> {
+        let unlocked = self.unlocked_keys()?;
+        self.throttled(false, || {
+            let (slot, master_key) = self.open_key_slot(password, keyfile, SlotKind::Password)?;
+            if Self::slot_kind(unlocked.master.expose_secret(), &slot).is_none() {
+                return Err(VaultError::EncryptionError);
+            }
+            Ok((slot, master_key))
+        })
+    }
+
+    /// The keys of the unlocked library. Unlike `with_data` this also works
+    /// while a rotation holds the vault.
+    fn unlocked_keys(&self) -> Result<VaultKeys, VaultError> {
+        let lock = self
+            .data
+            .read()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+        let data = lock.as_ref().ok_or(VaultError::EncryptionError)?;
+        Ok(data.keys.clone())
+    }
+
+    /// Hashes the keyfile a request uploaded, or else reads the configured one.
+    fn keyfile_secret(&self, uploaded: Option<&[u8]>) -> Result<Option<SecretKey>, VaultError> {
+        if let Some(contents) = uploaded {
+            return Ok(Some(crypto::keyfile_secret(contents)));
+        }
+        match &self.config.keyfile_path {
+            Some(path) => {
+                let contents = Zeroizing::new(std::fs::read(path)?);
+                Ok(Some(crypto::keyfile_secret(&contents)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The slots wrapping `master_key`, leaving out those of other libraries.
+    fn own_key_slots(&self, master_key: &[u8]) -> Result<Vec<KeySlot>, VaultError> {
+        Ok(self
+            .db
+            .get_key_slots()?
+            .into_iter()
+            .filter(|slot| Self::slot_kind(master_key, slot).is_some())
+            .collect())
+    }
+
+    fn wrap_key_slot(
+        id: u32,
+        secret: &str,
+        keyfile: Option<&SecretKey>,
+        master_key: &[u8],
+        kdf: &KdfParams,
+        label: &str,
+        kind: SlotKind,
+    ) -> Result<KeySlot, VaultError> {
+        let slot = KeySlot {
+            id,
+            salt: [0; 16],
+            kdf: *kdf,
+            wrapped_key: Vec::new(),
+            info: Self::seal_slot_info(master_key, id, label, kind)?,
+            keyfile: false,
+        };
+        Self::rewrap_key_slot(slot, secret, keyfile, master_key, kdf)
+    }
+
+    /// Wraps the master key under `secret` with a fresh salt, keeping the slot's
+    /// id and info. The slot requires `keyfile` from then on, or no keyfile if
+    /// it is `None`.
+    fn rewrap_key_slot(
+        slot: KeySlot,
+        secret: &str,
+        keyfile: Option<&SecretKey>,
+        master_key: &[u8],
+        kdf: &KdfParams,
+    ) -> Result<KeySlot, VaultError> {
+        let salt = rand::random::<[u8; 16]>();
+        let wrapping_key = crypto::derive_key(secret, keyfile, &salt, kdf)?;
+
+        Ok(KeySlot {
+            salt,
+            kdf: *kdf,
+            wrapped_key: crypto::encrypt(wrapping_key.expose_secret(), master_key, &[])?,
+            keyfile: keyfile.is_some(),
+            ..slot
+        })
+    }
+
+    /// Parameters for newly wrapped slots: the calibrated ones, but never below
+    /// the configured minimum.
+    fn slot_kdf(&self) -> KdfParams {
+        self.metadata.kdf.at_least(&self.config.kdf_min)
+    }
+
+    /// Re-wraps a slot that was just opened if its KDF parameters have fallen
+    /// below the configured minimum. The unlock itself already succeeded, so a
+    /// failure here is only logged and retried on the next unlock.
+    fn upgrade_key_slot(
+        &self,
+        slot: KeySlot,
+        secret: &str,
+        keyfile: Option<&SecretKey>,
+        master_key: &[u8],
+    ) {
+        if !slot.kdf.is_below(&self.config.kdf_min) {
+            return;
+        }
+
+        let id = slot.id;
+        let kdf = slot.kdf.at_least(&self.slot_kdf());
+        let keyfile = keyfile.filter(|_| slot.keyfile);
+        let result = Self::rewrap_key_slot(slot, secret, keyfile, master_key, &kdf)
+            .and_then(|slot| self.db.put_key_slot(&slot));
+        if let Err(e) = result {
+            eprintln!("Failed to upgrade KDF parameters of key slot {}: {}", id, e);
+        }
+    }
+
+    fn seal_slot_info(
+        master_key: &[u8],
+        id: u32,
+        label: &str,
+        kind: SlotKind,
+    ) -> Result<Vec<u8>, VaultError> {
+        let info = SlotInfo {
+            label: label.to_string(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            kind,
+        };
+        let bytes = postcard::to_stdvec(&info)?;
+        crypto::encrypt(master_key, &bytes, &id.to_be_bytes())
+    }
+
+    fn open_slot_info(master_key: &[u8], slot: &KeySlot) -> Result<SlotInfo, VaultError> {
+        let bytes = crypto::decrypt(master_key, &slot.info, &slot.id.to_be_bytes())?;
+
+        // Try the current format first, then the one from before slot kinds
+        match postcard::from_bytes::<SlotInfo>(&bytes) {
+            Ok(info) => Ok(info),
+            Err(_) => {
+                let v1: SlotInfoV1 = postcard::from_bytes(&bytes)?;
+                Ok(SlotInfo {
+                    label: v1.label,
+                    created_at: v1.created_at,
+                    kind: SlotKind::Password,
+                })
+            }
+        }
+    }
+
+    /// Recovery slots are wrapped under the normalized key, so whatever
+    /// grouping or case the user types it back in does not matter.
+    fn recovery_secret(recovery_key: &str) -> Result<String, VaultError> {
+        crypto::normalize_recovery_key(recovery_key).ok_or(VaultError::EncryptionError)
+    }
+
+    /// Kind of a slot wrapping `master_key`, or `None` if it belongs to
+    /// another library. The legacy pre-slot key has no info yet and can only
+    /// be a password.
+    fn slot_kind(master_key: &[u8], slot: &KeySlot) -> Option<SlotKind> {
+        if slot.info.is_empty() {
+            return Some(SlotKind::Password);
+        }
+        Self::open_slot_info(master_key, slot)
+            .ok()
+            .map(|info| info.kind)
+    }
+
+    /// Duress slots are listed as passwords, so the decoy library looks like
+    /// any other.
+    fn slot_summary(id: u32, info: SlotInfo) -> KeySlotSummary {
+        KeySlotSummary {
+            id,
+            label: info.label,
+            created_at: info.created_at,
+            kind: match info.kind {
+                SlotKind::Duress => SlotKind::Password,
+                kind => kind,
+            },
+        }
+    }
+
+    fn normalize_slot_label(label: &str) -> Result<String, VaultError> {
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(VaultError::Corruption("Label cannot be empty".into()));
+        }
+        if label.chars().count() > 64 {
+            return Err(VaultError::Corruption("Label too long".into()));
+        }
+        Ok(label.to_string())
+    }
+
+    /// Encrypts each variant of image `id` into a newly named blob.
+    async fn write_variants(
+        &self,
+        keys: &VaultKeys,
+        id: Uuid,
+        variants: &[(ImageVariant, Vec<u8>)],
+    ) -> Result<Vec<StoredBlob>, VaultError> {
+        let mut blobs = Vec::new();
+        for (variant, bytes) in variants {
+            let name = crypto::blob_name(
+                keys.index.expose_secret(),
+                id.as_bytes(),
+                variant.filename(),
+            )?;
+            fs::create_dir_all(Self::shard_dir(&name)).await?;
+
+            let aad = Self::make_aad(id, variant.filename());
+            blob::write_blob(
+                &Self::blob_path(id, *variant, Some(&name)),
+                keys.blobs.expose_secret(),
+                &aad,
+                bytes,
+                self.config.blob_padding,
+            )
+            .await?;
+            blobs.push(StoredBlob {
+                variant: *variant,
+                name,
+            });
+        }
+        Ok(blobs)
+    }
+
+    /// Deletes blob files. Ones already missing are skipped.
+    async fn remove_blobs(
+        blobs: &[(Uuid, ImageVariant, Option<String>)],
+    ) -> Result<(), VaultError> {
+        for (id, variant, name) in blobs {
+            let path = Self::blob_path(*id, *variant, name.as_deref());
+            if let Err(e) = fs::remove_file(&path).await
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                return Err(e.into());
+            }
+            if name.is_none() {
+                // Best effort, the directory is only empty after the last variant
+                let _ = fs::remove_dir(Self::legacy_dir(*id)).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of a blob. Blobs are sharded into directories by the first two
+    /// characters of their name; images stored before opaque names still have
+    /// a directory of their own.
+    fn blob_path(id: Uuid, variant: ImageVariant, name: Option<&str>) -> String {
+        match name {
+            Some(name) => format!("{}/{}.enc", Self::shard_dir(name), name),
+            None => format!("{}/{}.enc", Self::legacy_dir(id), variant.filename()),
+        }
+    }
+
+    fn shard_dir(name: &str) -> String {
+        format!("{}/{}", DATA_DIR, name.get(..2).unwrap_or_default())
+    }
+
+    fn legacy_dir(id: Uuid) -> String {
+        format!("{}/{}", DATA_DIR, id)
+    }
+
     fn make_aad(id: Uuid, variant: &str) -> Vec<u8> {
         let mut aad = id.as_bytes().to_vec();
         aad.extend_from_slice(variant.as_bytes());
         aad
     }
 
+    /// Entries in the trash are left out until they are restored.
     fn build_tag_index(entries: &[ImageEntry]) -> HashMap<String, HashSet<Uuid>> {
         let mut index = HashMap::new();
-        for entry in entries {
+        for entry in entries.iter().filter(|e| e.deleted_at.is_none()) {
             for tag in &entry.tags {
                 index
                     .entry(tag.clone())
@@ -642,3 +1293,1146 @@ impl Vault {
         index
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+    use tokio::sync::{Mutex, MutexGuard};
+
+    // The vault lives at paths relative to the working directory, which is
+    // shared by every test
+    static WORKING_DIR: Mutex<()> = Mutex::const_new(());
+
+    /// The working directory of one test, which keeps other tests out of it
+    /// and is removed once dropped, also when the test fails.
+    struct TempDir {
+        path: PathBuf,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.path).ok();
+        }
+    }
+
+    async fn enter_temp_dir() -> TempDir {
+        let guard = WORKING_DIR.lock().await;
+        let path = std::env::temp_dir().join(format!("vanta-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::env::set_current_dir(&path).unwrap();
+        TempDir {
+            path,
+            _guard: guard,
+        }
+    }
+
+    /// A vault set up with the password "password" and unlocked, in a
+    /// directory of its own.
+    async fn unlocked_vault() -> (TempDir, Vault) {
+        let dir = enter_temp_dir().await;
+        let mut vault = Vault::new(test_config()).unwrap();
+        vault.setup(password("password")).await.unwrap();
+        vault.unlock(password("password")).unwrap();
+        (dir, vault)
+    }
+
+    /// Stores a PNG that has only its original, `bytes`.
+    async fn store_original(vault: &Vault, bytes: &[u8]) -> ImageEntry {
+        let variants = vec![(ImageVariant::Original, bytes.to_vec())];
+        vault
+            .store_image("image/png".into(), bytes.len() as u64, variants)
+            .await
+            .unwrap()
+    }
+
+    fn test_config() -> VaultConfig {
+        let kdf = KdfParams {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+            ..KdfParams::default()
+        };
+        VaultConfig {
+            kdf_target: Duration::ZERO,
+            kdf_max_memory: kdf.m_cost,
+            kdf_max_iterations: kdf.t_cost,
+            kdf_min: kdf,
+            blob_padding: BlobPadding::Padme,
+            keyfile_path: None,
+            idle_lock: None,
+            max_unlock: None,
+            lockout_after: None,
+            self_destruct_after: None,
+            gc_interval: None,
+            gc_grace: Duration::ZERO,
+            trash_retention: None,
+            backup_dir: PathBuf::from("backups"),
+            mirror_dir: None,
+            mirror_retention: Duration::ZERO,
+        }
+    }
+
+    /// Opens a vault whose database was just closed by another handle,
+    /// which sled lets go of only a moment later.
+    async fn reopen() -> Vault {
+        loop {
+            match Vault::new(test_config()) {
+                Err(VaultError::Db(e)) if Database::is_locked(&e) => {
+                    tokio::time::sleep(Duration::from_millis(20)).await
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    fn password(password: &str) -> Credentials<'_> {
+        Credentials {
+            password,
+            keyfile: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn lock_clears_keys_and_index() {
+        let (_dir, vault) = unlocked_vault().await;
+        let entry = store_original(&vault, &[1, 2, 3]).await;
+        vault.tag_image(entry.id, "cat").unwrap();
+        assert!(vault.is_unlocked());
+
+        vault.lock();
+
+        assert!(!vault.is_unlocked());
+        assert!(vault.data.read().unwrap().is_none());
+        assert!(vault.list_tags().is_err());
+        assert!(vault.get_entry(entry.id).is_err());
+        assert!(
+            vault
+                .retrieve_image(entry.id, ImageVariant::Original)
+                .await
+                .is_err()
+        );
+
+        // Everything comes back from disk on the next unlock
+        vault.unlock(password("password")).unwrap();
+        assert_eq!(vault.list_tags().unwrap(), vec!["cat".to_string()]);
+        let (reader, _) = vault
+            .retrieve_image(entry.id, ImageVariant::Original)
+            .await
+            .unwrap();
+        assert_eq!(*reader.read_to_end().await.unwrap(), vec![1, 2, 3]);
+
+        vault.shutdown().unwrap();
+        assert!(!vault.is_unlocked());
+    }
+
+    #[tokio::test]
+    async fn every_kind_of_slot_opens_the_vault() {
+        let _dir = enter_temp_dir().await;
+
+        let mut vault = Vault::new(test_config()).unwrap();
+        let recovery_key = vault.setup(password("password")).await.unwrap();
+        // The recovery key is no password
+        assert!(vault.unlock(password(&recovery_key)).is_err());
+        vault.unlock(password("password")).unwrap();
+        let slot = vault
+            .add_key_slot(password("password"), "second", "Second")
+            .unwrap();
+        assert_eq!(slot.kind, SlotKind::Password);
+        vault.lock();
+
+        vault.unlock(password("second")).unwrap();
+        vault.lock();
+        // Recovering rewraps the oldest password slot, and leaves the others
+        vault.recover(&recovery_key, password("new"), None).unwrap();
+        assert!(vault.is_unlocked());
+        vault.lock();
+        assert!(vault.unlock(password("password")).is_err());
+        vault.unlock(password("new")).unwrap();
+        vault.unlock(password("second")).unwrap();
+
+        vault.remove_key_slot(password("new"), slot.id).unwrap();
+        vault.lock();
+        assert!(vault.unlock(password("second")).is_err());
+        vault.unlock(password("new")).unwrap();
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn regenerated_recovery_key_replaces_the_old_one() {
+        let _dir = enter_temp_dir().await;
+
+        let mut vault = Vault::new(test_config()).unwrap();
+        let old_key = vault.setup(password("password")).await.unwrap();
+        vault.unlock(password("password")).unwrap();
+        let new_key = vault.regenerate_recovery_key(password("password")).unwrap();
+        assert_ne!(new_key, old_key);
+        // Still one recovery slot, next to the password one
+        assert_eq!(vault.db.get_key_slots().unwrap().len(), 2);
+        vault.lock();
+
+        assert!(matches!(
+            vault.recover(&old_key, password("new"), None),
+            Err(VaultError::EncryptionError)
+        ));
+        vault.unlock(password("password")).unwrap();
+        vault.lock();
+        vault.recover(&new_key, password("new"), None).unwrap();
+        vault.lock();
+        vault.unlock(password("new")).unwrap();
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn duress_password_opens_a_separate_library() {
+        let (_dir, vault) = unlocked_vault().await;
+        let entry = store_original(&vault, &[1, 2, 3]).await;
+        vault.tag_image(entry.id, "cat").unwrap();
+        vault
+            .create_decoy(password("password"), "duress", false)
+            .unwrap();
+        assert!(
+            vault
+                .create_decoy(password("password"), "password", false)
+                .is_err()
+        );
+        assert_eq!(vault.list_key_slots().unwrap().len(), 2);
+
+        // Unlocking with the duress password replaces the open library
+        vault.unlock(password("duress")).unwrap();
+        assert!(vault.list_images().unwrap().is_empty());
+        assert!(vault.list_tags().unwrap().is_empty());
+        assert!(vault.get_entry(entry.id).is_err());
+        assert_eq!(vault.list_key_slots().unwrap().len(), 2);
+        // The real library's passwords are as wrong as any other here
+        assert!(
+            vault
+                .add_key_slot(password("password"), "other", "Other")
+                .is_err()
+        );
+        assert!(
+            vault
+                .remove_decoy(password("duress"), "duress")
+                .await
+                .is_err()
+        );
+
+        vault.unlock(password("password")).unwrap();
+        assert_eq!(vault.list_tags().unwrap(), vec!["cat".to_string()]);
+        vault
+            .remove_decoy(password("password"), "duress")
+            .await
+            .unwrap();
+        assert!(vault.unlock(password("duress")).is_err());
+        assert_eq!(vault.db.get_key_slots().unwrap().len(), 2);
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotation_of_another_library_does_not_hold_the_decoy() {
+        let (_dir, vault) = unlocked_vault().await;
+        vault
+            .create_decoy(password("password"), "duress", false)
+            .unwrap();
+        // Left pending, as if the process stopped right after starting it
+        vault.rotation_worker.store(true, Ordering::SeqCst);
+        vault.start_rotation(password("password")).unwrap();
+        assert!(vault.is_busy());
+        vault.rotation_worker.store(false, Ordering::SeqCst);
+
+        vault.unlock(password("duress")).unwrap();
+        assert!(!vault.is_busy());
+        assert!(vault.list_images().unwrap().is_empty());
+        assert!(!vault.rotation_progress().unwrap().active);
+
+        vault.unlock(password("password")).unwrap();
+        while vault.is_busy() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(vault.db.get_rotation().unwrap().is_none());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn duress_slot_removes_other_libraries() {
+        let (_dir, vault) = unlocked_vault().await;
+        vault
+            .create_decoy(password("password"), "duress", true)
+            .unwrap();
+
+        vault.unlock(password("duress")).unwrap();
+        // Listed like any password slot
+        assert!(
+            vault
+                .list_key_slots()
+                .unwrap()
+                .iter()
+                .all(|slot| slot.kind != SlotKind::Duress)
+        );
+        assert_eq!(vault.db.get_key_slots().unwrap().len(), 2);
+        assert!(vault.unlock(password("password")).is_err());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyfile_is_required_once_added() {
+        let _dir = enter_temp_dir().await;
+        let with_keyfile = |keyfile| Credentials {
+            password: "password",
+            keyfile: Some(keyfile),
+        };
+
+        let mut vault = Vault::new(test_config()).unwrap();
+        vault.setup(with_keyfile(b"key")).await.unwrap();
+        assert!(vault.unlock(password("password")).is_err());
+        assert!(vault.unlock(with_keyfile(b"other")).is_err());
+        vault.unlock(with_keyfile(b"key")).unwrap();
+
+        // Slots added with the keyfile require it too
+        vault
+            .add_key_slot(with_keyfile(b"key"), "second", "Second")
+            .unwrap();
+        vault.lock();
+        assert!(vault.unlock(password("second")).is_err());
+
+        vault.unlock(with_keyfile(b"key")).unwrap();
+        vault.change_keyfile(with_keyfile(b"key"), None).unwrap();
+        vault.lock();
+        vault.unlock(password("password")).unwrap();
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn totp_codes_are_checked_and_spent_once() {
+        let _dir = enter_temp_dir().await;
+        let now = 1_700_000_000;
+
+        let mut vault = Vault::new(test_config()).unwrap();
+        vault.setup(password("password")).await.unwrap();
+        vault.unlock(password("password")).unwrap();
+        vault.begin_totp(password("password")).unwrap();
+        // Not enforced until an authenticator proves it has the secret
+        assert!(!vault.second_factor_required().unwrap());
+
+        let secret = vault
+            .db
+            .get_setting::<types::TotpState>(&vault.unlocked_keys().unwrap(), totp::TOTP_SETTING)
+            .unwrap()
+            .unwrap()
+            .secret
+            .clone();
+        let code = |time: u64| format!("{:06}", totp::code_at(&secret, time / 30));
+
+        assert!(vault.confirm_totp(&code(now + 3600), now).is_err());
+        let backup_codes = vault.confirm_totp(&code(now), now).unwrap();
+        assert!(vault.second_factor_required().unwrap());
+        assert!(vault.begin_totp(password("password")).is_err());
+
+        // The code used to confirm is spent, the next one works once
+        assert!(vault.verify_second_factor(&code(now), now).is_err());
+        vault
+            .verify_second_factor(&code(now + 30), now + 30)
+            .unwrap();
+        assert!(
+            vault
+                .verify_second_factor(&code(now + 30), now + 30)
+                .is_err()
+        );
+        assert!(vault.verify_second_factor(&code(now + 600), now).is_err());
+
+        vault.verify_second_factor(&backup_codes[0], now).unwrap();
+        assert!(vault.verify_second_factor(&backup_codes[0], now).is_err());
+
+        // Still enabled after a lock, and gone once disabled
+        vault.lock();
+        vault.unlock(password("password")).unwrap();
+        assert!(vault.second_factor_required().unwrap());
+        vault.disable_totp(password("password")).unwrap();
+        assert!(!vault.second_factor_required().unwrap());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_lock_after_idle_period_or_maximum() {
+        let _dir = enter_temp_dir().await;
+        let config = VaultConfig {
+            idle_lock: Some(Duration::from_secs(60)),
+            max_unlock: Some(Duration::from_secs(150)),
+            ..test_config()
+        };
+
+        let mut vault = Vault::new(config).unwrap();
+        vault.setup(password("password")).await.unwrap();
+        assert_eq!(vault.auto_lock_remaining(Instant::now()), None);
+        vault.unlock(password("password")).unwrap();
+        let unlocked = Instant::now();
+        assert!(vault.auto_lock_remaining(unlocked).unwrap() <= Duration::from_secs(60));
+        assert!(!vault.auto_lock(unlocked + Duration::from_secs(30)));
+
+        // Activity pushes the idle lock back, but never past the maximum
+        *vault.last_activity.lock().unwrap() = unlocked + Duration::from_secs(50);
+        assert!(!vault.auto_lock(unlocked + Duration::from_secs(100)));
+        *vault.last_activity.lock().unwrap() = unlocked + Duration::from_secs(100);
+        assert!(
+            vault
+                .auto_lock_remaining(unlocked + Duration::from_secs(140))
+                .unwrap()
+                <= Duration::from_secs(10)
+        );
+        assert!(vault.auto_lock(unlocked + Duration::from_secs(150)));
+        assert!(!vault.is_unlocked());
+        assert!(!vault.auto_lock(unlocked + Duration::from_secs(150)));
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_unlocks_are_throttled_then_wipe_key_slots() {
+        let _dir = enter_temp_dir().await;
+        let config = VaultConfig {
+            lockout_after: Some(5),
+            self_destruct_after: Some(7),
+            ..test_config()
+        };
+        // Moves the last failure back, as if the delay had passed
+        let wait_out = |vault: &Vault| {
+            let mut failures = vault.db.get_failed_attempts().unwrap();
+            failures.last_failure = 0;
+            vault.db.save_failed_attempts(&failures).unwrap();
+        };
+
+        let mut vault = Vault::new(config).unwrap();
+        let recovery_key = vault.setup(password("password")).await.unwrap();
+        for _ in 0..3 {
+            assert!(matches!(
+                vault.unlock(password("wrong")),
+                Err(VaultError::EncryptionError)
+            ));
+        }
+        // Even the right password has to wait now
+        assert!(matches!(
+            vault.unlock(password("password")),
+            Err(VaultError::Throttled(1))
+        ));
+        assert!(vault.retry_after().unwrap() > 0);
+        wait_out(&vault);
+        vault.unlock(password("password")).unwrap();
+        assert_eq!(vault.db.get_failed_attempts().unwrap().count, 0);
+        vault.lock();
+
+        for _ in 0..5 {
+            wait_out(&vault);
+            assert!(vault.unlock(password("wrong")).is_err());
+        }
+        wait_out(&vault);
+        assert!(matches!(
+            vault.unlock(password("password")),
+            Err(VaultError::LockedOut)
+        ));
+        // The recovery key still gets through, subject to the delay
+        let wrong_key = crypto::generate_recovery_key();
+        wait_out(&vault);
+        assert!(matches!(
+            vault.recover(&wrong_key, password("new"), None),
+            Err(VaultError::EncryptionError)
+        ));
+
+        // The seventh failure in a row wipes every slot
+        wait_out(&vault);
+        assert!(vault.recover(&wrong_key, password("new"), None).is_err());
+        assert!(vault.needs_setup());
+        assert!(vault.recover(&recovery_key, password("new"), None).is_err());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_chained() {
+        let _dir = enter_temp_dir().await;
+        let ip = Some("192.0.2.1".parse().unwrap());
+
+        let mut vault = Vault::new(test_config()).unwrap();
+        vault.setup(password("password")).await.unwrap();
+        assert!(vault.unlock(password("wrong")).is_err());
+        vault.unlock(password("password")).unwrap();
+        vault.audit(AuditEvent::Unlock, ip);
+        let id = Uuid::new_v4();
+        vault.audit(AuditEvent::Delete { id }, ip);
+
+        let events: Vec<AuditEvent> = vault
+            .audit_log(None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.event)
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], AuditEvent::Delete { id });
+        assert_eq!(events[1], AuditEvent::Unlock);
+        assert!(matches!(
+            events[2],
+            AuditEvent::FailedAttempts { count: 1, .. }
+        ));
+        assert_eq!(events[3], AuditEvent::Setup);
+        let page = vault.audit_log(Some(2), 10).unwrap();
+        assert_eq!(page.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![1, 0]);
+        assert!(vault.verify_audit_log().unwrap().intact);
+
+        // Records are only readable by the library they belong to
+        vault
+            .create_decoy(password("password"), "duress", false)
+            .unwrap();
+        vault.unlock(password("duress")).unwrap();
+        assert!(vault.audit_log(None, 10).unwrap().is_empty());
+        vault.unlock(password("password")).unwrap();
+
+        // A record gone from the middle breaks the chain there
+        let keys = vault.unlocked_keys().unwrap();
+        let state = vault
+            .db
+            .get_setting::<types::AuditState>(&keys, audit::AUDIT_SETTING)
+            .unwrap()
+            .unwrap();
+        vault
+            .db
+            .remove_audit_record(&crypto::audit_row(&state.key, 1).unwrap())
+            .unwrap();
+        let check = vault.verify_audit_log().unwrap();
+        assert!(!check.intact);
+        assert_eq!(check.broken_at, Some(1));
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_repairs_variants_and_quarantines_orphans() {
+        let (_dir, vault) = unlocked_vault().await;
+
+        let mut png = Vec::new();
+        image::RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let processed = crate::image_processor::process_upload(&png, "image/png").unwrap();
+        let entry = vault
+            .store_image(
+                processed.original_mime,
+                processed.original_size,
+                processed.variants,
+            )
+            .await
+            .unwrap();
+        assert!(vault.check(false).await.unwrap().is_clean());
+
+        // Lose the thumbnail, garble the low variant and leave a stray directory
+        let path = |v| Vault::blob_path(entry.id, v, StoredBlob::find(&entry.blobs, v));
+        std::fs::remove_file(path(ImageVariant::Thumbnail)).unwrap();
+        std::fs::write(path(ImageVariant::Low), b"garbage").unwrap();
+        let stray = Vault::legacy_dir(Uuid::new_v4());
+        std::fs::create_dir_all(&stray).unwrap();
+        std::fs::write(format!("{}/original.enc", stray), b"stray").unwrap();
+
+        let report = vault.check(false).await.unwrap();
+        let issues: Vec<_> = report
+            .problems
+            .iter()
+            .map(|p| (p.variant, p.issue))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (ImageVariant::Low, types::FsckIssue::Unreadable),
+                (ImageVariant::Thumbnail, types::FsckIssue::Missing),
+            ]
+        );
+        assert_eq!(report.orphans, vec![stray.clone()]);
+        assert!(!report.is_clean());
+
+        let report = vault.check(true).await.unwrap();
+        assert!(report.is_clean());
+        assert!(!std::path::Path::new(&stray).exists());
+        let (thumbnail, _) = vault
+            .retrieve_image(entry.id, ImageVariant::Thumbnail)
+            .await
+            .unwrap();
+        assert!(!thumbnail.read_to_end().await.unwrap().is_empty());
+
+        // A blob quarantined by the check of another library is moved back
+        let original = path(ImageVariant::Original);
+        let quarantined = original.replacen(DATA_DIR, "vault/quarantine", 1);
+        std::fs::create_dir_all(std::path::Path::new(&quarantined).parent().unwrap()).unwrap();
+        std::fs::rename(&original, &quarantined).unwrap();
+        assert!(!vault.check(false).await.unwrap().is_clean());
+        let report = vault.check(true).await.unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.is_clean());
+        assert!(vault.check(false).await.unwrap().is_clean());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_removes_orphans_after_grace_period() {
+        let (_dir, vault) = unlocked_vault().await;
+        let entry = store_original(&vault, &[1, 2, 3]).await;
+
+        // What an interrupted upload and an interrupted delete leave behind
+        let name = "ab".repeat(32);
+        std::fs::create_dir_all(Vault::shard_dir(&name)).unwrap();
+        std::fs::write(
+            Vault::blob_path(entry.id, ImageVariant::Low, Some(&name)),
+            [0; 10],
+        )
+        .unwrap();
+        let stray = Vault::legacy_dir(Uuid::new_v4());
+        std::fs::create_dir_all(&stray).unwrap();
+        std::fs::write(format!("{}/original.enc", stray), [0; 20]).unwrap();
+
+        let mut waiting = vault.clone();
+        waiting.config.gc_grace = Duration::from_secs(3600);
+        assert_eq!(waiting.collect_garbage().await.unwrap().removed, 0);
+
+        let report = vault.collect_garbage().await.unwrap();
+        assert_eq!((report.removed, report.reclaimed), (2, 30));
+        assert!(!std::path::Path::new(&stray).exists());
+        let (reader, _) = vault
+            .retrieve_image(entry.id, ImageVariant::Original)
+            .await
+            .unwrap();
+        assert_eq!(*reader.read_to_end().await.unwrap(), vec![1, 2, 3]);
+
+        // Once another library exists, unreferenced blobs may be its own
+        vault
+            .create_decoy(password("password"), "duress", false)
+            .unwrap();
+        std::fs::create_dir_all(&stray).unwrap();
+        assert!(vault.collect_garbage().await.unwrap().skipped);
+        assert!(std::path::Path::new(&stray).exists());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_undoes_writes_and_finishes_removals() {
+        let (_dir, vault) = unlocked_vault().await;
+        let keys = vault.unlocked_keys().unwrap();
+        let variants = vec![(ImageVariant::Original, vec![1, 2, 3])];
+        let exists = |blobs: &[(Uuid, ImageVariant, Option<String>)]| {
+            blobs
+                .iter()
+                .map(|(id, v, name)| Vault::blob_path(*id, *v, name.as_deref()))
+                .any(|path| std::path::Path::new(&path).exists())
+        };
+
+        // Blobs written for an entry that was never stored
+        let id = Uuid::new_v4();
+        let written = Vault::new_blobs(&keys, id, &[ImageVariant::Original]).unwrap();
+        vault.begin_blobs(&keys, id, written.clone()).unwrap();
+        vault.write_variants(&keys, id, &variants).await.unwrap();
+
+        // An entry removed before its blobs were
+        let entry = vault
+            .store_image("image/png".into(), 3, variants.clone())
+            .await
+            .unwrap();
+        let removed = Vault::entry_blobs(&entry);
+        vault.begin_blobs(&keys, entry.id, removed.clone()).unwrap();
+        vault.db.remove_entry(&keys, entry.id).unwrap();
+
+        // A removal that did not get to the entry yet
+        let kept = vault
+            .store_image("image/png".into(), 3, variants)
+            .await
+            .unwrap();
+        vault
+            .begin_blobs(&keys, kept.id, Vault::entry_blobs(&kept))
+            .unwrap();
+
+        // Operations still running are left alone
+        vault.replay_journal(&keys).unwrap();
+        assert!(exists(&written) && exists(&removed));
+
+        // As after a restart
+        vault.journal.lock().unwrap().clear();
+        vault.replay_journal(&keys).unwrap();
+        assert!(!exists(&written) && !exists(&removed));
+        assert!(exists(&Vault::entry_blobs(&kept)));
+        assert!(vault.db.get_journal_records(&keys).unwrap().is_empty());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_rotation_resumes_from_its_checkpoint() {
+        let (_dir, vault) = unlocked_vault().await;
+        let mut entries = Vec::new();
+        for byte in 0..3 {
+            let entry = store_original(&vault, &[byte]).await;
+            entries.push(entry);
+        }
+
+        // The blob of the entry rotated last is swapped for a directory, which
+        // fails to read and stops the rotation there
+        let old_keys = vault.unlocked_keys().unwrap();
+        let last = entries
+            .iter()
+            .max_by_key(|entry| Database::entry_key(&old_keys, entry.id).unwrap())
+            .unwrap();
+        let (id, variant, name) = Vault::entry_blobs(last).remove(0);
+        let blob = Vault::blob_path(id, variant, name.as_deref());
+        std::fs::rename(&blob, "blob").unwrap();
+        std::fs::create_dir(&blob).unwrap();
+
+        vault.start_rotation(password("password")).unwrap();
+        while vault.rotation_worker.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let progress = vault.rotation_progress().unwrap();
+        assert!(progress.active);
+        assert_eq!((progress.done, progress.total), (2, 3));
+        assert!(vault.is_busy());
+        assert!(vault.list_images().is_err());
+
+        // The next unlock picks up after the two entries done
+        std::fs::remove_dir(&blob).unwrap();
+        std::fs::rename("blob", &blob).unwrap();
+        vault.lock();
+        vault.unlock(password("password")).unwrap();
+        while vault.is_busy() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!vault.rotation_progress().unwrap().active);
+
+        assert_eq!(vault.list_images().unwrap().len(), 3);
+        for (byte, entry) in entries.iter().enumerate() {
+            let (reader, _) = vault
+                .retrieve_image(entry.id, ImageVariant::Original)
+                .await
+                .unwrap();
+            assert_eq!(*reader.read_to_end().await.unwrap(), vec![byte as u8]);
+        }
+        let new_keys = vault.unlocked_keys().unwrap();
+        assert_ne!(
+            new_keys.blobs.expose_secret(),
+            old_keys.blobs.expose_secret()
+        );
+        let encrypted = std::fs::read(&blob).unwrap();
+        let aad = Vault::make_aad(id, variant.filename());
+        assert!(crypto::decrypt_blob(old_keys.blobs.expose_secret(), &encrypted, &aad).is_err());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumed_migration_counts_each_entry_once() {
+        let (_dir, vault) = unlocked_vault().await;
+        let keys = vault.unlocked_keys().unwrap();
+        drop(vault);
+
+        // A v6 vault, with entries under their raw ids and blobs at the
+        // per-image paths. The ids sort first and last, so rows moved under
+        // their new keys land between them.
+        let ids = [1, 2, 3, u128::MAX].map(Uuid::from_u128);
+        let db = loop {
+            match sled::open(DATABASE_DIR) {
+                Err(e) if Database::is_locked(&e) => {
+                    tokio::time::sleep(Duration::from_millis(20)).await
+                }
+                result => break result.unwrap(),
+            }
+        };
+        db.insert("vault_version", "6").unwrap();
+        let tree = db.open_tree("entries").unwrap();
+        for (byte, &id) in ids.iter().enumerate() {
+            let entry = ImageEntry {
+                id,
+                original_mime: "image/png".into(),
+                original_size: 1,
+                created_at: 1_700_000_000,
+                variants: vec![ImageVariant::Original],
+                tags: Vec::new(),
+                linked_images: Vec::new(),
+                blobs: Vec::new(),
+                deleted_at: None,
+            };
+            let bytes = postcard::to_stdvec(&entry).unwrap();
+            let encrypted =
+                crypto::encrypt(keys.entries.expose_secret(), &bytes, id.as_bytes()).unwrap();
+            tree.insert(id.as_bytes(), encrypted).unwrap();
+
+            let aad = Vault::make_aad(id, ImageVariant::Original.filename());
+            let blob = crypto::encrypt_blob(
+                keys.blobs.expose_secret(),
+                &[byte as u8],
+                &aad,
+                BlobPadding::Padme,
+            )
+            .unwrap();
+            std::fs::create_dir_all(Vault::legacy_dir(id)).unwrap();
+            std::fs::write(Vault::blob_path(id, ImageVariant::Original, None), blob).unwrap();
+        }
+        db.flush().unwrap();
+        drop((tree, db));
+
+        // A directory in place of a blob stops the rewrite at its entry
+        let blob = |id: Uuid| Vault::blob_path(id, ImageVariant::Original, None);
+        let block = |id: Uuid| {
+            std::fs::rename(blob(id), format!("blob-{}", id)).unwrap();
+            std::fs::create_dir(blob(id)).unwrap();
+        };
+        let unblock = |id: Uuid| {
+            std::fs::remove_dir(blob(id)).unwrap();
+            std::fs::rename(format!("blob-{}", id), blob(id)).unwrap();
+        };
+        let run = async |vault: &Vault| {
+            vault.lock();
+            vault.unlock(password("password")).unwrap();
+            while vault.rotation_worker.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            vault.rotation_progress().unwrap()
+        };
+        block(ids[2]);
+        block(ids[3]);
+
+        let vault = reopen().await;
+        let progress = run(&vault).await;
+        assert_eq!((progress.done, progress.total), (2, 4));
+
+        // The keys stay the same, so the rows moved by the first run still
+        // decrypt with the old ones when the second one comes across them
+        unblock(ids[2]);
+        let progress = run(&vault).await;
+        assert!(progress.active);
+        assert!(progress.done <= progress.total);
+        assert_eq!(progress.done, 3);
+
+        unblock(ids[3]);
+        assert!(!run(&vault).await.active);
+        assert!(!vault.is_busy());
+        assert_eq!(vault.db.count_entries(), 4);
+        for (byte, &id) in ids.iter().enumerate() {
+            let (reader, _) = vault
+                .retrieve_image(id, ImageVariant::Original)
+                .await
+                .unwrap();
+            assert_eq!(*reader.read_to_end().await.unwrap(), vec![byte as u8]);
+        }
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn v2_vault_migrates_to_the_current_version() {
+        let _dir = enter_temp_dir().await;
+
+        // A vault as the first release wrote it: the master key wrapped under
+        // a single salt, entries under their raw ids and blobs in a directory
+        // per image, all encrypted with the master key itself
+        #[derive(serde::Serialize)]
+        struct EntryV2 {
+            id: Uuid,
+            original_mime: String,
+            original_size: u64,
+            created_at: u64,
+            variants: Vec<ImageVariant>,
+            tags: Vec<String>,
+            linked_images: Vec<LinkedImageV2>,
+        }
+        #[derive(serde::Serialize)]
+        struct LinkedImageV2 {
+            id: Uuid,
+            original_mime: String,
+            original_size: u64,
+            variants: Vec<ImageVariant>,
+        }
+
+        let master = [9u8; 32];
+        let salt = [3u8; 16];
+        let contents = |id: Uuid, variant: ImageVariant| format!("{}/{}", id, variant.filename());
+        let write_blob = |id: Uuid, variant: ImageVariant| {
+            let aad = Vault::make_aad(id, variant.filename());
+            let encrypted =
+                crypto::encrypt(&master, contents(id, variant).as_bytes(), &aad).unwrap();
+            std::fs::create_dir_all(Vault::legacy_dir(id)).unwrap();
+            std::fs::write(Vault::blob_path(id, variant, None), encrypted).unwrap();
+        };
+        let linked = LinkedImageV2 {
+            id: Uuid::new_v4(),
+            original_mime: "image/png".into(),
+            original_size: 2,
+            variants: vec![ImageVariant::Original, ImageVariant::Thumbnail],
+        };
+        let entries = [
+            EntryV2 {
+                id: Uuid::new_v4(),
+                original_mime: "image/png".into(),
+                original_size: 1,
+                created_at: 1_600_000_000,
+                variants: vec![ImageVariant::Original, ImageVariant::Low],
+                tags: vec!["cat".into()],
+                linked_images: vec![linked],
+            },
+            EntryV2 {
+                id: Uuid::new_v4(),
+                original_mime: "image/jpeg".into(),
+                original_size: 3,
+                created_at: 1_600_000_001,
+                variants: vec![ImageVariant::Original],
+                tags: Vec::new(),
+                linked_images: Vec::new(),
+            },
+        ];
+
+        let db = sled::open(DATABASE_DIR).unwrap();
+        let wrapping_key =
+            crypto::derive_key("password", None, &salt, &KdfParams::default()).unwrap();
+        let check = crypto::encrypt(wrapping_key.expose_secret(), &master, &[]).unwrap();
+        db.insert("vault_version", "2").unwrap();
+        db.insert("created_at", "1600000000").unwrap();
+        db.insert("vault_salt", &salt).unwrap();
+        db.insert("master_key_check", check).unwrap();
+        std::fs::write(LEGACY_SALT_PATH, salt).unwrap();
+        let tree = db.open_tree("entries").unwrap();
+        for entry in &entries {
+            let bytes = postcard::to_stdvec(entry).unwrap();
+            let encrypted = crypto::encrypt(&master, &bytes, entry.id.as_bytes()).unwrap();
+            tree.insert(entry.id.as_bytes(), encrypted).unwrap();
+            for &variant in &entry.variants {
+                write_blob(entry.id, variant);
+            }
+            for linked in &entry.linked_images {
+                for &variant in &linked.variants {
+                    write_blob(linked.id, variant);
+                }
+            }
+        }
+        db.flush().unwrap();
+        drop((tree, db));
+
+        // Unlocking runs the migrations, and the rotation worker rewrites
+        // every entry and blob
+        let vault = reopen().await;
+        assert!(!vault.needs_setup());
+        vault.unlock(password("password")).unwrap();
+        while vault.is_busy() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let progress = vault.rotation_progress().unwrap();
+        assert!(!progress.active);
+        assert_eq!(vault.db.get_version().unwrap(), 7);
+        assert_eq!(
+            vault.db.get_key_derivation().unwrap(),
+            crypto::CURRENT_KEY_DERIVATION
+        );
+        assert!(!Path::new(LEGACY_SALT_PATH).exists());
+        let slots = vault.list_key_slots().unwrap();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].label, PRIMARY_SLOT_LABEL);
+
+        // Nothing is left under the raw ids
+        assert!(
+            vault
+                .db
+                .entries_after(None)
+                .iter()
+                .all(|(k, _)| entries.iter().all(|e| k.as_ref() != e.id.as_bytes()))
+        );
+        assert_eq!(vault.list_images().unwrap().len(), entries.len());
+        assert_eq!(vault.list_tags().unwrap(), vec!["cat".to_string()]);
+        for expected in &entries {
+            assert!(!Path::new(&Vault::legacy_dir(expected.id)).exists());
+            let entry = vault.get_entry(expected.id).unwrap();
+            assert_eq!(entry.original_mime, expected.original_mime);
+            assert_eq!(entry.created_at, expected.created_at);
+            assert_eq!(entry.tags, expected.tags);
+            for &variant in &expected.variants {
+                let (reader, _) = vault.retrieve_image(entry.id, variant).await.unwrap();
+                assert_eq!(
+                    *reader.read_to_end().await.unwrap(),
+                    contents(entry.id, variant).into_bytes()
+                );
+            }
+            assert_eq!(entry.linked_images.len(), expected.linked_images.len());
+            for linked in &expected.linked_images {
+                for &variant in &linked.variants {
+                    let (reader, _) = vault
+                        .retrieve_linked_image(entry.id, linked.id, variant)
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        *reader.read_to_end().await.unwrap(),
+                        contents(linked.id, variant).into_bytes()
+                    );
+                }
+            }
+        }
+
+        // The migrated slot keeps working
+        vault.lock();
+        vault.unlock(password("password")).unwrap();
+        assert_eq!(vault.list_images().unwrap().len(), entries.len());
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn trash_hides_entries_until_restored_or_purged() {
+        let (_dir, vault) = unlocked_vault().await;
+        let variants = vec![(ImageVariant::Original, vec![1, 2, 3])];
+        let entry = vault
+            .store_image("image/png".into(), 3, variants)
+            .await
+            .unwrap();
+        vault.tag_image(entry.id, "cat").unwrap();
+        let blobs = Vault::entry_blobs(&entry);
+
+        vault.trash_image(entry.id).unwrap();
+        assert!(vault.list_images().unwrap().is_empty());
+        assert!(
+            vault
+                .search_by_tags(&[], &["dog".into()])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(vault.list_tags().unwrap().is_empty());
+        assert!(vault.tag_image(entry.id, "dog").is_err());
+        assert_eq!(vault.list_trash().unwrap().len(), 1);
+
+        vault.restore_image(entry.id).unwrap();
+        assert_eq!(vault.search_by_tags(&["cat".into()], &[]).unwrap().len(), 1);
+        assert!(vault.list_trash().unwrap().is_empty());
+
+        // Only what is in the trash can be purged
+        assert!(vault.purge_image(entry.id).await.is_err());
+        vault.trash_image(entry.id).unwrap();
+        assert_eq!(vault.purge_trash(0, None).await.unwrap(), 0);
+        assert_eq!(vault.purge_trash(u64::MAX, None).await.unwrap(), 1);
+        assert!(vault.list_trash().unwrap().is_empty());
+        assert!(blobs.iter().all(|(id, v, name)| {
+            !std::path::Path::new(&Vault::blob_path(*id, *v, name.as_deref())).exists()
+        }));
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn backup_restores_to_a_working_vault() {
+        let (dir, vault) = unlocked_vault().await;
+        let entry = store_original(&vault, &[1, 2, 3]).await;
+        let archive = dir.path.join("backup.zip");
+        // What is removed once the snapshot is taken is still backed up
+        let snapshot = vault.snapshot().unwrap();
+        vault.trash_image(entry.id).unwrap();
+        vault.purge_image(entry.id).await.unwrap();
+        let manifest = vault.backup(snapshot, archive.clone()).await.unwrap();
+        assert!(manifest.files.len() > 1);
+        assert_eq!(std::fs::read_dir(backup::SNAPSHOT_DIR).unwrap().count(), 0);
+        vault.shutdown().unwrap();
+        drop(vault);
+
+        // Restoring never overwrites a vault
+        assert!(Vault::restore_backup(archive.clone()).await.is_err());
+        std::fs::rename("vault", "vault.old").unwrap();
+
+        // A tampered archive is rejected and leaves nothing behind
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let tampered = dir.path.join("tampered.zip");
+        let blob = bytes.windows(4).position(|w| w == b".enc").unwrap();
+        bytes[blob + 16] ^= 1;
+        std::fs::write(&tampered, &bytes).unwrap();
+        assert!(Vault::restore_backup(tampered).await.is_err());
+        assert!(!std::path::Path::new("vault").exists());
+
+        Vault::restore_backup(archive).await.unwrap();
+        let vault = reopen().await;
+        vault.unlock(password("password")).unwrap();
+        assert_eq!(vault.list_images().unwrap()[0].id, entry.id);
+        let (reader, _) = vault
+            .retrieve_image(entry.id, ImageVariant::Original)
+            .await
+            .unwrap();
+        assert_eq!(*reader.read_to_end().await.unwrap(), vec![1, 2, 3]);
+
+        vault.shutdown().unwrap();
+    }
+
+    #[tokio::test]
+    async fn mirror_copies_only_changes_and_can_replace_the_vault() {
+        let (dir, vault) = unlocked_vault().await;
+        let variants = vec![(ImageVariant::Original, vec![1, 2, 3])];
+        let kept = vault
+            .store_image("image/png".into(), 3, variants.clone())
+            .await
+            .unwrap();
+        let purged = vault
+            .store_image("image/png".into(), 3, variants)
+            .await
+            .unwrap();
+        let target = dir.path.join("mirror");
+
+        let report = vault
+            .mirror(vault.snapshot().unwrap(), target.clone())
+            .await
+            .unwrap();
+        assert_eq!((report.copied, report.verified), (2, 2));
+        assert!(report.records > 0);
+        let report = vault
+            .mirror(vault.snapshot().unwrap(), target.clone())
+            .await
+            .unwrap();
+        assert_eq!((report.copied, report.records), (0, 0));
+
+        // A copy an interrupted run made is still verified
+        let name = Vault::entry_blobs(&kept)[0].2.clone().unwrap();
+        let relative = format!("{}/{}.enc", &name[..2], name);
+        std::fs::write(
+            target.join("mirror.progress"),
+            format!("{} {}\n", "0".repeat(64), relative),
+        )
+        .unwrap();
+        let report = vault
+            .mirror(vault.snapshot().unwrap(), target.clone())
+            .await
+            .unwrap();
+        assert_eq!(report.corrupt, vec![relative]);
+        let report = vault
+            .mirror(vault.snapshot().unwrap(), target.clone())
+            .await
+            .unwrap();
+        assert_eq!((report.copied, report.verified), (1, 1));
+
+        vault.trash_image(purged.id).unwrap();
+        vault.purge_image(purged.id).await.unwrap();
+        let report = vault
+            .mirror(vault.snapshot().unwrap(), target.clone())
+            .await
+            .unwrap();
+        assert_eq!(report.removed, 1);
+        vault.shutdown().unwrap();
+        drop(vault);
+
+        std::fs::rename("vault", "vault.old").unwrap();
+        std::fs::rename(&target, "vault").unwrap();
+        let vault = reopen().await;
+        vault.unlock(password("password")).unwrap();
+        let entries = vault.list_images().unwrap();
+        assert_eq!(entries.len(), 1);
+        let (reader, _) = vault
+            .retrieve_image(kept.id, ImageVariant::Original)
+            .await
+            .unwrap();
+        assert_eq!(*reader.read_to_end().await.unwrap(), vec![1, 2, 3]);
+
+        vault.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+use super::{
+    PRIMARY_SLOT_LABEL, RECOVERY_SLOT_LABEL, Vault,
+    audit::AUDIT_SETTING,
+    blob,
+    crypto::{self, VaultKeys},
+    db::Database,
+    error::VaultError,
+    secret::SecretKey,
+    totp::TOTP_SETTING,
+    types::{
+        Credentials, ImageEntry, ImageVariant, RotationProgress, RotationStarted, RotationState,
+        SlotKind, StoredBlob,
+    },
+};
+use secrecy::ExposeSecret;
+use std::{
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// AAD binding the wrapped old key to its purpose
+pub(super) const ROTATION_AAD: &[u8] = b"rotation";
+
+/// Every per-library setting, carried over to the new keys
+pub(super) const SETTINGS: &[&str] = &[AUDIT_SETTING, TOTP_SETTING];
+
+impl Vault {
+    /// Starts replacing the master key. Every entry and blob is re-encrypted in
+    /// the background while the vault reports itself busy.
+    ///
+    /// Slots can only be re-wrapped with a secret that opens them, so the slot
+    /// `credentials` open is kept, a new recovery key replaces the old one and
+    /// every other slot of the library is removed. The new slots and the
+    /// rotation checkpoint are written in one transaction, so any remaining
+    /// password unlocks the new key and can resume an interrupted rotation.
+    /// Other libraries keep their slots, entries and blobs.
+    pub fn start_rotation(&self, credentials: Credentials) -> Result<RotationStarted, VaultError> {
+        if self.is_busy() || self.db.get_rotation()?.is_some() {
+            return Err(VaultError::Busy("Re-encryption in progress".into()));
+        }
+
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        let (slot, old_key) =
+            self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+        let old = old_key.expose_secret();
+        let new_key = SecretKey::random();
+        let new = new_key.expose_secret();
+        let kdf = self.slot_kdf();
+
+        let (label, kind) = Self::open_slot_info(old, &slot)
+            .map(|info| (info.label, info.kind))
+            .unwrap_or_else(|_| (PRIMARY_SLOT_LABEL.to_string(), SlotKind::Password));
+        let replaced: Vec<_> = self
+            .own_key_slots(old)?
+            .into_iter()
+            .filter(|s| s.id != slot.id)
+            .collect();
+        let removed_slots = replaced
+            .iter()
+            .filter(|s| Self::slot_kind(old, s).is_some_and(|k| k.opens_as(SlotKind::Password)))
+            .map(|s| s.id)
+            .collect();
+        let replaced: Vec<_> = replaced.iter().map(|s| s.id).collect();
+
+        let password_slot = Self::wrap_key_slot(
+            slot.id,
+            credentials.password,
+            keyfile.filter(|_| slot.keyfile).as_ref(),
+            new,
+            &kdf,
+            &label,
+            kind,
+        )?;
+        let recovery_key = crypto::generate_recovery_key();
+        let recovery_slot = Self::wrap_key_slot(
+            self.db.next_key_slot_id()?,
+            &Self::recovery_secret(&recovery_key)?,
+            None,
+            new,
+            &kdf,
+            RECOVERY_SLOT_LABEL,
+            SlotKind::Recovery,
+        )?;
+
+        let derivation = self.db.get_key_derivation()?;
+        let old_keys = VaultKeys::derive(old, derivation)?;
+        let new_keys = VaultKeys::derive(new, derivation)?;
+
+        let state = RotationState {
+            wrapped_old_key: Some(crypto::encrypt(new, old, ROTATION_AAD)?),
+            old_derivation: derivation,
+            cursor: None,
+            done: 0,
+            total: self.db.count_entries(),
+            failed: 0,
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        // Under the write lock so no request is holding the old key meanwhile.
+        // Settings are copied first, so they are never missing under the keys
+        // in use.
+        self.with_data_mut(|data| {
+            // Nor is an audit record appended under the old keys meanwhile
+            let _audit = self
+                .audit_lock
+                .lock()
+                .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+            // The journal is not carried over, so it has to be empty
+            self.replay_journal(&old_keys)?;
+            for name in SETTINGS {
+                self.db.copy_setting(&old_keys, &new_keys, name)?;
+            }
+            self.db
+                .begin_rotation(&[password_slot, recovery_slot], &replaced, &state)?;
+            data.keys = new_keys.clone();
+            Ok(())
+        })?;
+        self.spawn_rotation(old_keys, new_keys);
+
+        Ok(RotationStarted {
+            recovery_key,
+            removed_slots,
+        })
+    }
+
+    /// Progress of the rotation holding the unlocked library. The checkpoint
+    /// of another library's rotation is not reported.
+    pub fn rotation_progress(&self) -> Result<RotationProgress, VaultError> {
+        let state = match self.is_busy() {
+            true => self.db.get_rotation()?,
+            false => None,
+        };
+        Ok(match state {
+            Some(state) => RotationProgress {
+                active: true,
+                done: state.done,
+                total: state.total,
+                failed: state.failed,
+                started_at: Some(state.started_at),
+            },
+            None => RotationProgress {
+                active: false,
+                done: 0,
+                total: 0,
+                failed: 0,
+                started_at: None,
+            },
+        })
+    }
+
+    /// True while a rotation holds the unlocked library; regular operations
+    /// are refused. Another library unlocked meanwhile works as usual, so
+    /// nothing tells it the rotation exists.
+    pub fn is_busy(&self) -> bool {
+        self.rotating.load(Ordering::SeqCst)
+            && self.rotation_generation.load(Ordering::SeqCst) == self.unlock_generation()
+    }
+
+    /// Marks the unlocked library as held by its rotation.
+    pub(super) fn hold_for_rotation(&self) {
+        self.rotation_generation
+            .store(self.unlock_generation(), Ordering::SeqCst);
+        self.rotating.store(true, Ordering::SeqCst);
+    }
+
+    /// Called on unlock while a rotation checkpoint exists, before the
+    /// unlocked keys are in use: recovers the old keys from the checkpoint and
+    /// copies the settings over, so they never look missing. Returns `None`
+    /// for a checkpoint the unlocked key cannot open, which belongs to another
+    /// library and has to wait for that one to be unlocked.
+    pub(super) fn rotation_old_keys(
+        &self,
+        state: &RotationState,
+        new_keys: &VaultKeys,
+    ) -> Result<Option<VaultKeys>, VaultError> {
+        let new_master = new_keys.master.expose_secret();
+        let old_master = match &state.wrapped_old_key {
+            Some(wrapped) => match crypto::decrypt(new_master, wrapped, ROTATION_AAD) {
+                Ok(old_master) => Zeroizing::new(old_master),
+                Err(_) => return Ok(None),
+            },
+            None => Zeroizing::new(new_master.to_vec()),
+        };
+        let old_keys = VaultKeys::derive(&old_master, state.old_derivation)?;
+        for name in SETTINGS {
+            self.db.copy_setting(&old_keys, new_keys, name)?;
+        }
+        Ok(Some(old_keys))
+    }
+
+    /// Runs the rotation to the end in the background, or leaves it to the
+    /// worker already running. Also continues an interrupted rotation with
+    /// the keys `rotation_old_keys` found.
+    pub(super) fn spawn_rotation(&self, old_keys: VaultKeys, new_keys: VaultKeys) {
+        self.hold_for_rotation();
+        if self.rotation_worker.swap(true, Ordering::SeqCst) {
+            // A worker is already running, e.g. the vault was locked and unlocked again
+            return;
+        }
+
+        let vault = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match vault.run_rotation(&old_keys, &new_keys) {
+                Ok(()) => vault.rotating.store(false, Ordering::SeqCst),
+                // The checkpoint stays, so the vault stays busy until the next
+                // unlock resumes it
+                Err(e) => eprintln!("Master key rotation stopped: {}", e),
+            }
+            vault.rotation_worker.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn run_rotation(&self, old: &VaultKeys, new: &VaultKeys) -> Result<(), VaultError> {
+        let (old_entries, new_entries) = (old.entries.expose_secret(), new.entries.expose_secret());
+
+        let mut state = self
+            .db
+            .get_rotation()?
+            .ok_or_else(|| VaultError::NotFound("Rotation checkpoint".into()))?;
+
+        for (k, v) in self.db.entries_after(state.cursor.as_deref()) {
+            let _step = self
+                .rotation_step
+                .lock()
+                .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+            // Committed entries move the cursor in the same transaction, so
+            // anything after it is still under the old keys, or was moved
+            // there under a new row key and needs nothing more. Rows passed
+            // over are saved along with the next one committed.
+            state.cursor = Some(k.to_vec());
+            let (row, encrypted) = match crypto::decrypt(old_entries, &v, &k) {
+                Ok(bytes) => match Database::decode_entry(&bytes) {
+                    // Moved earlier in this rotation. The migrations keep the
+                    // keys, so it still decrypts with the old ones.
+                    Ok(entry) if *k == *Database::entry_key(new, entry.id)? => continue,
+                    Ok(mut entry) => {
+                        let ImageEntry {
+                            id,
+                            variants,
+                            blobs,
+                            linked_images,
+                            ..
+                        } = &mut entry;
+                        state.failed += self.rotate_image(*id, variants, blobs, old, new)?;
+                        for linked in linked_images {
+                            state.failed += self.rotate_image(
+                                linked.id,
+                                &linked.variants,
+                                &mut linked.blobs,
+                                old,
+                                new,
+                            )?;
+                        }
+                        // Written in the current format, with the blob names,
+                        // under the new row key
+                        let row = Database::entry_key(new, entry.id)?;
+                        let bytes = postcard::to_stdvec(&entry)?;
+                        let encrypted = crypto::encrypt(new_entries, &bytes, &row)?;
+                        (row, encrypted)
+                    }
+                    Err(_) => {
+                        state.failed += 1;
+                        (k.to_vec(), crypto::encrypt(new_entries, &bytes, &k)?)
+                    }
+                },
+                Err(_) if crypto::decrypt(new_entries, &v, &k).is_ok() => continue,
+                // Another library's entry. Counted like ours, so the progress
+                // does not tell how many there are.
+                Err(_) => {
+                    state.done += 1;
+                    continue;
+                }
+            };
+
+            state.done += 1;
+            self.db.commit_rotated_entry(&k, &row, &encrypted, &state)?;
+        }
+
+        for name in SETTINGS {
+            self.db.remove_rotated_setting(old, new, name)?;
+        }
+        self.db.finish_rotation()?;
+
+        // The index could not be built while entries were mixed. It is built
+        // for whichever library is unlocked now, which need not be this one.
+        if let Ok(mut lock) = self.data.write()
+            && let Some(data) = lock.as_mut()
+        {
+            let all_entries = self.db.get_all_entries(data.keys.entries.expose_secret())?;
+            data.tag_index = Self::build_tag_index(&all_entries);
+        }
+
+        Ok(())
+    }
+
+    /// Re-encrypts every variant of one image. Variants stored before opaque
+    /// names are given one under the new keys and moved to it. Returns how
+    /// many blobs could not be re-encrypted.
+    fn rotate_image(
+        &self,
+        id: Uuid,
+        variants: &[ImageVariant],
+        blobs: &mut Vec<StoredBlob>,
+        old: &VaultKeys,
+        new: &VaultKeys,
+    ) -> Result<u64, VaultError> {
+        let mut failed = 0;
+        let mut moved = false;
+
+        for &variant in variants {
+            let from = Self::blob_path(id, variant, StoredBlob::find(blobs, variant));
+            let name = match StoredBlob::find(blobs, variant) {
+                Some(name) => name.to_string(),
+                None => {
+                    let name = crypto::blob_name(
+                        new.index.expose_secret(),
+                        id.as_bytes(),
+                        variant.filename(),
+                    )?;
+                    blobs.push(StoredBlob {
+                        variant,
+                        name: name.clone(),
+                    });
+                    moved = true;
+                    name
+                }
+            };
+
+            if !self.rotate_blob(id, variant, &from, &name, old, new)? {
+                failed += 1;
+            }
+        }
+
+        if moved {
+            // Best effort, anything left behind was not a blob of this image
+            let _ = std::fs::remove_dir(Self::legacy_dir(id));
+        }
+        Ok(failed)
+    }
+
+    /// Re-encrypts one blob under the new key, writing it to the blob named
+    /// `name` and removing `from` if that is elsewhere. Blobs already under
+    /// the new key (from an entry that was interrupted mid-way) are left
+    /// alone. Returns false if the blob is missing or readable with neither key.
+    fn rotate_blob(
+        &self,
+        id: Uuid,
+        variant: ImageVariant,
+        from: &str,
+        name: &str,
+        old: &VaultKeys,
+        new: &VaultKeys,
+    ) -> Result<bool, VaultError> {
+        let (old_key, new_key) = (old.blobs.expose_secret(), new.blobs.expose_secret());
+        let path = Self::blob_path(id, variant, Some(name));
+        let aad = Self::make_aad(id, variant.filename());
+
+        let encrypted = match std::fs::read(from) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // Moved before an interruption
+                return Ok(from != path
+                    && std::fs::read(&path)
+                        .is_ok_and(|bytes| crypto::decrypt_blob(new_key, &bytes, &aad).is_ok()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match crypto::decrypt_blob(old_key, &encrypted, &aad) {
+            Ok(plain) => {
+                let reencrypted =
+                    crypto::encrypt_blob(new_key, &plain, &aad, self.config.blob_padding)?;
+                std::fs::create_dir_all(Self::shard_dir(name))?;
+                blob::replace_file(&path, &reencrypted)?;
+                if from != path {
+                    std::fs::remove_file(from)?;
+                }
+                Ok(true)
+            }
+            Err(_) => Ok(crypto::decrypt_blob(new_key, &encrypted, &aad).is_ok()),
+        }
+    }
+
+    /// Every blob an entry owns: its own variants and those of its linked
+    /// images, with their names if they have one.
+    pub(super) fn entry_blobs(entry: &ImageEntry) -> Vec<(Uuid, ImageVariant, Option<String>)> {
+        let mut blobs = Self::image_blobs(entry.id, &entry.variants, &entry.blobs);
+        for linked in &entry.linked_images {
+            blobs.extend(Self::image_blobs(
+                linked.id,
+                &linked.variants,
+                &linked.blobs,
+            ));
+        }
+        blobs
+    }
+
+    pub(super) fn image_blobs(
+        id: Uuid,
+        variants: &[ImageVariant],
+        blobs: &[StoredBlob],
+    ) -> Vec<(Uuid, ImageVariant, Option<String>)> {
+        variants
+            .iter()
+            .map(|v| (id, *v, StoredBlob::find(blobs, *v).map(String::from)))
+            .collect()
+    }
+}
@@ -0,0 +1,180 @@
+use super::error::VaultError;
+use secrecy::ExposeSecret;
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ptr::NonNull,
+    sync::OnceLock,
+};
+use zeroize::Zeroize;
+
+pub const KEY_LEN: usize = 32;
+
+/// A 32-byte key in a page of its own, locked into RAM where the OS allows it
+/// so it is never swapped out, and zeroized before the page is freed.
+///
+/// Every key gets a whole page because locks are per page and do not nest:
+/// unlocking one key must not unlock another sharing its page.
+pub struct SecretKey {
+    ptr: NonNull<u8>,
+    locked: bool,
+}
+
+// The page is owned exclusively and only handed out as a shared slice
+unsafe impl Send for SecretKey {}
+unsafe impl Sync for SecretKey {}
+
+impl SecretKey {
+    /// Allocates a zeroed key and lets `init` fill it in place, so the key
+    /// never exists outside the locked page.
+    pub fn new_with<E>(init: impl FnOnce(&mut [u8]) -> Result<(), E>) -> Result<Self, E> {
+        let layout = page_layout();
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        let mut key = SecretKey {
+            ptr,
+            locked: lock_page(ptr, layout.size()),
+        };
+        init(key.bytes_mut())?;
+        Ok(key)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, VaultError> {
+        if bytes.len() != KEY_LEN {
+            return Err(VaultError::EncryptionError);
+        }
+        Self::new_with(|key| {
+            key.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    pub fn random() -> Self {
+        Self::new_with(|key| {
+            rand::fill(key);
+            Ok::<_, VaultError>(())
+        })
+        .expect("filling a key cannot fail")
+    }
+
+    /// Whether the page could be locked into RAM.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Overwrites the key with zeros. Dropping a key does this first.
+    pub fn wipe(&mut self) {
+        self.bytes_mut().zeroize();
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the page is at least KEY_LEN bytes and owned by this key
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), KEY_LEN) }
+    }
+}
+
+impl ExposeSecret<[u8]> for SecretKey {
+    fn expose_secret(&self) -> &[u8] {
+        // SAFETY: the page is at least KEY_LEN bytes and owned by this key
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), KEY_LEN) }
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        Self::from_slice(self.expose_secret()).expect("keys are always KEY_LEN bytes")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.wipe();
+        let layout = page_layout();
+        if self.locked {
+            unlock_page(self.ptr, layout.size());
+        }
+        // SAFETY: allocated in `new_with` with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) };
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+fn page_layout() -> Layout {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    let size = *PAGE_SIZE.get_or_init(page_size);
+    Layout::from_size_align(size, size).expect("page size is a power of two")
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size)
+        .ok()
+        .filter(|size| size.is_power_of_two() && *size >= KEY_LEN)
+        .unwrap_or(4096)
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Best effort: fails without privileges once RLIMIT_MEMLOCK is used up, in
+/// which case the key still works but may be swapped out.
+#[cfg(unix)]
+fn lock_page(ptr: NonNull<u8>, len: usize) -> bool {
+    // SAFETY: the range is a live allocation owned by the caller
+    unsafe { libc::mlock(ptr.as_ptr().cast(), len) == 0 }
+}
+
+#[cfg(not(unix))]
+fn lock_page(_ptr: NonNull<u8>, _len: usize) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn unlock_page(ptr: NonNull<u8>, len: usize) {
+    // SAFETY: the range is a live allocation owned by the caller
+    unsafe { libc::munlock(ptr.as_ptr().cast(), len) };
+}
+
+#[cfg(not(unix))]
+fn unlock_page(_ptr: NonNull<u8>, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipe_zeroes_the_key() {
+        let mut key = SecretKey::from_slice(&[7u8; KEY_LEN]).unwrap();
+        key.wipe();
+        assert_eq!(key.expose_secret(), &[0u8; KEY_LEN]);
+    }
+
+    #[test]
+    fn clones_have_their_own_page() {
+        let key = SecretKey::random();
+        let mut copy = key.clone();
+        assert_eq!(key.expose_secret(), copy.expose_secret());
+        assert_ne!(key.ptr, copy.ptr);
+
+        copy.wipe();
+        assert_ne!(key.expose_secret(), &[0u8; KEY_LEN]);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert!(SecretKey::from_slice(&[0u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use super::{Vault, error::VaultError, types::FailedAttempts};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Failed attempts allowed before any delay
+const FREE_ATTEMPTS: u32 = 3;
+/// The delay doubles with every failure up to this many seconds
+const MAX_DELAY: u64 = 15 * 60;
+
+impl Vault {
+    /// Runs `attempt`, a check of a password, recovery key or code, under the
+    /// failed attempt limits. A failure is whatever `attempt` rejects with
+    /// `EncryptionError`; any success resets the count.
+    ///
+    /// Attempts run one at a time, so parallel requests cannot slip past the
+    /// count. With `recovery`, the lockout does not apply, as the recovery
+    /// key is the way out of it.
+    pub(super) fn throttled<T>(
+        &self,
+        recovery: bool,
+        attempt: impl FnOnce() -> Result<T, VaultError>,
+    ) -> Result<T, VaultError> {
+        let _guard = self
+            .attempts
+            .lock()
+            .map_err(|_| VaultError::Corruption("Lock poisoned".into()))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut failures = self.db.get_failed_attempts()?;
+
+        if !recovery
+            && self
+                .config
+                .lockout_after
+                .is_some_and(|n| failures.count >= n)
+        {
+            return Err(VaultError::LockedOut);
+        }
+        let wait = retry_after(&failures, now);
+        if wait > 0 {
+            return Err(VaultError::Throttled(wait));
+        }
+
+        match attempt() {
+            Ok(value) => {
+                if failures.count > 0 {
+                    self.db.clear_failed_attempts()?;
+                }
+                Ok(value)
+            }
+            Err(VaultError::EncryptionError) => {
+                failures.count += 1;
+                failures.last_failure = now;
+                if self
+                    .config
+                    .self_destruct_after
+                    .is_some_and(|n| failures.count >= n)
+                {
+                    eprintln!("Too many failed attempts, wiping every key slot");
+                    self.db.remove_all_key_slots()?;
+                    self.db.clear_failed_attempts()?;
+                    self.lock();
+                } else {
+                    self.db.save_failed_attempts(&failures)?;
+                }
+                Err(VaultError::EncryptionError)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Seconds until the next attempt is allowed, 0 if it is now.
+    pub fn retry_after(&self) -> Result<u64, VaultError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(retry_after(&self.db.get_failed_attempts()?, now))
+    }
+}
+
+fn retry_after(failures: &FailedAttempts, now: u64) -> u64 {
+    let Some(doublings) = failures.count.checked_sub(FREE_ATTEMPTS) else {
+        return 0;
+    };
+    let delay = (1u64 << doublings.min(63)).min(MAX_DELAY);
+    (failures.last_failure + delay).saturating_sub(now)
+}
@@ -0,0 +1,260 @@
+use super::{
+    Vault, crypto,
+    error::VaultError,
+    types::{Credentials, TotpEnrollment, TotpState},
+};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Name of the setting holding a library's TOTP state
+pub(super) const TOTP_SETTING: &str = "totp";
+
+// RFC 6238 defaults, which is what authenticator apps assume
+const PERIOD: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of the current one that are still accepted, for clock drift
+const WINDOW: u64 = 1;
+const SECRET_LEN: usize = 20;
+
+const BACKUP_CODE_COUNT: usize = 10;
+const BACKUP_CODE_BYTES: usize = 5;
+const BACKUP_CODE_GROUP_LEN: usize = 4;
+
+impl Vault {
+    /// Whether sessions for the unlocked library need a TOTP or backup code
+    /// before they are let in.
+    pub fn second_factor_required(&self) -> Result<bool, VaultError> {
+        Ok(self.totp_state()?.is_some_and(|state| state.enabled))
+    }
+
+    /// Generates a new TOTP secret for the unlocked library. It only takes
+    /// effect once `confirm_totp` sees a code from it, so a secret that never
+    /// made it into an authenticator cannot lock anyone out.
+    pub fn begin_totp(&self, credentials: Credentials) -> Result<TotpEnrollment, VaultError> {
+        self.check_unlocked_password(credentials)?;
+        if self.second_factor_required()? {
+            return Err(VaultError::Corruption(
+                "Two-factor authentication is already enabled".into(),
+            ));
+        }
+
+        let state = TotpState {
+            secret: rand::random::<[u8; SECRET_LEN]>().to_vec(),
+            enabled: false,
+            last_step: 0,
+            backup_codes: Vec::new(),
+        };
+        self.with_data(|data| self.db.put_setting(&data.keys, TOTP_SETTING, &state))?;
+
+        let secret = crypto::base32_encode(&state.secret);
+        let uri = format!(
+            "otpauth://totp/Vanta?secret={}&issuer=Vanta&algorithm=SHA1&digits={}&period={}",
+            secret, DIGITS, PERIOD
+        );
+        Ok(TotpEnrollment { secret, uri })
+    }
+
+    /// Enables the secret from `begin_totp` once `code` shows the
+    /// authenticator has it. Returns the backup codes, which are only stored
+    /// hashed and cannot be shown again.
+    pub fn confirm_totp(&self, code: &str, now: u64) -> Result<Vec<String>, VaultError> {
+        self.with_data(|data| {
+            let mut state = self
+                .db
+                .get_setting::<TotpState>(&data.keys, TOTP_SETTING)?
+                .filter(|state| !state.enabled)
+                .ok_or_else(|| VaultError::NotFound("TOTP enrollment".into()))?;
+
+            state.last_step = matching_step(&state.secret, code, now, state.last_step)
+                .ok_or(VaultError::EncryptionError)?;
+            state.enabled = true;
+            let codes = generate_backup_codes(&mut state);
+            self.db.put_setting(&data.keys, TOTP_SETTING, &state)?;
+            Ok(codes)
+        })
+    }
+
+    pub fn disable_totp(&self, credentials: Credentials) -> Result<(), VaultError> {
+        self.check_unlocked_password(credentials)?;
+        self.with_data(|data| self.db.remove_setting(&data.keys, TOTP_SETTING))
+    }
+
+    /// Replaces every backup code with a fresh set.
+    pub fn regenerate_backup_codes(
+        &self,
+        credentials: Credentials,
+    ) -> Result<Vec<String>, VaultError> {
+        self.check_unlocked_password(credentials)?;
+        self.with_data(|data| {
+            let mut state = self
+                .db
+                .get_setting::<TotpState>(&data.keys, TOTP_SETTING)?
+                .filter(|state| state.enabled)
+                .ok_or_else(|| VaultError::NotFound("Two-factor authentication".into()))?;
+
+            let codes = generate_backup_codes(&mut state);
+            self.db.put_setting(&data.keys, TOTP_SETTING, &state)?;
+            Ok(codes)
+        })
+    }
+
+    /// Checks the second factor of a session: a current TOTP code, or a
+    /// backup code, which is used up. Codes are never accepted twice.
+    pub fn verify_second_factor(&self, code: &str, now: u64) -> Result<(), VaultError> {
+        let keys = self.unlocked_keys()?;
+
+        // Six digits are quick to guess without the failed attempt limits.
+        // Attempts run one at a time, and each reads the state the one before
+        // left, so a code cannot be spent twice.
+        self.throttled(false, || {
+            let mut state = self
+                .totp_state()?
+                .filter(|state| state.enabled)
+                .ok_or(VaultError::EncryptionError)?;
+            if let Some(step) = matching_step(&state.secret, code, now, state.last_step) {
+                state.last_step = step;
+            } else {
+                let hash = hash_backup_code(code).ok_or(VaultError::EncryptionError)?;
+                let used = state
+                    .backup_codes
+                    .iter()
+                    .position(|h| *h == hash)
+                    .ok_or(VaultError::EncryptionError)?;
+                state.backup_codes.swap_remove(used);
+            }
+            self.db.put_setting(&keys, TOTP_SETTING, &state)
+        })
+    }
+
+    /// The TOTP state of the unlocked library. Also works while a rotation
+    /// holds the vault, as sessions still have to be let in to follow it.
+    fn totp_state(&self) -> Result<Option<TotpState>, VaultError> {
+        let keys = self.unlocked_keys()?;
+        self.db.get_setting(&keys, TOTP_SETTING)
+    }
+
+    fn check_unlocked_password(&self, credentials: Credentials) -> Result<(), VaultError> {
+        let keyfile = self.keyfile_secret(credentials.keyfile)?;
+        self.open_unlocked_key_slot(credentials.password, keyfile.as_ref())?;
+        Ok(())
+    }
+}
+
+/// The code for time step `step`, per RFC 4226 with the RFC 6238 defaults.
+pub(super) fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac =
+        <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The time step `code` is valid for at `now`, if any. Steps up to
+/// `last_step` were already used and are not accepted again.
+fn matching_step(secret: &[u8], code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = now / PERIOD;
+    (current.saturating_sub(WINDOW)..=current + WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| code_at(secret, *step) == code)
+}
+
+/// Replaces the backup codes of `state` and returns the new ones, formatted
+/// like recovery keys.
+fn generate_backup_codes(state: &mut TotpState) -> Vec<String> {
+    let codes: Vec<String> = (0..BACKUP_CODE_COUNT)
+        .map(|_| {
+            let encoded = crypto::base32_encode(&rand::random::<[u8; BACKUP_CODE_BYTES]>());
+            encoded
+                .as_bytes()
+                .chunks(BACKUP_CODE_GROUP_LEN)
+                .map(|g| std::str::from_utf8(g).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect();
+    state.backup_codes = codes
+        .iter()
+        .filter_map(|code| hash_backup_code(code))
+        .collect();
+    codes
+}
+
+/// Case, dashes and whitespace are ignored, like for recovery keys.
+fn hash_backup_code(code: &str) -> Option<[u8; 32]> {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if normalized.len() != BACKUP_CODE_BYTES * 8 / 5 {
+        return None;
+    }
+    Some(Sha256::digest(normalized.as_bytes()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret from RFC 6238, appendix B
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // The RFC lists 8 digits; these are their last 6
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(code_at(SECRET, time / PERIOD), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_adjacent_steps_once() {
+        let now = 1111111111;
+        let step = now / PERIOD;
+        let previous = format!("{:06}", code_at(SECRET, step - 1));
+
+        assert_eq!(matching_step(SECRET, &previous, now, 0), Some(step - 1));
+        assert_eq!(matching_step(SECRET, &previous, now, step - 1), None);
+        assert_eq!(matching_step(SECRET, &previous, now + 2 * PERIOD, 0), None);
+        assert_eq!(matching_step(SECRET, "12345", now, 0), None);
+    }
+
+    #[test]
+    fn backup_codes_ignore_formatting() {
+        let mut state = TotpState {
+            secret: SECRET.to_vec(),
+            enabled: true,
+            last_step: 0,
+            backup_codes: Vec::new(),
+        };
+        let codes = generate_backup_codes(&mut state);
+        assert_eq!(codes.len(), BACKUP_CODE_COUNT);
+
+        let typed = codes[0].replace('-', " ").to_lowercase();
+        assert_eq!(hash_backup_code(&typed), Some(state.backup_codes[0]));
+        assert_eq!(hash_backup_code("ABC-DEF"), None);
+    }
+}
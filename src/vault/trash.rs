@@ -0,0 +1,149 @@
+use super::{
+    Vault,
+    crypto::VaultKeys,
+    error::VaultError,
+    types::{AuditEvent, ImageEntry},
+};
+use secrecy::ExposeSecret;
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// How often the background task looks for expired entries
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+impl Vault {
+    /// Moves an entry to the trash. It disappears from listings, searches and
+    /// the tag index, but keeps its blobs until it is purged.
+    pub fn trash_image(&self, id: Uuid) -> Result<ImageEntry, VaultError> {
+        let deleted_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        self.with_data_mut(|data| {
+            let mut entry = self.live_entry(&data.keys, id)?;
+            entry.deleted_at = Some(deleted_at);
+            self.db.insert_entry(&data.keys, &entry)?;
+
+            for tag in &entry.tags {
+                if let Some(set) = data.tag_index.get_mut(tag) {
+                    set.remove(&id);
+                    if set.is_empty() {
+                        data.tag_index.remove(tag);
+                    }
+                }
+            }
+            Ok(entry)
+        })
+    }
+
+    /// Takes an entry back out of the trash.
+    pub fn restore_image(&self, id: Uuid) -> Result<ImageEntry, VaultError> {
+        self.with_data_mut(|data| {
+            let mut entry = self.trashed_entry(&data.keys, id)?;
+            entry.deleted_at = None;
+            self.db.insert_entry(&data.keys, &entry)?;
+
+            for tag in &entry.tags {
+                data.tag_index.entry(tag.clone()).or_default().insert(id);
+            }
+            Ok(entry)
+        })
+    }
+
+    /// Entries in the trash, most recently deleted first.
+    pub fn list_trash(&self) -> Result<Vec<ImageEntry>, VaultError> {
+        let mut entries =
+            self.with_data(|data| self.db.get_all_entries(data.keys.entries.expose_secret()))?;
+        entries.retain(|entry| entry.deleted_at.is_some());
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.deleted_at));
+        Ok(entries)
+    }
+
+    /// Deletes an entry in the trash for good, along with the files of its
+    /// cover and linked images.
+    pub async fn purge_image(&self, id: Uuid) -> Result<(), VaultError> {
+        // 1. Remove the entry, collect the blobs of the cover and linked images
+        let (row, blobs) = self.with_data(|data| {
+            let entry = self.trashed_entry(&data.keys, id)?;
+            let blobs = Self::entry_blobs(&entry);
+            let row = self.begin_blobs(&data.keys, id, blobs.clone())?;
+            self.db.remove_entry(&data.keys, id)?;
+            // The entry must be gone for good before its files are
+            self.db.flush()?;
+            Ok((row, blobs))
+        })?;
+
+        // 2. Delete cover and linked image files
+        Self::remove_blobs(&blobs).await?;
+        self.end_blobs(&row)
+    }
+
+    /// Purges the entries moved to the trash at or before `before`, in
+    /// seconds since the epoch, so `u64::MAX` empties it. Returns how many
+    /// entries were purged.
+    pub async fn purge_trash(&self, before: u64, ip: Option<IpAddr>) -> Result<u32, VaultError> {
+        let mut purged = 0;
+        for entry in self.list_trash()? {
+            if entry.deleted_at.is_some_and(|at| at <= before) {
+                self.purge_image(entry.id).await?;
+                self.audit(AuditEvent::Purge { id: entry.id }, ip);
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Starts the task that purges entries once they have been in the trash
+    /// for `trash_retention`. Does nothing when no retention is configured.
+    pub fn spawn_trash_purge(&self) {
+        let Some(retention) = self.config.trash_retention else {
+            return;
+        };
+
+        let vault = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !vault.is_unlocked() {
+                    continue;
+                }
+
+                let before = SystemTime::now()
+                    .checked_sub(retention)
+                    .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |at| at.as_secs());
+                match vault.purge_trash(before, None).await {
+                    Ok(0) => {}
+                    Ok(purged) => println!("Purged {} entries from the trash", purged),
+                    Err(e) => eprintln!("Failed to purge the trash: {}", e),
+                }
+            }
+        });
+    }
+
+    /// An entry that is not in the trash. Entries in the trash cannot be
+    /// changed until they are restored.
+    pub(super) fn live_entry(&self, keys: &VaultKeys, id: Uuid) -> Result<ImageEntry, VaultError> {
+        let entry = self.db.get_entry(keys, id)?;
+        match entry.deleted_at {
+            None => Ok(entry),
+            Some(_) => Err(VaultError::NotFound(format!(
+                "Image {} is in the trash",
+                id
+            ))),
+        }
+    }
+
+    fn trashed_entry(&self, keys: &VaultKeys, id: Uuid) -> Result<ImageEntry, VaultError> {
+        let entry = self.db.get_entry(keys, id)?;
+        match entry.deleted_at {
+            Some(_) => Ok(entry),
+            None => Err(VaultError::NotFound(format!(
+                "Image {} is not in the trash",
+                id
+            ))),
+        }
+    }
+}
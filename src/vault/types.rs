@@ -1,6 +1,8 @@
 use super::error::VaultError;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr};
 use uuid::Uuid;
+use zeroize::Zeroize;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageVariant {
@@ -38,12 +40,32 @@ impl ImageVariant {
     }
 }
 
+/// Where one variant of an image is stored. Blob names are opaque, so only the
+/// encrypted entry links a file on disk to its image.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredBlob {
+    pub variant: ImageVariant,
+    pub name: String,
+}
+
+impl StoredBlob {
+    /// Name of `variant` in `blobs`. Images stored before opaque names have none.
+    pub fn find(blobs: &[StoredBlob], variant: ImageVariant) -> Option<&str> {
+        blobs
+            .iter()
+            .find(|b| b.variant == variant)
+            .map(|b| b.name.as_str())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinkedImage {
     pub id: Uuid,
     pub original_mime: String,
     pub original_size: u64,
     pub variants: Vec<ImageVariant>,
+    #[serde(default)]
+    pub blobs: Vec<StoredBlob>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -57,6 +79,11 @@ pub struct ImageEntry {
     pub tags: Vec<String>,
     #[serde(default)]
     pub linked_images: Vec<LinkedImage>,
+    #[serde(default)]
+    pub blobs: Vec<StoredBlob>,
+    /// When the entry was moved to the trash
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
 }
 
 impl ImageEntry {
@@ -94,8 +121,394 @@ pub fn mime_to_ext(mime: &str) -> &'static str {
 
 #[derive(Clone)]
 pub struct VaultMetadata {
-    pub vault_version: u32,
+    /// KDF parameters new key slots are wrapped with, calibrated at setup
+    pub kdf: KdfParams,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2id,
+}
+
+/// Key derivation algorithm and cost used to derive a slot's wrapping key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// True if either memory or iterations are weaker than `min`.
+    pub fn is_below(&self, min: &KdfParams) -> bool {
+        self.m_cost < min.m_cost || self.t_cost < min.t_cost
+    }
+
+    /// Raises each cost to at least the one in `min`.
+    pub fn at_least(&self, min: &KdfParams) -> KdfParams {
+        KdfParams {
+            algorithm: self.algorithm,
+            m_cost: self.m_cost.max(min.m_cost),
+            t_cost: self.t_cost.max(min.t_cost),
+            p_cost: self.p_cost.max(min.p_cost),
+        }
+    }
+}
+
+/// The parameters every vault used before they were stored.
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            m_cost: 65536,
+            t_cost: 3,
+            p_cost: 4,
+        }
+    }
+}
+
+/// One independently unlockable copy of the master key, wrapped under a
+/// password-derived key. The label lives in `info`, encrypted under the master key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeySlot {
+    pub id: u32,
+    pub salt: [u8; 16],
+    pub kdf: KdfParams,
+    pub wrapped_key: Vec<u8>,
+    pub info: Vec<u8>,
+    /// Whether the wrapping key also depends on a keyfile
+    pub keyfile: bool,
+}
+
+/// What a password slot is opened with: the password and, for slots that
+/// require one, the contents of a keyfile.
+#[derive(Clone, Copy)]
+pub struct Credentials<'a> {
+    pub password: &'a str,
+    pub keyfile: Option<&'a [u8]>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotKind {
+    Password,
+    Recovery,
+    /// A decoy library's password that, once used, removes the key slots of
+    /// every other library. Listed as a plain password.
+    Duress,
+}
+
+impl SlotKind {
+    /// Whether a slot of this kind opens where a `wanted` slot is asked for.
+    pub fn opens_as(self, wanted: SlotKind) -> bool {
+        self == wanted || (self == SlotKind::Duress && wanted == SlotKind::Password)
+    }
+}
+
+/// The decrypted contents of `KeySlot::info`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlotInfo {
+    pub label: String,
+    pub created_at: u64,
+    pub kind: SlotKind,
+}
+
+/// Slot info written before recovery slots existed, used only as a decode fallback.
+#[derive(Deserialize)]
+pub struct SlotInfoV1 {
+    pub label: String,
+    pub created_at: u64,
+}
+
+/// What the API exposes about a key slot.
+#[derive(Serialize, Debug, Clone)]
+pub struct KeySlotSummary {
+    pub id: u32,
+    pub label: String,
+    pub created_at: u64,
+    pub kind: SlotKind,
+}
+
+/// Consecutive failed unlock attempts, kept in the clear so they count while
+/// the vault is locked and across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FailedAttempts {
+    pub count: u32,
+    pub last_failure: u64,
+}
+
+/// Checkpoint of an in-progress master key rotation, persisted so a crash or
+/// restart resumes instead of leaving a half-rotated vault.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RotationState {
+    /// The old master key, wrapped under the new one. Deleted with this record
+    /// once every entry and blob has been re-encrypted. `None` when only the
+    /// key derivation changes and the master key stays the same.
+    pub wrapped_old_key: Option<Vec<u8>>,
+    /// Key derivation version the old keys were derived with
+    pub old_derivation: u32,
+    /// Sled key of the last entry fully re-encrypted
+    pub cursor: Option<Vec<u8>>,
+    pub done: u64,
+    pub total: u64,
+    /// Entries that could not be decoded and blobs that were missing or
+    /// readable with neither key
+    pub failed: u64,
+    pub started_at: u64,
+}
+
+/// Checkpoint written before key derivation versions existed, used only as a
+/// decode fallback.
+#[derive(Deserialize)]
+pub struct RotationStateV1 {
+    pub wrapped_old_key: Vec<u8>,
+    pub cursor: Option<Vec<u8>>,
+    pub done: u64,
+    pub total: u64,
+    pub failed: u64,
+    pub started_at: u64,
+}
+
+/// What the API reports about a rotation.
+#[derive(Serialize, Debug, Clone)]
+pub struct RotationProgress {
+    pub active: bool,
+    pub done: u64,
+    pub total: u64,
+    pub failed: u64,
+    pub started_at: Option<u64>,
+}
+
+/// Returned once when a rotation starts.
+#[derive(Serialize, Debug, Clone)]
+pub struct RotationStarted {
+    /// Replaces the old recovery key, which no longer unlocks anything
+    pub recovery_key: String,
+    /// Password slots that could not be re-wrapped and were removed
+    pub removed_slots: Vec<u32>,
+}
+
+/// Two-factor state of a library, stored encrypted as a setting.
+#[derive(Serialize, Deserialize)]
+pub struct TotpState {
+    pub secret: Vec<u8>,
+    /// Set once a code from the authenticator has been confirmed
+    pub enabled: bool,
+    /// Time step of the last accepted code, so a code is never accepted twice
+    pub last_step: u64,
+    /// SHA-256 hashes of the backup codes not used yet
+    pub backup_codes: Vec<[u8; 32]>,
+}
+
+impl Drop for TotpState {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Returned once when TOTP enrollment starts, to set up an authenticator.
+#[derive(Serialize, Debug, Clone)]
+pub struct TotpEnrollment {
+    /// The secret in base32, for typing in by hand
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub uri: String,
+}
+
+/// Audit log state of a library, stored encrypted as a setting. Records are
+/// encrypted under their own random key, so a rotation only has to carry
+/// this over.
+#[derive(Serialize, Deserialize)]
+pub struct AuditState {
+    pub key: [u8; 32],
+    /// Sequence number the next record gets
+    pub next_seq: u64,
+    /// Hash of the last record, so truncating the log is noticed
+    pub head: [u8; 32],
+}
+
+impl Drop for AuditState {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Blobs of an entry that an operation was about to write, or to remove from
+/// it. Replaying the record removes whichever of them the entry does not
+/// refer to, which undoes an interrupted write and finishes a removal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalRecord {
+    pub entry: Uuid,
+    pub blobs: Vec<(Uuid, ImageVariant, Option<String>)>,
+}
+
+/// Something security-relevant that happened to a library.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Setup,
+    Unlock,
+    UnlockFailed,
+    /// Failed attempts since the last successful one, summed up as those made
+    /// while the vault was locked could not be recorded one by one
+    FailedAttempts {
+        count: u32,
+        last_at: u64,
+    },
+    Recover,
+    Lock,
+    AutoLock,
+    /// Moved to the trash
+    Delete {
+        id: Uuid,
+    },
+    RenameTag {
+        old: String,
+        new: String,
+    },
+    Download {
+        id: Uuid,
+    },
+    Restore {
+        id: Uuid,
+    },
+    Purge {
+        id: Uuid,
+    },
+}
+
+/// One entry of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub at: u64,
+    /// Address of the client, for events caused by a request
+    pub ip: Option<IpAddr>,
+    pub event: AuditEvent,
+}
+
+/// An audit record as stored, chained to the one before it by its hash.
+#[derive(Serialize, Deserialize)]
+pub struct ChainedAuditRecord {
+    pub record: AuditRecord,
+    pub prev_hash: [u8; 32],
+}
+
+/// What verifying the audit log found.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditCheck {
+    pub records: u64,
+    pub intact: bool,
+    /// First record that is missing, altered or out of order
+    pub broken_at: Option<u64>,
+}
+
+/// What is wrong with one variant of an image.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsckIssue {
+    /// Not listed by the entry, or listed without a blob on disk
+    Missing,
+    /// The blob is there but does not decrypt
+    Unreadable,
+}
+
+/// A variant of an entry, or of one of its linked images, that failed the check.
+#[derive(Serialize, Debug, Clone)]
+pub struct FsckProblem {
+    pub entry: Uuid,
+    /// Set when the problem is with a linked image of the entry
+    pub linked: Option<Uuid>,
+    pub variant: ImageVariant,
+    pub issue: FsckIssue,
+    pub repaired: bool,
+}
+
+/// What checking the unlocked library against `vault/storage` found.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FsckReport {
+    pub entries: u64,
+    /// Blobs that decrypted, including repaired ones
+    pub blobs: u64,
+    /// Rows that decrypt with the library's key but do not decode
+    pub corrupt_entries: u64,
+    pub problems: Vec<FsckProblem>,
+    /// Storage paths no entry of the library refers to
+    pub orphans: Vec<String>,
+    /// Whether the orphans were moved to quarantine
+    pub quarantined: bool,
+}
+
+impl FsckReport {
+    /// Whether nothing is left to fix.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_entries == 0
+            && self.problems.iter().all(|p| p.repaired)
+            && (self.orphans.is_empty() || self.quarantined)
+    }
+}
+
+/// What a garbage collection removed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GcReport {
+    /// Orphaned blobs and directories removed
+    pub removed: u64,
+    /// Bytes their files took up
+    pub reclaimed: u64,
+    /// Set when other libraries exist, whose blobs cannot be told from orphans
+    pub skipped: bool,
+}
+
+/// Lists what a backup archive holds, stored in it as `manifest.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupManifest {
+    /// Layout of the archive, so restores can refuse ones they do not know
+    pub format: u32,
     pub created_at: u64,
-    pub vault_salt: Option<[u8; 16]>,
-    pub master_key_check: Option<Vec<u8>>,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupFile {
+    /// Path within the archive
+    pub path: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// What a mirror run did.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MirrorReport {
+    /// Blobs new or changed since the last run
+    pub copied: u64,
+    /// Bytes of the blobs copied
+    pub bytes: u64,
+    /// Database records written or removed
+    pub records: u64,
+    /// Copied blobs that read back intact
+    pub verified: u64,
+    /// Copied blobs that did not, which the next run copies again
+    pub corrupt: Vec<String>,
+    /// Blobs gone from the vault, removed from the mirror
+    pub removed: u64,
+    /// Blobs gone from the vault, kept until the retention period is over
+    pub retained: u64,
+}
+
+/// Kept in a mirror as `mirror.json` between runs.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MirrorState {
+    /// Blobs gone from the vault but still in the mirror, with when that was
+    /// first seen
+    pub removed: BTreeMap<String, u64>,
+}
+
+/// A database tree as backed up: its records exactly as stored.
+#[derive(Serialize, Deserialize)]
+pub struct DbTree {
+    pub name: Vec<u8>,
+    pub records: Vec<(Vec<u8>, Vec<u8>)>,
 }